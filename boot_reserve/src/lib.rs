@@ -0,0 +1,472 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * The interval logic behind the boot reserve ranges: a sorted
+ * list of physical ranges that never overlap, and a search for
+ * free space between them. It only needs a Vec, so that it can
+ * be tested on the host; the kernel wraps it in vm::bootreserve.
+ */
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootReserveRange {
+    pub pa: usize,
+    pub len: usize,
+}
+
+/* Where in the free space an allocation goes */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootAllocPolicy {
+    /* the lowest address that fits */
+    FirstFit,
+    /* the highest address that fits */
+    TopDown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootReserveError {
+    /* a zero length or an alignment that isn't a power of two */
+    InvalidArgs,
+    /* the range wraps around or overlaps a reserve */
+    BadRange,
+    /* no reserve holds the range to free */
+    NotFound,
+    /* no room left, in memory or in the list */
+    NoMem,
+}
+
+/* given two offset/length pairs, determine if they overlap at all */
+#[inline]
+fn intersects(offset1: usize, len1: usize,
+              offset2: usize, len2: usize) -> bool {
+    /* Can't overlap a zero-length region. */
+    if len1 == 0 || len2 == 0 {
+        return false;
+    }
+
+    if offset1 <= offset2 {
+        /* doesn't intersect, 1 is completely below 2 */
+        if offset1 + len1 <= offset2 {
+            return false;
+        }
+    } else if offset1 >= offset2 + len2 {
+        /* 1 is completely above 2 */
+        return false;
+    }
+
+    true
+}
+
+/* The end of [pa, pa + len), if it doesn't wrap around */
+#[inline]
+fn range_end(pa: usize, len: usize) -> Result<usize, BootReserveError> {
+    pa.checked_add(len).ok_or(BootReserveError::BadRange)
+}
+
+fn grow(ranges: &mut Vec<BootReserveRange>)
+    -> Result<(), BootReserveError> {
+    ranges.try_reserve(1).map_err(|_| BootReserveError::NoMem)
+}
+
+/* Reserve [pa, pa + len), keeping |ranges| sorted */
+pub fn add_range(pa: usize, len: usize,
+                 ranges: &mut Vec<BootReserveRange>)
+    -> Result<(), BootReserveError> {
+
+    if len == 0 {
+        return Err(BootReserveError::InvalidArgs);
+    }
+    let end = range_end(pa, len)?;
+
+    /* insert into the list, sorted */
+    let mut i = 0;
+    while i < ranges.len() {
+        if intersects(ranges[i].pa, ranges[i].len, pa, len) {
+            return Err(BootReserveError::BadRange);
+        }
+
+        if ranges[i].pa >= end {
+            break;
+        }
+
+        i += 1;
+    }
+
+    grow(ranges)?;
+    ranges.insert(i, BootReserveRange{pa, len});
+    Ok(())
+}
+
+/* Give back [pa, pa + len), which must lie within a single
+ * reserved range; that range shrinks or is split in two. */
+pub fn free(pa: usize, len: usize, ranges: &mut Vec<BootReserveRange>)
+    -> Result<(), BootReserveError> {
+
+    if len == 0 {
+        return Err(BootReserveError::InvalidArgs);
+    }
+    let end = range_end(pa, len)?;
+
+    let i = ranges.iter()
+        .position(|r| pa >= r.pa && end <= r.pa + r.len)
+        .ok_or(BootReserveError::NotFound)?;
+
+    let r = ranges[i];
+    let r_end = r.pa + r.len;
+    match (pa == r.pa, end == r_end) {
+        (true, true) => {
+            ranges.remove(i);
+        },
+        (true, false) => {
+            ranges[i] = BootReserveRange{pa: end, len: r_end - end};
+        },
+        (false, true) => {
+            ranges[i].len = pa - r.pa;
+        },
+        (false, false) => {
+            grow(ranges)?;
+            ranges[i].len = pa - r.pa;
+            ranges.insert(i + 1,
+                          BootReserveRange{pa: end, len: r_end - end});
+        },
+    }
+    Ok(())
+}
+
+/* Place |alloc_len| bytes aligned to |align| in the free
+ * gap [gap_start, gap_end), following |policy|. */
+fn fit_in_gap(gap_start: usize, gap_end: usize,
+              alloc_len: usize, align: usize, policy: BootAllocPolicy)
+    -> Option<usize> {
+
+    if gap_end <= gap_start || gap_end - gap_start < alloc_len {
+        return None;
+    }
+
+    match policy {
+        BootAllocPolicy::FirstFit => {
+            let pa = gap_start.checked_add(align - 1)? & !(align - 1);
+            if pa.checked_add(alloc_len)? <= gap_end {
+                return Some(pa);
+            }
+            None
+        },
+        BootAllocPolicy::TopDown => {
+            let pa = (gap_end - alloc_len) & !(align - 1);
+            if pa >= gap_start {
+                return Some(pa);
+            }
+            None
+        },
+    }
+}
+
+/* Search [range_pa, range_pa + range_len) for |alloc_len| bytes,
+ * aligned to |align| (a power of two), that don't intersect any
+ * of the reserved |ranges|. Nothing gets reserved here. */
+pub fn range_search(range_pa: usize,
+                    range_len: usize,
+                    alloc_len: usize,
+                    align: usize,
+                    policy: BootAllocPolicy,
+                    ranges: &[BootReserveRange])
+    -> Result<BootReserveRange, BootReserveError> {
+
+    if alloc_len == 0 || align == 0 || !align.is_power_of_two() {
+        return Err(BootReserveError::InvalidArgs);
+    }
+    let range_end = range_end(range_pa, range_len)?;
+
+    /* walk the gaps between the reserves, lowest first */
+    let mut found = None;
+    let mut gap_start = range_pa;
+    for r in ranges {
+        if gap_start >= range_end {
+            break;
+        }
+
+        let gap_end = r.pa.min(range_end);
+        if let Some(pa) = fit_in_gap(gap_start, gap_end,
+                                     alloc_len, align, policy) {
+            found = Some(pa);
+            if policy == BootAllocPolicy::FirstFit {
+                break;
+            }
+        }
+
+        gap_start = gap_start.max(r.pa.saturating_add(r.len));
+    }
+
+    let first_fit_done =
+        policy == BootAllocPolicy::FirstFit && found.is_some();
+    if !first_fit_done {
+        if let Some(pa) = fit_in_gap(gap_start, range_end,
+                                     alloc_len, align, policy) {
+            found = Some(pa);
+        }
+    }
+
+    let pa = found.ok_or(BootReserveError::NoMem)?;
+    Ok(BootReserveRange{pa, len: alloc_len})
+}
+
+/* Reserve |len| bytes aligned to |align| anywhere in the memory
+ * ranges |mem|. Physically contiguous ranges are searched as one,
+ * so an allocation may span several of them. */
+pub fn alloc(len: usize, align: usize, policy: BootAllocPolicy,
+             mem: &[BootReserveRange],
+             ranges: &mut Vec<BootReserveRange>)
+    -> Result<usize, BootReserveError> {
+
+    /* merge the memory ranges into contiguous spans */
+    let mut spans: Vec<BootReserveRange> = mem.iter()
+        .filter(|m| m.len != 0).copied().collect();
+    spans.sort_unstable_by_key(|m| m.pa);
+    spans.dedup_by(|next, prev| {
+        let prev_end = prev.pa.saturating_add(prev.len);
+        if next.pa > prev_end {
+            return false;
+        }
+        prev.len = prev_end.max(next.pa.saturating_add(next.len)) - prev.pa;
+        true
+    });
+
+    if policy == BootAllocPolicy::TopDown {
+        spans.reverse();
+    }
+
+    for span in &spans {
+        match range_search(span.pa, span.len, len, align, policy, ranges) {
+            Ok(found) => {
+                add_range(found.pa, found.len, ranges)?;
+                return Ok(found.pa);
+            },
+            Err(BootReserveError::NoMem) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(BootReserveError::NoMem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const POLICIES: [BootAllocPolicy; 2] =
+        [BootAllocPolicy::FirstFit, BootAllocPolicy::TopDown];
+
+    /* The reserves made of the runs of set bits in |mask|,
+     * one unit per bit; adjacent runs are split at |split|. */
+    fn ranges_from_mask(mask: u32, bits: usize, split: usize)
+        -> Vec<BootReserveRange> {
+
+        let mut ranges = Vec::new();
+        let mut i = 0;
+        while i < bits {
+            if mask & (1 << i) == 0 {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < bits && mask & (1 << i) != 0 &&
+                  (i == start || i != split) {
+                i += 1;
+            }
+            ranges.push(BootReserveRange{pa: start, len: i - start});
+        }
+        ranges
+    }
+
+    /* What the search must find, by trying every address. */
+    fn oracle(mask: u32, window: (usize, usize), len: usize,
+              align: usize, policy: BootAllocPolicy) -> Option<usize> {
+
+        let (start, end) = window;
+        let mut fits = (start..end)
+            .filter(|pa| pa % align == 0 && pa + len <= end)
+            .filter(|pa| (*pa..pa + len).all(|u| mask & (1 << u) == 0));
+
+        match policy {
+            BootAllocPolicy::FirstFit => fits.next(),
+            BootAllocPolicy::TopDown => fits.last(),
+        }
+    }
+
+    fn search(window: (usize, usize), len: usize, align: usize,
+              policy: BootAllocPolicy, ranges: &[BootReserveRange])
+        -> Option<usize> {
+
+        match range_search(window.0, window.1 - window.0,
+                           len, align, policy, ranges) {
+            Ok(r) => {
+                assert_eq!(r.len, len);
+                Some(r.pa)
+            },
+            Err(BootReserveError::NoMem) => None,
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn search_matches_oracle_exhaustively() {
+        const BITS: usize = 12;
+        let windows = [(0, BITS), (3, BITS), (0, 9), (2, 7), (5, 6)];
+
+        for mask in 0..(1u32 << BITS) {
+            for split in [BITS, 4, 7] {
+                let ranges = ranges_from_mask(mask, BITS, split);
+                for window in windows {
+                    for len in 1..=5 {
+                        for align in [1, 2, 4, 8] {
+                            for policy in POLICIES {
+                                assert_eq!(
+                                    search(window, len, align,
+                                           policy, &ranges),
+                                    oracle(mask, window, len,
+                                           align, policy),
+                                    "mask {:b} split {} window {:?} \
+                                     len {} align {} {:?}",
+                                    mask, split, window, len,
+                                    align, policy);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn search_does_not_underflow() {
+        /* a reserve at the very bottom used to wrap the address */
+        let ranges = vec![BootReserveRange{pa: 0, len: 0x1000}];
+        for policy in POLICIES {
+            assert_eq!(search((0, 0x1800), 0x1000, 1, policy, &ranges),
+                       None);
+        }
+    }
+
+    #[test]
+    fn search_rejects_bad_args() {
+        for (len, align) in [(0, 1), (1, 0), (1, 3)] {
+            assert_eq!(range_search(0, 16, len, align,
+                                    BootAllocPolicy::FirstFit, &[]),
+                       Err(BootReserveError::InvalidArgs));
+        }
+        assert_eq!(range_search(usize::MAX, 2, 1, 1,
+                                BootAllocPolicy::FirstFit, &[]),
+                   Err(BootReserveError::BadRange));
+    }
+
+    #[test]
+    fn search_near_top_of_address_space() {
+        let ranges = vec![BootReserveRange{pa: usize::MAX - 7, len: 4}];
+        for policy in POLICIES {
+            assert_eq!(search((usize::MAX - 15, usize::MAX), 8, 8,
+                              policy, &ranges),
+                       Some(usize::MAX - 15));
+        }
+    }
+
+    #[test]
+    fn add_range_keeps_order_and_rejects_overlap() {
+        let mut ranges = Vec::new();
+        for (pa, len) in [(8, 2), (0, 4), (4, 4), (12, 1)] {
+            add_range(pa, len, &mut ranges).unwrap();
+        }
+        let pas: Vec<usize> = ranges.iter().map(|r| r.pa).collect();
+        assert_eq!(pas, vec![0, 4, 8, 12]);
+
+        for (pa, len) in [(3, 2), (9, 1), (0, 13), (11, 2)] {
+            assert_eq!(add_range(pa, len, &mut ranges),
+                       Err(BootReserveError::BadRange));
+        }
+        assert_eq!(add_range(20, 0, &mut ranges),
+                   Err(BootReserveError::InvalidArgs));
+        assert_eq!(add_range(usize::MAX, 2, &mut ranges),
+                   Err(BootReserveError::BadRange));
+        assert_eq!(ranges.len(), 4);
+    }
+
+    #[test]
+    fn free_trims_and_splits() {
+        let mut ranges = vec![BootReserveRange{pa: 0, len: 16}];
+
+        free(0, 2, &mut ranges).unwrap();
+        assert_eq!(ranges, vec![BootReserveRange{pa: 2, len: 14}]);
+
+        free(14, 2, &mut ranges).unwrap();
+        assert_eq!(ranges, vec![BootReserveRange{pa: 2, len: 12}]);
+
+        free(6, 4, &mut ranges).unwrap();
+        assert_eq!(ranges, vec![BootReserveRange{pa: 2, len: 4},
+                                BootReserveRange{pa: 10, len: 4}]);
+
+        /* not within a single range */
+        assert_eq!(free(4, 8, &mut ranges),
+                   Err(BootReserveError::NotFound));
+        assert_eq!(free(0, 1, &mut ranges),
+                   Err(BootReserveError::NotFound));
+
+        free(2, 4, &mut ranges).unwrap();
+        free(10, 4, &mut ranges).unwrap();
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn free_then_alloc_reuses_the_gap() {
+        let mem = [BootReserveRange{pa: 0, len: 16}];
+        let mut ranges = vec![BootReserveRange{pa: 0, len: 16}];
+
+        free(4, 4, &mut ranges).unwrap();
+        assert_eq!(alloc(4, 4, BootAllocPolicy::TopDown, &mem, &mut ranges),
+                   Ok(4));
+        assert_eq!(alloc(1, 1, BootAllocPolicy::FirstFit,
+                         &mem, &mut ranges),
+                   Err(BootReserveError::NoMem));
+    }
+
+    #[test]
+    fn alloc_spans_contiguous_arenas() {
+        /* two arenas back to back, out of order, and a lone one */
+        let mem = [BootReserveRange{pa: 8, len: 8},
+                   BootReserveRange{pa: 32, len: 4},
+                   BootReserveRange{pa: 0, len: 8}];
+        let mut ranges = vec![BootReserveRange{pa: 2, len: 2}];
+
+        assert_eq!(alloc(10, 2, BootAllocPolicy::FirstFit,
+                         &mem, &mut ranges),
+                   Ok(4));
+        assert_eq!(alloc(4, 1, BootAllocPolicy::TopDown, &mem, &mut ranges),
+                   Ok(32));
+        assert_eq!(alloc(3, 1, BootAllocPolicy::TopDown, &mem, &mut ranges),
+                   Err(BootReserveError::NoMem));
+        assert_eq!(alloc(2, 1, BootAllocPolicy::TopDown, &mem, &mut ranges),
+                   Ok(14));
+
+        let pas: Vec<usize> = ranges.iter().map(|r| r.pa).collect();
+        assert_eq!(pas, vec![2, 4, 14, 32]);
+    }
+
+    #[test]
+    fn alloc_first_fit_prefers_lower_arenas() {
+        let mem = [BootReserveRange{pa: 64, len: 16},
+                   BootReserveRange{pa: 16, len: 16}];
+        let mut ranges = Vec::new();
+
+        assert_eq!(alloc(8, 8, BootAllocPolicy::FirstFit, &mem, &mut ranges),
+                   Ok(16));
+        assert_eq!(alloc(8, 8, BootAllocPolicy::TopDown, &mem, &mut ranges),
+                   Ok(72));
+    }
+}
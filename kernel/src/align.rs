@@ -21,7 +21,7 @@ macro_rules! ALIGN {
 
 #[macro_export]
 macro_rules! IS_ALIGNED {
-    ($a: expr, $b: expr) => {((($a) & (($b) - 1)) == 0)}
+    ($a: expr, $b: expr) => {{(($a) & (($b) - 1)) == 0}}
 }
 
 #[macro_export]
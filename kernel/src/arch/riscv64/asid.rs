@@ -0,0 +1,210 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * ASID allocation
+ *
+ * An aspace holds a context id, its ASID with the generation it
+ * was handed out in above the ASID bits. ASIDs come out of a bitmap
+ * until it runs out; then the generation moves on, the bitmap is
+ * cleared except for the ASIDs the cpus are running with, and every
+ * cpu flushes its TLB before it next switches to a new ASID.
+ * An aspace with a context id of an older generation gets a fresh
+ * ASID the next time it is switched to.
+ */
+
+use core::arch::asm;
+use super::defines::*;
+use super::mp::arch_curr_cpu_num;
+use crate::{dprint, INFO};
+use crate::kernel::spinlock::SpinLock;
+
+pub const SATP_ASID_SHIFT: usize = 44;
+pub const SATP_ASID_MASK: usize = 0xffff;
+
+const MAX_ASIDS: usize = SATP_ASID_MASK + 1;
+const ASID_MAP_WORDS: usize = MAX_ASIDS / 64;
+
+/* The kernel runs with ASID 0, and so does everything
+ * when the harts don't implement ASIDs. */
+pub const KERNEL_ASID: usize = 0;
+
+struct AsidAllocator {
+    /* ASIDLEN, 0 when there are no ASIDs */
+    bits: usize,
+    /* the current generation, a multiple of 1 << bits */
+    generation: u64,
+    /* where to look for a free ASID */
+    next: usize,
+    map: [u64; ASID_MAP_WORDS],
+    /* the context id each cpu is running with */
+    active: [u64; NR_CPUS],
+    /* the context ids that were active at the last rollover,
+     * whose ASIDs are kept for the new generation */
+    reserved: [u64; NR_CPUS],
+    /* cpus that have to flush their TLB since the last rollover */
+    flush_pending: usize,
+}
+
+impl AsidAllocator {
+    const fn new() -> Self {
+        Self {
+            bits: 0,
+            generation: 0,
+            next: KERNEL_ASID + 1,
+            map: [0; ASID_MAP_WORDS],
+            active: [0; NR_CPUS],
+            reserved: [0; NR_CPUS],
+            flush_pending: 0,
+        }
+    }
+
+    fn asid_mask(&self) -> u64 {
+        (1 << self.bits) - 1
+    }
+
+    fn test_and_set(&mut self, asid: usize) -> bool {
+        let bit = 1 << (asid % 64);
+        let was_set = (self.map[asid / 64] & bit) != 0;
+        self.map[asid / 64] |= bit;
+        was_set
+    }
+
+    fn rollover(&mut self) {
+        self.generation += 1 << self.bits;
+        self.map = [0; ASID_MAP_WORDS];
+        self.test_and_set(KERNEL_ASID);
+
+        for cpu in 0..NR_CPUS {
+            let ctx = self.active[cpu];
+            self.reserved[cpu] = ctx;
+            if ctx != 0 {
+                self.test_and_set((ctx & self.asid_mask()) as usize);
+            }
+        }
+        self.flush_pending = usize::MAX >> (usize::BITS as usize - NR_CPUS);
+        self.next = KERNEL_ASID + 1;
+    }
+
+    /* A context id of the current generation for an aspace
+     * that held |old| */
+    fn new_context(&mut self, old: u64) -> u64 {
+        let asid_mask = self.asid_mask();
+
+        if old != 0 {
+            let asid = (old & asid_mask) as usize;
+
+            /* it was running across the rollover, keep its ASID */
+            for cpu in 0..NR_CPUS {
+                if self.reserved[cpu] == old {
+                    let ctx = self.generation | asid as u64;
+                    self.reserved[cpu] = ctx;
+                    return ctx;
+                }
+            }
+
+            /* reuse its ASID if nobody took it yet */
+            if !self.test_and_set(asid) {
+                return self.generation | asid as u64;
+            }
+        }
+
+        let count = 1 << self.bits;
+        for _ in 0..2 {
+            while self.next < count {
+                let asid = self.next;
+                self.next += 1;
+                if !self.test_and_set(asid) {
+                    return self.generation | asid as u64;
+                }
+            }
+            self.rollover();
+        }
+        /* riscv64_asid_init() makes sure a rollover leaves some */
+        unreachable!("no ASID left after a rollover");
+    }
+}
+
+static ASID_ALLOCATOR: SpinLock<AsidAllocator> =
+    SpinLock::new(AsidAllocator::new());
+
+/*
+ * Find how many ASID bits the hart implements by writing all ones
+ * to the field and reading back what sticks. The kernel mappings
+ * are global, so they stay put while the ASID changes.
+ */
+pub fn riscv64_asid_init() {
+    let val: usize;
+    unsafe {
+        asm!(
+            "csrr {old}, satp",
+            "or {tmp}, {old}, {ones}",
+            "csrw satp, {tmp}",
+            "csrr {val}, satp",
+            "csrw satp, {old}",
+            "sfence.vma",
+            old = out(reg) _,
+            tmp = out(reg) _,
+            val = out(reg) val,
+            ones = in(reg) SATP_ASID_MASK << SATP_ASID_SHIFT,
+        );
+    }
+    let mut bits = ((val >> SATP_ASID_SHIFT) & SATP_ASID_MASK).count_ones();
+
+    /* a rollover keeps the ASID of each cpu, make sure there
+     * are plenty more; otherwise do without */
+    if bits != 0 && (1 << bits) < 2 * NR_CPUS {
+        dprint!(INFO, "asid: only {} bits for {} cpus, not using them\n",
+                bits, NR_CPUS);
+        bits = 0;
+    }
+
+    let mut allocator = ASID_ALLOCATOR.lock();
+    allocator.bits = bits as usize;
+    allocator.rollover();
+    /* no cpu has used an ASID yet */
+    allocator.flush_pending = 0;
+}
+
+pub fn asid_bits() -> usize {
+    ASID_ALLOCATOR.lock().bits
+}
+
+/* The ASID of the context id |ctx| */
+pub fn asid_of(ctx: u64) -> usize {
+    (ctx & ASID_ALLOCATOR.lock().asid_mask()) as usize
+}
+
+/*
+ * Make |ctx| a context id of the current generation on the way to
+ * switching this cpu to its aspace. Returns the ASID to run with,
+ * and whether the TLB has to be flushed once it is loaded.
+ */
+pub fn asid_context_switch(ctx: &mut u64) -> (usize, bool) {
+    let cpu = arch_curr_cpu_num();
+    let mut allocator = ASID_ALLOCATOR.lock();
+
+    /* without ASIDs every aspace shares one */
+    if allocator.bits == 0 {
+        return (KERNEL_ASID, true);
+    }
+
+    let generation = *ctx & !allocator.asid_mask();
+    if *ctx == 0 || generation != allocator.generation {
+        *ctx = allocator.new_context(*ctx);
+    }
+    allocator.active[cpu] = *ctx;
+
+    let flush = (allocator.flush_pending & (1 << cpu)) != 0;
+    allocator.flush_pending &= !(1 << cpu);
+    ((*ctx & allocator.asid_mask()) as usize, flush)
+}
+
+/* This cpu is back to running the kernel alone */
+pub fn asid_switch_to_kernel() {
+    let cpu = arch_curr_cpu_num();
+    ASID_ALLOCATOR.lock().active[cpu] = 0;
+}
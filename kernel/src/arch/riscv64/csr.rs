@@ -14,3 +14,27 @@ pub const SR_FS_INITIAL: usize  = 0x00002000;
 pub const SR_FS_CLEAN: usize    = 0x00004000;
 pub const SR_FS_DIRTY: usize    = 0x00006000;
 */
+
+pub const SR_SPP: usize         = 0x00000100;  /* Previously Supervisor */
+/*
+pub const SR_SIE: usize         = 0x00000002;  // Interrupt Enable
+pub const SR_SPIE: usize        = 0x00000020;  // Previous SIE
+pub const SR_SUM: usize         = 0x00040000;  // Access User Memory
+*/
+
+/* Interrupt causes have the top bit of scause set */
+pub const SCAUSE_IRQ_FLAG: usize = 1 << 63;
+
+/* Exception causes */
+pub const EXC_INST_MISALIGNED: usize    = 0;
+pub const EXC_INST_ACCESS: usize        = 1;
+pub const EXC_INST_ILLEGAL: usize       = 2;
+pub const EXC_BREAKPOINT: usize         = 3;
+pub const EXC_LOAD_MISALIGNED: usize    = 4;
+pub const EXC_LOAD_ACCESS: usize        = 5;
+pub const EXC_STORE_MISALIGNED: usize   = 6;
+pub const EXC_STORE_ACCESS: usize       = 7;
+pub const EXC_SYSCALL: usize            = 8;
+pub const EXC_INST_PAGE_FAULT: usize    = 12;
+pub const EXC_LOAD_PAGE_FAULT: usize    = 13;
+pub const EXC_STORE_PAGE_FAULT: usize   = 15;
@@ -0,0 +1,230 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * Traps taken in supervisor mode.
+ * Only the kernel runs so far, so a trap comes in on the stack
+ * of whatever was interrupted and leaves the registers there in
+ * an Iframe for the handler.
+ */
+
+use core::arch::asm;
+use super::csr::*;
+use crate::{dprint, CRITICAL, vaddr_t};
+use crate::vm::vm::{
+    vmm_page_fault_handler, VMM_PF_FLAG_WRITE, VMM_PF_FLAG_USER,
+    VMM_PF_FLAG_INSTRUCTION,
+};
+use crate::platform::platform_halt;
+
+/* The registers of the interrupted context, |regs| indexed by
+ * register number; x0 is always zero. */
+#[repr(C)]
+pub struct Iframe {
+    pub regs: [usize; 32],
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub scause: usize,
+    pub stval: usize,
+}
+
+const IFRAME_SIZE: usize = core::mem::size_of::<Iframe>();
+const IFRAME_SP: usize = 2 * 8;
+const IFRAME_SEPC: usize = 32 * 8;
+const IFRAME_SSTATUS: usize = 33 * 8;
+const IFRAME_SCAUSE: usize = 34 * 8;
+const IFRAME_STVAL: usize = 35 * 8;
+
+/* ABI names of x0 to x31 */
+const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+#[naked]
+#[repr(align(4))]
+unsafe extern "C"
+fn riscv64_exception_entry() -> ! {
+    asm!(
+        "addi sp, sp, -{iframe_size}",
+        "sd x1, 8(sp)
+         sd x3, 24(sp)
+         sd x4, 32(sp)
+         sd x5, 40(sp)",
+        "sd x6, 48(sp)
+         sd x7, 56(sp)
+         sd x8, 64(sp)
+         sd x9, 72(sp)",
+        "sd x10, 80(sp)
+         sd x11, 88(sp)
+         sd x12, 96(sp)
+         sd x13, 104(sp)",
+        "sd x14, 112(sp)
+         sd x15, 120(sp)
+         sd x16, 128(sp)
+         sd x17, 136(sp)",
+        "sd x18, 144(sp)
+         sd x19, 152(sp)
+         sd x20, 160(sp)
+         sd x21, 168(sp)",
+        "sd x22, 176(sp)
+         sd x23, 184(sp)
+         sd x24, 192(sp)
+         sd x25, 200(sp)",
+        "sd x26, 208(sp)
+         sd x27, 216(sp)
+         sd x28, 224(sp)
+         sd x29, 232(sp)",
+        "sd x30, 240(sp)
+         sd x31, 248(sp)",
+        /* sp as it was before the trap */
+        "addi t0, sp, {iframe_size}
+         sd t0, {iframe_sp}(sp)
+         sd zero, 0(sp)",
+
+        "csrr t0, sepc
+         sd t0, {iframe_sepc}(sp)
+         csrr t0, sstatus
+         sd t0, {iframe_sstatus}(sp)
+         csrr t0, scause
+         sd t0, {iframe_scause}(sp)
+         csrr t0, stval
+         sd t0, {iframe_stval}(sp)",
+
+        "mv a0, sp
+         call {handler}",
+
+        /* the handler may have moved sepc on */
+        "ld t0, {iframe_sepc}(sp)
+         csrw sepc, t0
+         ld t0, {iframe_sstatus}(sp)
+         csrw sstatus, t0",
+
+        "ld x1, 8(sp)
+         ld x3, 24(sp)
+         ld x4, 32(sp)
+         ld x5, 40(sp)",
+        "ld x6, 48(sp)
+         ld x7, 56(sp)
+         ld x8, 64(sp)
+         ld x9, 72(sp)",
+        "ld x10, 80(sp)
+         ld x11, 88(sp)
+         ld x12, 96(sp)
+         ld x13, 104(sp)",
+        "ld x14, 112(sp)
+         ld x15, 120(sp)
+         ld x16, 128(sp)
+         ld x17, 136(sp)",
+        "ld x18, 144(sp)
+         ld x19, 152(sp)
+         ld x20, 160(sp)
+         ld x21, 168(sp)",
+        "ld x22, 176(sp)
+         ld x23, 184(sp)
+         ld x24, 192(sp)
+         ld x25, 200(sp)",
+        "ld x26, 208(sp)
+         ld x27, 216(sp)
+         ld x28, 224(sp)
+         ld x29, 232(sp)",
+        "ld x30, 240(sp)
+         ld x31, 248(sp)",
+        "addi sp, sp, {iframe_size}
+         sret",
+
+        iframe_size = const IFRAME_SIZE,
+        iframe_sp = const IFRAME_SP,
+        iframe_sepc = const IFRAME_SEPC,
+        iframe_sstatus = const IFRAME_SSTATUS,
+        iframe_scause = const IFRAME_SCAUSE,
+        iframe_stval = const IFRAME_STVAL,
+        handler = sym riscv64_exception_handler,
+        options(noreturn)
+    );
+}
+
+/* Point stvec at the entry above */
+pub fn riscv64_exception_init() {
+    unsafe {
+        asm!("csrw stvec, {0}", in(reg) riscv64_exception_entry as usize);
+    }
+}
+
+fn cause_name(scause: usize) -> &'static str {
+    if (scause & SCAUSE_IRQ_FLAG) != 0 {
+        return "interrupt";
+    }
+    match scause {
+        EXC_INST_MISALIGNED => "instruction address misaligned",
+        EXC_INST_ACCESS => "instruction access fault",
+        EXC_INST_ILLEGAL => "illegal instruction",
+        EXC_BREAKPOINT => "breakpoint",
+        EXC_LOAD_MISALIGNED => "load address misaligned",
+        EXC_LOAD_ACCESS => "load access fault",
+        EXC_STORE_MISALIGNED => "store address misaligned",
+        EXC_STORE_ACCESS => "store access fault",
+        EXC_SYSCALL => "environment call",
+        EXC_INST_PAGE_FAULT => "instruction page fault",
+        EXC_LOAD_PAGE_FAULT => "load page fault",
+        EXC_STORE_PAGE_FAULT => "store page fault",
+        _ => "unknown exception",
+    }
+}
+
+fn dump_iframe(iframe: &Iframe) {
+    dprint!(CRITICAL, "sepc    {:#018x} sstatus {:#018x}\n",
+            iframe.sepc, iframe.sstatus);
+    dprint!(CRITICAL, "scause  {:#018x} stval   {:#018x}\n",
+            iframe.scause, iframe.stval);
+    for i in (0..32).step_by(2) {
+        dprint!(CRITICAL, "{:>4} {:#018x} {:>4} {:#018x}\n",
+                REG_NAMES[i], iframe.regs[i],
+                REG_NAMES[i + 1], iframe.regs[i + 1]);
+    }
+}
+
+/* Nothing can be done about this trap; say why and stop */
+fn exception_die(iframe: &Iframe, msg: &str) -> ! {
+    dprint!(CRITICAL, "{}: {} ({}) at {:#x}\n", msg,
+            cause_name(iframe.scause), iframe.scause, iframe.sepc);
+    dump_iframe(iframe);
+    platform_halt();
+}
+
+fn riscv64_page_fault_handler(iframe: &mut Iframe) {
+    let addr: vaddr_t = iframe.stval;
+    let mut pf_flags = 0;
+    match iframe.scause {
+        EXC_INST_PAGE_FAULT => pf_flags |= VMM_PF_FLAG_INSTRUCTION,
+        EXC_STORE_PAGE_FAULT => pf_flags |= VMM_PF_FLAG_WRITE,
+        _ => (),
+    }
+    if (iframe.sstatus & SR_SPP) == 0 {
+        pf_flags |= VMM_PF_FLAG_USER;
+    }
+
+    if let Err(e) = vmm_page_fault_handler(addr, pf_flags) {
+        dprint!(CRITICAL, "page fault at {:#x} unresolved: {:?}\n",
+                addr, e);
+        exception_die(iframe, "unhandled kernel fault");
+    }
+}
+
+extern "C" fn riscv64_exception_handler(iframe: &mut Iframe) {
+    if (iframe.scause & SCAUSE_IRQ_FLAG) != 0 {
+        exception_die(iframe, "unexpected interrupt");
+    }
+
+    match iframe.scause {
+        EXC_INST_PAGE_FAULT |
+        EXC_LOAD_PAGE_FAULT |
+        EXC_STORE_PAGE_FAULT => riscv64_page_fault_handler(iframe),
+        _ => exception_die(iframe, "unhandled exception"),
+    }
+}
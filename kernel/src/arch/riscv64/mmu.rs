@@ -8,17 +8,41 @@ extern crate alloc;
 
 use core::cmp::min;
 use core::alloc::*;
+use core::arch::asm;
 use super::defines::*;
 use crate::errors::ErrNO;
-use crate::vm::physmap::paddr_to_physmap;
+use crate::{IS_ALIGNED, IS_PAGE_ALIGNED};
+use crate::vm::physmap::{paddr_to_physmap, physmap_max_size};
+use crate::vm::pmm::{
+    pmm_alloc_page, pmm_free_page, pmm_free, pmm_paddr_to_page,
+    PMM_ALLOC_FLAG_ZERO,
+};
+use crate::vm::page::vm_page_t;
+use crate::vm::vm_page_state;
+use crate::lib::list::List;
+use super::asid::{
+    asid_bits, asid_of, asid_context_switch, asid_switch_to_kernel,
+    SATP_ASID_SHIFT,
+};
+use super::tlb::{TlbBatch, flush_tlb_asid, local_flush_tlb_all};
+use crate::{dprint, CRITICAL, INFO, WARN, paddr_t, vaddr_t};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /*
  * PTE format:
- * | XLEN-1  10 | 9             8 | 7 | 6 | 5 | 4 | 3 | 2 | 1 | 0
- *       PFN      reserved for SW   D   A   G   U   X   W   R   V
+ * | 63 | 62 61 | 60   54 | 53  10 | 9  8 | 7 | 6 | 5 | 4 | 3 | 2 | 1 | 0
+ *    N   PBMT   reserved    PFN     RSW    D   A   G   U   X   W   R   V
  */
 
 const _PAGE_PFN_SHIFT: usize = 10;
+const _PAGE_PFN_MASK: usize = ((1 << 44) - 1) << _PAGE_PFN_SHIFT;
+
+/* Page-based memory types of Svpbmt, 0 keeps the PMA of the memory */
+const _PAGE_PBMT_NC   : usize = 1 << 61;   /* Non-cacheable, idempotent */
+const _PAGE_PBMT_IO   : usize = 2 << 61;   /* Non-cacheable, strongly
+                                             * ordered, for I/O */
+const _PAGE_PBMT_MASK : usize = 3 << 61;
 
 const _PAGE_PRESENT : usize = 1 << 0;     /* Valid */
 const _PAGE_READ    : usize = 1 << 1;     /* Readable */
@@ -43,11 +67,46 @@ pub const PAGE_KERNEL: usize =
 
 pub const PAGE_KERNEL_EXEC : usize = PAGE_KERNEL | _PAGE_EXEC;
 
+pub const PAGE_KERNEL_READ: usize =
+    _PAGE_PRESENT | _PAGE_READ |
+    _PAGE_GLOBAL | _PAGE_ACCESSED | _PAGE_DIRTY;
+
+pub const PAGE_KERNEL_READ_EXEC: usize = PAGE_KERNEL_READ | _PAGE_EXEC;
+
 /*
- * The RISC-V ISA doesn't yet specify how to query or modify PMAs,
- * so we can't change the properties of memory regions.
+ * The RISC-V ISA doesn't specify how to query or modify PMAs,
+ * but with Svpbmt a mapping may override them with its own memory
+ * type. Without it, mappings of devices rely on their PMAs being
+ * uncached already.
  */
-pub const PAGE_IOREMAP: usize = PAGE_KERNEL;
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+
+/* Called once the boot hart is known to implement Svpbmt */
+pub fn riscv64_mmu_enable_svpbmt() {
+    SVPBMT.store(true, Ordering::Relaxed);
+}
+
+pub fn arch_mmu_has_svpbmt() -> bool {
+    SVPBMT.load(Ordering::Relaxed)
+}
+
+/* The memory type bits of |cache_flags| */
+fn pbmt_bits(cache_flags: u32) -> usize {
+    if !arch_mmu_has_svpbmt() {
+        return 0;
+    }
+    match cache_flags & ARCH_MMU_FLAG_CACHE_MASK {
+        ARCH_MMU_FLAG_UNCACHED_DEVICE => _PAGE_PBMT_IO,
+        ARCH_MMU_FLAG_UNCACHED |
+        ARCH_MMU_FLAG_WRITE_COMBINING => _PAGE_PBMT_NC,
+        _ => 0,
+    }
+}
+
+/* How peripherals are mapped */
+pub fn page_ioremap() -> usize {
+    PAGE_KERNEL | pbmt_bits(ARCH_MMU_FLAG_UNCACHED_DEVICE)
+}
 
 const PAGE_TABLE_ENTRIES: usize = 1 << (PAGE_SHIFT - 3);
 
@@ -61,6 +120,14 @@ impl PageTable {
         self.0[index] = (pfn << _PAGE_PFN_SHIFT) | prot;
     }
 
+    fn entry(&self, index: usize) -> usize {
+        self.0[index]
+    }
+
+    fn set_entry(&mut self, index: usize, pte: usize) {
+        self.0[index] = pte;
+    }
+
     fn item_present(&self, index: usize) -> bool {
         (self.0[index] & _PAGE_PRESENT) == _PAGE_PRESENT
     }
@@ -70,24 +137,109 @@ impl PageTable {
     }
 
     fn item_descend(&self, index: usize) -> *mut PageTable {
-        ((self.0[index] >> _PAGE_PFN_SHIFT) << PAGE_SHIFT) as *mut PageTable
+        pte_paddr(self.0[index]) as *mut PageTable
     }
 }
 
 pub static mut SWAPPER_PG_DIR: PageTable = PageTable::ZERO;
 pub static mut SWAPPER_SATP: usize = 0;
 
-/* Todo: Check KERNEL_ASPACE_BITS < 57 because SV57 is
- * the highest mode that is supported. */
-const MMU_LEVELS: usize =
-    (KERNEL_ASPACE_BITS - PAGE_SHIFT) / (PAGE_SHIFT - 3) + 1;
+/*
+ * The paging modes the kernel can run in, deepest first.
+ * The kernel aspace is the top of the canonical upper half
+ * that reaches down to |aspace_base|, and the physmap is
+ * mapped at its base.
+ */
+struct MmuMode {
+    name: &'static str,
+    satp_mode: usize,
+    levels: usize,
+    aspace_base: vaddr_t,
+    physmap_size: usize,
+}
+
+static MMU_MODES: [MmuMode; 3] = [
+    MmuMode {
+        name: "sv57",
+        satp_mode: SATP_MODE_57,
+        levels: 5,
+        aspace_base: 0xffff_0000_0000_0000,
+        physmap_size: 1 << 39,
+    },
+    MmuMode {
+        name: "sv48",
+        satp_mode: SATP_MODE_48,
+        levels: 4,
+        aspace_base: 0xffff_8000_0000_0000,
+        physmap_size: 1 << 39,
+    },
+    /* the upper half is only 256GB, share it with the kernel */
+    MmuMode {
+        name: "sv39",
+        satp_mode: SATP_MODE_39,
+        levels: 3,
+        aspace_base: 0xffff_ffc0_0000_0000,
+        physmap_size: 1 << 36,
+    },
+];
+
+/* Index into MMU_MODES of the mode in use, set by
+ * riscv64_probe_mmu_mode before anything is mapped.
+ * It is an index rather than a reference because the probe
+ * runs at the physical address, before relocation. */
+static mut MMU_MODE: usize = 0;
+
+fn mmu_mode() -> &'static MmuMode {
+    unsafe { &MMU_MODES[MMU_MODE] }
+}
+
+pub fn mmu_levels() -> usize {
+    mmu_mode().levels
+}
+
+pub fn mmu_mode_name() -> &'static str {
+    mmu_mode().name
+}
+
+/* Virtual address where the kernel address space begins.
+ * Below this is the user address space. */
+pub fn kernel_aspace_base() -> vaddr_t {
+    mmu_mode().aspace_base
+}
+
+pub fn kernel_aspace_size() -> usize {
+    0usize.wrapping_sub(kernel_aspace_base())
+}
+
+/* User aspaces leave the first 16M unmapped, to catch
+ * null pointers with large offsets */
+pub const USER_ASPACE_BASE: vaddr_t = 0x0100_0000;
+
+/* The lower half mirrors the kernel's upper half */
+pub fn user_aspace_size() -> usize {
+    kernel_aspace_size() - USER_ASPACE_BASE
+}
+
+/* How much of physical memory is mapped at the base of
+ * the kernel aspace at boot */
+pub fn arch_physmap_size() -> usize {
+    mmu_mode().physmap_size
+}
 
 macro_rules! LEVEL_SHIFT {
     ($level: expr) => {
-        ((MMU_LEVELS - ($level)) * (PAGE_SHIFT - 3) + 3)
+        ((mmu_levels() - ($level)) * (PAGE_SHIFT - 3) + 3)
     }
 }
 
+/* Leaves are used up to 512GB, above that a single leaf
+ * would cover more than any physmap. */
+const MAX_LEAF_SHIFT: usize = 39;
+
+fn leaf_allowed(level: usize) -> bool {
+    LEVEL_SHIFT!(level) <= MAX_LEAF_SHIFT
+}
+
 macro_rules! LEVEL_SIZE {
     ($level: expr) => {
         1 << LEVEL_SHIFT!($level)
@@ -100,12 +252,6 @@ macro_rules! LEVEL_MASK {
     }
 }
 
-macro_rules! LEVEL_PA_TO_PFN {
-    ($pa: expr, $level: expr) => {
-        (($pa) >> LEVEL_SHIFT!($level))
-    }
-}
-
 macro_rules! PA_TO_PFN {
     ($pa: expr) => {
         (($pa) >> PAGE_SHIFT)
@@ -129,7 +275,7 @@ where F1: Fn(usize) -> usize
     let mut off = 0;
     while off < len {
         let index = vaddr_to_index(vaddr + off, level);
-        if level == (MMU_LEVELS-1) {
+        if level == (mmu_levels()-1) {
             /* generate a standard leaf mapping */
             table.mk_item(index, PA_TO_PFN!(paddr + off), prot);
 
@@ -137,14 +283,12 @@ where F1: Fn(usize) -> usize
             continue;
         }
         if !table.item_present(index) {
-            if (level != 0) &&
+            if leaf_allowed(level) &&
                 aligned_in_level(vaddr+off, level) &&
                 aligned_in_level(paddr+off, level) &&
                 ((len - off) >= LEVEL_SIZE!(level)) {
                 /* set up a large leaf at this level */
-                table.mk_item(index,
-                              LEVEL_PA_TO_PFN!(paddr + off, level),
-                              prot);
+                table.mk_item(index, PA_TO_PFN!(paddr + off), prot);
 
                 off += LEVEL_SIZE!(level);
                 continue;
@@ -208,17 +352,827 @@ pub fn riscv64_boot_map_v(vaddr: usize, paddr: usize, len: usize,
     }
 }
 
+/*
+ * Arch independent mmu flags of a mapping
+ */
+pub const ARCH_MMU_FLAG_CACHED:          u32 = 0;
+pub const ARCH_MMU_FLAG_UNCACHED:        u32 = 1;
+pub const ARCH_MMU_FLAG_UNCACHED_DEVICE: u32 = 2;
+pub const ARCH_MMU_FLAG_WRITE_COMBINING: u32 = 3;
+pub const ARCH_MMU_FLAG_CACHE_MASK:      u32 = 3;
+pub const ARCH_MMU_FLAG_PERM_USER:       u32 = 1 << 2;
+pub const ARCH_MMU_FLAG_PERM_READ:       u32 = 1 << 3;
+pub const ARCH_MMU_FLAG_PERM_WRITE:      u32 = 1 << 4;
+pub const ARCH_MMU_FLAG_PERM_EXECUTE:    u32 = 1 << 5;
+pub const ARCH_MMU_FLAG_INVALID:         u32 = 1 << 7;
+
+/* A leaf needs one of these, without any it is a table pointer */
+const ARCH_MMU_FLAG_PERM_RWX_MASK: u32 =
+    ARCH_MMU_FLAG_PERM_READ | ARCH_MMU_FLAG_PERM_WRITE |
+    ARCH_MMU_FLAG_PERM_EXECUTE;
+
+/* Flags of an ArchMmu */
+pub const ARCH_ASPACE_FLAG_KERNEL: u32 = 1 << 0;
+
+fn pte_paddr(pte: usize) -> paddr_t {
+    ((pte & _PAGE_PFN_MASK) >> _PAGE_PFN_SHIFT) << PAGE_SHIFT
+}
+
+fn pte_is_present(pte: usize) -> bool {
+    (pte & _PAGE_PRESENT) != 0
+}
+
+fn pte_is_leaf(pte: usize) -> bool {
+    pte_is_present(pte) && (pte & _PAGE_LEAF) != 0
+}
+
+/* The bits of a leaf pte for the ARCH_MMU_FLAG_* |flags|.
+ * Accessed and dirty are set up front, for the harts which fault
+ * instead of updating them; writable implies readable, as the ISA
+ * reserves write-only. */
+fn mmu_flags_to_pte(flags: u32, global: bool) -> usize {
+    let mut pte = _PAGE_PRESENT | _PAGE_ACCESSED | _PAGE_DIRTY;
+    if (flags & (ARCH_MMU_FLAG_PERM_READ | ARCH_MMU_FLAG_PERM_WRITE)) != 0 {
+        pte |= _PAGE_READ;
+    }
+    if (flags & ARCH_MMU_FLAG_PERM_WRITE) != 0 {
+        pte |= _PAGE_WRITE;
+    }
+    if (flags & ARCH_MMU_FLAG_PERM_EXECUTE) != 0 {
+        pte |= _PAGE_EXEC;
+    }
+    if (flags & ARCH_MMU_FLAG_PERM_USER) != 0 {
+        pte |= _PAGE_USER;
+    }
+    if global {
+        pte |= _PAGE_GLOBAL;
+    }
+    pte | pbmt_bits(flags)
+}
+
+fn pte_to_mmu_flags(pte: usize) -> u32 {
+    let mut flags = match pte & _PAGE_PBMT_MASK {
+        _PAGE_PBMT_IO => ARCH_MMU_FLAG_UNCACHED_DEVICE,
+        _PAGE_PBMT_NC => ARCH_MMU_FLAG_UNCACHED,
+        _ => ARCH_MMU_FLAG_CACHED,
+    };
+    if (pte & _PAGE_READ) != 0 {
+        flags |= ARCH_MMU_FLAG_PERM_READ;
+    }
+    if (pte & _PAGE_WRITE) != 0 {
+        flags |= ARCH_MMU_FLAG_PERM_WRITE;
+    }
+    if (pte & _PAGE_EXEC) != 0 {
+        flags |= ARCH_MMU_FLAG_PERM_EXECUTE;
+    }
+    if (pte & _PAGE_USER) != 0 {
+        flags |= ARCH_MMU_FLAG_PERM_USER;
+    }
+    flags
+}
+
+fn table_at(pa: paddr_t) -> &'static mut PageTable {
+    unsafe { &mut *(paddr_to_physmap(pa) as *mut PageTable) }
+}
+
+/* The vm_page of the table at |pa| if it came from the PMM;
+ * the tables set up at boot live in the kernel image or in
+ * the boot heap and aren't accounted. */
+fn table_page(pa: paddr_t) -> Option<NonNull<vm_page_t>> {
+    pmm_paddr_to_page(pa).filter(|p| unsafe {
+        p.as_ref().state() == vm_page_state::MMU
+    })
+}
+
+/* Keep count of the valid entries of the table at |pa| */
+fn table_add_mappings(pa: paddr_t, delta: i32) {
+    if let Some(mut page) = table_page(pa) {
+        let mmu = unsafe { page.as_mut().mmu_mut() };
+        mmu.num_mappings = (mmu.num_mappings as i32 + delta) as u32;
+    }
+}
+
+fn table_is_empty(pa: paddr_t) -> bool {
+    match table_page(pa) {
+        Some(page) => unsafe { page.as_ref().mmu().num_mappings == 0 },
+        None => false,
+    }
+}
+
+/* A zeroed page table page from the PMM, in the MMU state. */
+fn alloc_page_table() -> Result<paddr_t, ErrNO> {
+    let mut page = pmm_alloc_page(PMM_ALLOC_FLAG_ZERO, 0)?;
+    unsafe {
+        page.as_mut().set_state(vm_page_state::MMU);
+        Ok(page.as_ref().paddr())
+    }
+}
+
+/* The part of [vaddr, vaddr + len) that falls into
+ * the entry of |level| covering |vaddr|. */
+fn level_chunk(vaddr: vaddr_t, len: usize, level: usize) -> usize {
+    min(LEVEL_SIZE!(level) - (vaddr & !LEVEL_MASK!(level)), len)
+}
+
+/* Map [vaddr, vaddr + len) to the contiguous [paddr, paddr + len)
+ * below the table at |table_pa|, with large pages where possible.
+ * |mapped| grows by the bytes mapped, always a prefix of the range. */
+fn map_range(table_pa: paddr_t, level: usize,
+             vaddr: vaddr_t, paddr: paddr_t, len: usize,
+             pte_flags: usize, mapped: &mut usize)
+    -> Result<(), ErrNO> {
+
+    let table = table_at(table_pa);
+    let mut off = 0;
+    while off < len {
+        let va = vaddr + off;
+        let pa = paddr + off;
+        let index = vaddr_to_index(va, level);
+        let chunk = level_chunk(va, len - off, level);
+        let pte = table.entry(index);
+
+        if level == (mmu_levels()-1) ||
+           (!pte_is_present(pte) && leaf_allowed(level) &&
+            chunk == LEVEL_SIZE!(level) && aligned_in_level(pa, level)) {
+            if pte_is_present(pte) {
+                return Err(ErrNO::BadState);
+            }
+
+            table.mk_item(index, PA_TO_PFN!(pa), pte_flags);
+            table_add_mappings(table_pa, 1);
+            *mapped += chunk;
+        } else {
+            if !pte_is_present(pte) {
+                let lower_pa = alloc_page_table()?;
+                table.mk_item(index, PA_TO_PFN!(lower_pa), PAGE_TABLE);
+                table_add_mappings(table_pa, 1);
+            } else if pte_is_leaf(pte) {
+                return Err(ErrNO::BadState);
+            }
+
+            map_range(pte_paddr(table.entry(index)), level+1,
+                      va, pa, chunk, pte_flags, mapped)?;
+        }
+
+        off += chunk;
+    }
+    Ok(())
+}
+
+/* Replace the large leaf at |index| of the table at |table_pa|
+ * by a table of the next level mapping the same. */
+fn split_large_page(table_pa: paddr_t, level: usize, index: usize)
+    -> Result<(), ErrNO> {
+
+    let table = table_at(table_pa);
+    let pte = table.entry(index);
+    let lower_pa = alloc_page_table()?;
+    let lower = table_at(lower_pa);
+
+    let attrs = pte & !_PAGE_PFN_MASK;
+    let size = LEVEL_SIZE!(level + 1);
+    for i in 0..PAGE_TABLE_ENTRIES {
+        lower.mk_item(i, PA_TO_PFN!(pte_paddr(pte) + i * size), attrs);
+    }
+    table_add_mappings(lower_pa, PAGE_TABLE_ENTRIES as i32);
+
+    table.mk_item(index, PA_TO_PFN!(lower_pa), PAGE_TABLE);
+    Ok(())
+}
+
+/* The kernel keeps the tables below its shared top level */
+const KERNEL_KEEP_LEVEL: usize = 2;
+
+/* What an unmap gathers while it walks the tables */
+struct UnmapContext {
+    /* tables at levels below this one are kept even if empty */
+    keep_level: usize,
+    /* bytes that were mapped */
+    unmapped: usize,
+    /* the tables left empty, freed once the TLBs are flushed */
+    freed: List<vm_page_t>,
+    batch: TlbBatch,
+}
+
+impl UnmapContext {
+    fn new(keep_level: usize, batch: TlbBatch) -> Self {
+        Self {
+            keep_level,
+            unmapped: 0,
+            freed: List::new(),
+            batch,
+        }
+    }
+
+    /* Flush what was unmapped and free the empty tables.
+     * Returns the number of bytes that were mapped. */
+    fn finish(mut self) -> usize {
+        /* nothing may still walk the tables once they are reused,
+         * which takes more than the flush by address */
+        if self.freed.is_empty() {
+            self.batch.flush();
+        } else {
+            self.batch.flush_all();
+        }
+        pmm_free(&mut self.freed);
+        self.unmapped
+    }
+}
+
+/* Unmap [vaddr, vaddr + len) below the table at |table_pa|,
+ * splitting the large pages that are partly in the range.
+ * Tables left empty are unhooked and put on the free list of
+ * |ctx|, except for those below its keep level. What was
+ * unmapped goes to its batch to be flushed. */
+fn unmap_range(table_pa: paddr_t, level: usize,
+               vaddr: vaddr_t, len: usize, ctx: &mut UnmapContext)
+    -> Result<(), ErrNO> {
+
+    let table = table_at(table_pa);
+    let mut off = 0;
+    while off < len {
+        let va = vaddr + off;
+        let index = vaddr_to_index(va, level);
+        let chunk = level_chunk(va, len - off, level);
+        let pte = table.entry(index);
+
+        if !pte_is_present(pte) {
+            off += chunk;
+            continue;
+        }
+
+        if pte_is_leaf(pte) {
+            if chunk == LEVEL_SIZE!(level) || level == (mmu_levels()-1) {
+                table.set_entry(index, 0);
+                table_add_mappings(table_pa, -1);
+                ctx.unmapped += chunk;
+                ctx.batch.add(va, chunk);
+                off += chunk;
+                continue;
+            }
+            split_large_page(table_pa, level, index)?;
+        }
+
+        let lower_pa = pte_paddr(table.entry(index));
+        unmap_range(lower_pa, level+1, va, chunk, ctx)?;
+
+        if level + 1 >= ctx.keep_level && table_is_empty(lower_pa) {
+            table.set_entry(index, 0);
+            table_add_mappings(table_pa, -1);
+            if let Some(page) = table_page(lower_pa) {
+                ctx.freed.add_tail(page);
+            }
+        }
+
+        off += chunk;
+    }
+    Ok(())
+}
+
+/* Change the leaf ptes of [vaddr, vaddr + len) to |pte_flags|,
+ * keeping the addresses they map. */
+fn protect_range(table_pa: paddr_t, level: usize,
+                 vaddr: vaddr_t, len: usize, pte_flags: usize)
+    -> Result<(), ErrNO> {
+
+    let table = table_at(table_pa);
+    let mut off = 0;
+    while off < len {
+        let va = vaddr + off;
+        let index = vaddr_to_index(va, level);
+        let chunk = level_chunk(va, len - off, level);
+        let pte = table.entry(index);
+
+        if !pte_is_present(pte) {
+            off += chunk;
+            continue;
+        }
+
+        if pte_is_leaf(pte) {
+            if chunk == LEVEL_SIZE!(level) || level == (mmu_levels()-1) {
+                table.mk_item(index, PA_TO_PFN!(pte_paddr(pte)), pte_flags);
+                off += chunk;
+                continue;
+            }
+            split_large_page(table_pa, level, index)?;
+        }
+
+        protect_range(pte_paddr(table.entry(index)), level+1,
+                      va, chunk, pte_flags)?;
+        off += chunk;
+    }
+    Ok(())
+}
+
+/* Clear the accessed bit of the leaf ptes of [vaddr, vaddr + len),
+ * calling |accessed| with the vaddr and paddr of each that had it. */
+fn harvest_range<F>(table_pa: paddr_t, level: usize,
+                    vaddr: vaddr_t, len: usize, accessed: &mut F)
+where F: FnMut(vaddr_t, paddr_t)
+{
+    let table = table_at(table_pa);
+    let mut off = 0;
+    while off < len {
+        let va = vaddr + off;
+        let index = vaddr_to_index(va, level);
+        let chunk = level_chunk(va, len - off, level);
+        let pte = table.entry(index);
+
+        if pte_is_leaf(pte) {
+            if (pte & _PAGE_ACCESSED) != 0 {
+                table.set_entry(index, pte & !_PAGE_ACCESSED);
+                let page_va = va & LEVEL_MASK!(level);
+                accessed(page_va, pte_paddr(pte));
+            }
+        } else if pte_is_present(pte) {
+            harvest_range(pte_paddr(pte), level+1, va, chunk, accessed);
+        }
+        off += chunk;
+    }
+}
+
+fn swapper_pg_dir_pa() -> paddr_t {
+    unsafe { (SWAPPER_SATP & SATP_PPN_MASK) << PAGE_SHIFT }
+}
+
+/*
+ * The page tables of an address space.
+ * All of the kernel aspace is shared with the user ones, which get
+ * a copy of the top level entries outside of their own range;
+ * the kernel aspace never frees the tables those point to.
+ */
+pub struct ArchMmu {
+    base: vaddr_t,
+    size: usize,
+    flags: u32,
+    /* the top level table, 0 before init() and after destroy(),
+     * when nothing can be mapped */
+    pt_phys: paddr_t,
+    /* pages currently mapped */
+    mapped_pages: usize,
+    /* the context id of the ASID, 0 until first switched to */
+    asid_ctx: u64,
+}
+
+impl ArchMmu {
+    pub const fn new() -> Self {
+        Self {
+            base: 0,
+            size: 0,
+            flags: 0,
+            pt_phys: 0,
+            mapped_pages: 0,
+            asid_ctx: 0,
+        }
+    }
+
+    /* Set up the top level table for [base, base + size) */
+    pub fn init(&mut self, base: vaddr_t, size: usize, flags: u32)
+        -> Result<(), ErrNO> {
+
+        if !IS_PAGE_ALIGNED!(base) || !IS_PAGE_ALIGNED!(size) ||
+           base.checked_add(size - 1).is_none() {
+            return Err(ErrNO::InvalidArgs);
+        }
+        if self.pt_phys != 0 {
+            return Err(ErrNO::BadState);
+        }
+
+        self.base = base;
+        self.size = size;
+        self.flags = flags;
+
+        if self.is_kernel() {
+            /* the kernel aspace lives in the boot tables. The user
+             * aspaces copy its top level when they are made, so
+             * each entry of it gets its table now and keeps it. */
+            let pt_phys = swapper_pg_dir_pa();
+            let pt = table_at(pt_phys);
+            let first = vaddr_to_index(base, 0);
+            let last = vaddr_to_index(base + size - 1, 0);
+            for i in first..=last {
+                if !pte_is_present(pt.entry(i)) {
+                    let lower_pa = alloc_page_table()?;
+                    pt.mk_item(i, PA_TO_PFN!(lower_pa), PAGE_TABLE);
+                    table_add_mappings(pt_phys, 1);
+                }
+            }
+            self.pt_phys = pt_phys;
+            return Ok(());
+        }
+
+        let pt_phys = alloc_page_table()?;
+        let kernel_pt = table_at(swapper_pg_dir_pa());
+        let pt = table_at(pt_phys);
+        for i in 0..PAGE_TABLE_ENTRIES {
+            if !self.owns_top_index(i) {
+                pt.set_entry(i, kernel_pt.entry(i));
+            }
+        }
+        self.pt_phys = pt_phys;
+
+        dprint!(INFO, "mmu: aspace [{:x}, {:x}) table at {:x}\n",
+                base, base + size, pt_phys);
+        Ok(())
+    }
+
+    /* Drop whatever is still mapped and free all of the tables */
+    pub fn destroy(&mut self) -> Result<(), ErrNO> {
+        if self.is_kernel() || self.pt_phys == 0 {
+            return Err(ErrNO::BadState);
+        }
+
+        if self.mapped_pages != 0 {
+            dprint!(WARN, "mmu: destroying aspace with {} pages mapped\n",
+                    self.mapped_pages);
+        }
+        /* this frees the tables left empty by earlier unmaps too */
+        self.unmap(self.base, self.size / PAGE_SIZE)?;
+
+        /* the top level entries of the kernel aren't ours */
+        let pt = table_at(self.pt_phys);
+        for i in 0..PAGE_TABLE_ENTRIES {
+            if !self.owns_top_index(i) {
+                pt.set_entry(i, 0);
+            }
+        }
+
+        /* the ASID may be handed out again */
+        if self.asid_ctx != 0 && asid_bits() != 0 {
+            flush_tlb_asid(asid_of(self.asid_ctx));
+        }
+        self.asid_ctx = 0;
+
+        if let Some(page) = table_page(self.pt_phys) {
+            pmm_free_page(page);
+        }
+        self.pt_phys = 0;
+        Ok(())
+    }
+
+    /* Whether the top level entry |index| is within the aspace;
+     * those that aren't are the kernel's. */
+    fn owns_top_index(&self, index: usize) -> bool {
+        index >= vaddr_to_index(self.base, 0) &&
+            index <= vaddr_to_index(self.base + self.size - 1, 0)
+    }
+
+    pub fn is_kernel(&self) -> bool {
+        (self.flags & ARCH_ASPACE_FLAG_KERNEL) != 0
+    }
+
+    pub fn pt_phys(&self) -> paddr_t {
+        self.pt_phys
+    }
+
+    pub fn mapped_pages(&self) -> usize {
+        self.mapped_pages
+    }
+
+    fn is_valid_range(&self, vaddr: vaddr_t, count: usize) -> bool {
+        let len = count * PAGE_SIZE;
+        IS_PAGE_ALIGNED!(vaddr) &&
+            vaddr >= self.base &&
+            count <= self.size / PAGE_SIZE &&
+            vaddr - self.base <= self.size - len
+    }
+
+    fn pte_flags(&self, mmu_flags: u32) -> Result<usize, ErrNO> {
+        if (mmu_flags & ARCH_MMU_FLAG_PERM_RWX_MASK) == 0 ||
+           (mmu_flags & ARCH_MMU_FLAG_INVALID) != 0 {
+            return Err(ErrNO::InvalidArgs);
+        }
+        if self.is_kernel() && (mmu_flags & ARCH_MMU_FLAG_PERM_USER) != 0 {
+            return Err(ErrNO::InvalidArgs);
+        }
+        Ok(mmu_flags_to_pte(mmu_flags, self.is_kernel()))
+    }
+
+    /* Collects what an operation leaves stale in the TLBs */
+    fn tlb_batch(&self) -> TlbBatch {
+        if self.is_kernel() || asid_bits() == 0 {
+            TlbBatch::new(None)
+        } else {
+            TlbBatch::new(Some(asid_of(self.asid_ctx)))
+        }
+    }
+
+    fn keep_level(&self) -> usize {
+        if self.is_kernel() { KERNEL_KEEP_LEVEL } else { 1 }
+    }
+
+    /* Map |count| pages at |vaddr| to [paddr, paddr + count pages),
+     * using large pages where the alignment allows. */
+    pub fn map_contiguous(&mut self, vaddr: vaddr_t, paddr: paddr_t,
+                          count: usize, mmu_flags: u32)
+        -> Result<usize, ErrNO> {
+
+        if self.pt_phys == 0 {
+            return Err(ErrNO::BadState);
+        }
+        if !self.is_valid_range(vaddr, count) || !IS_PAGE_ALIGNED!(paddr) {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let pte_flags = self.pte_flags(mmu_flags)?;
+
+        let mut mapped = 0;
+        let ret = map_range(self.pt_phys, 0, vaddr, paddr,
+                            count * PAGE_SIZE, pte_flags, &mut mapped);
+        self.mapped_pages += mapped / PAGE_SIZE;
+        if let Err(e) = ret {
+            self.unmap(vaddr, mapped / PAGE_SIZE)?;
+            return Err(e);
+        }
+        Ok(count)
+    }
+
+    /* Map the pages of |paddrs| one after the other from |vaddr| */
+    pub fn map(&mut self, vaddr: vaddr_t, paddrs: &[paddr_t],
+               mmu_flags: u32)
+        -> Result<usize, ErrNO> {
+
+        if self.pt_phys == 0 {
+            return Err(ErrNO::BadState);
+        }
+        if !self.is_valid_range(vaddr, paddrs.len()) {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let pte_flags = self.pte_flags(mmu_flags)?;
+
+        let mut mapped = 0;
+        for (i, &pa) in paddrs.iter().enumerate() {
+            let ret = if IS_PAGE_ALIGNED!(pa) {
+                map_range(self.pt_phys, 0, vaddr + i * PAGE_SIZE, pa,
+                          PAGE_SIZE, pte_flags, &mut mapped)
+            } else {
+                Err(ErrNO::InvalidArgs)
+            };
+
+            if let Err(e) = ret {
+                self.mapped_pages += mapped / PAGE_SIZE;
+                self.unmap(vaddr, mapped / PAGE_SIZE)?;
+                return Err(e);
+            }
+        }
+        self.mapped_pages += paddrs.len();
+        Ok(paddrs.len())
+    }
+
+    /* Unmap |count| pages at |vaddr|, freeing the tables that end up
+     * empty. Returns the number of pages that were mapped. */
+    pub fn unmap(&mut self, vaddr: vaddr_t, count: usize)
+        -> Result<usize, ErrNO> {
+
+        if self.pt_phys == 0 {
+            return Err(ErrNO::BadState);
+        }
+        if !self.is_valid_range(vaddr, count) {
+            return Err(ErrNO::InvalidArgs);
+        }
+
+        let mut ctx = UnmapContext::new(self.keep_level(), self.tlb_batch());
+        let ret = unmap_range(self.pt_phys, 0, vaddr, count * PAGE_SIZE,
+                              &mut ctx);
+        let unmapped = ctx.finish() / PAGE_SIZE;
+        self.mapped_pages -= unmapped;
+
+        ret.map(|_| unmapped)
+    }
+
+    /* Change the permissions of the pages mapped in
+     * [vaddr, vaddr + count pages) to |mmu_flags|. */
+    pub fn protect(&mut self, vaddr: vaddr_t, count: usize,
+                   mmu_flags: u32)
+        -> Result<(), ErrNO> {
+
+        if self.pt_phys == 0 {
+            return Err(ErrNO::BadState);
+        }
+        if !self.is_valid_range(vaddr, count) {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let pte_flags = self.pte_flags(mmu_flags)?;
+
+        let ret = protect_range(self.pt_phys, 0, vaddr, count * PAGE_SIZE,
+                                pte_flags);
+        let mut batch = self.tlb_batch();
+        batch.add(vaddr, count * PAGE_SIZE);
+        batch.flush();
+        ret
+    }
+
+    /* The paddr and the mmu flags of the mapping of |vaddr| */
+    pub fn query(&self, vaddr: vaddr_t) -> Result<(paddr_t, u32), ErrNO> {
+        if self.pt_phys == 0 {
+            return Err(ErrNO::BadState);
+        }
+        if vaddr < self.base || vaddr - self.base >= self.size {
+            return Err(ErrNO::OutOfRange);
+        }
+
+        let mut table_pa = self.pt_phys;
+        for level in 0..mmu_levels() {
+            let pte = table_at(table_pa).entry(vaddr_to_index(vaddr, level));
+            if !pte_is_present(pte) {
+                break;
+            }
+            if pte_is_leaf(pte) {
+                let offset = vaddr & !LEVEL_MASK!(level);
+                return Ok((pte_paddr(pte) + offset, pte_to_mmu_flags(pte)));
+            }
+            table_pa = pte_paddr(pte);
+        }
+        Err(ErrNO::NotFound)
+    }
+
+    /* Clear the accessed bit of the pages mapped in
+     * [vaddr, vaddr + count pages), calling |accessed| with
+     * the vaddr and paddr of each of them that had it set. */
+    pub fn harvest_accessed<F>(&mut self, vaddr: vaddr_t, count: usize,
+                               mut accessed: F)
+        -> Result<(), ErrNO>
+    where F: FnMut(vaddr_t, paddr_t)
+    {
+        if self.pt_phys == 0 {
+            return Err(ErrNO::BadState);
+        }
+        if !self.is_valid_range(vaddr, count) {
+            return Err(ErrNO::InvalidArgs);
+        }
+
+        harvest_range(self.pt_phys, 0, vaddr, count * PAGE_SIZE,
+                      &mut accessed);
+        let mut batch = self.tlb_batch();
+        batch.add(vaddr, count * PAGE_SIZE);
+        batch.flush();
+        Ok(())
+    }
+}
+
+/* Extends the physmap over the physical range [pa, pa + len),
+ * which lies past what was mapped at boot, e.g. hot-added memory.
+ * Table pages come out of the PMM, so it must be up already. */
+pub fn arch_physmap_map(pa: usize, len: usize) -> Result<(), ErrNO> {
+    if !IS_PAGE_ALIGNED!(pa) || !IS_PAGE_ALIGNED!(len) {
+        return Err(ErrNO::BadAlign);
+    }
+    match pa.checked_add(len) {
+        Some(end) if end <= physmap_max_size() => (),
+        _ => return Err(ErrNO::OutOfRange),
+    }
+
+    /* the range is mapped from the bottom up; on failure,
+     * take back the part that made it */
+    let mut mapped = 0;
+    if let Err(e) = map_range(swapper_pg_dir_pa(), 0, paddr_to_physmap(pa),
+                              pa, len, PAGE_KERNEL, &mut mapped) {
+        if mapped != 0 {
+            arch_physmap_unmap(pa, mapped)?;
+        }
+        return Err(e);
+    }
+
+    /* make the new translations visible */
+    let mut batch = TlbBatch::new(None);
+    batch.add(paddr_to_physmap(pa), len);
+    batch.flush();
+    Ok(())
+}
+
+/* Takes [pa, pa + len) back out of the physmap, as mapped by
+ * arch_physmap_map(), and frees the tables left empty. */
+pub fn arch_physmap_unmap(pa: usize, len: usize) -> Result<(), ErrNO> {
+    if !IS_PAGE_ALIGNED!(pa) || !IS_PAGE_ALIGNED!(len) {
+        return Err(ErrNO::BadAlign);
+    }
+    match pa.checked_add(len) {
+        Some(end) if end <= physmap_max_size() => (),
+        _ => return Err(ErrNO::OutOfRange),
+    }
+
+    let mut ctx = UnmapContext::new(KERNEL_KEEP_LEVEL, TlbBatch::new(None));
+    let ret = unmap_range(swapper_pg_dir_pa(), 0, paddr_to_physmap(pa),
+                          len, &mut ctx);
+    ctx.finish();
+    ret
+}
+
+/* The canonical form of |vaddr|, whose top bit of the
+ * virtual address width is copied to the bits above */
+fn canonical_vaddr(vaddr: vaddr_t) -> vaddr_t {
+    let bits = LEVEL_SHIFT!(0) + PAGE_SHIFT - 3;
+    if (vaddr & (1 << (bits - 1))) != 0 {
+        vaddr | !((1 << bits) - 1)
+    } else {
+        vaddr
+    }
+}
+
+/* Report the leaves below the table at |table_pa|, which maps
+ * from |vaddr|, that are both writable and executable. */
+fn check_wx_range(table_pa: paddr_t, level: usize, vaddr: vaddr_t)
+    -> usize {
+
+    let table = table_at(table_pa);
+    let mut found = 0;
+    for index in 0..PAGE_TABLE_ENTRIES {
+        let pte = table.entry(index);
+        if !pte_is_present(pte) {
+            continue;
+        }
+
+        let va = canonical_vaddr(vaddr + (index << LEVEL_SHIFT!(level)));
+        if !pte_is_leaf(pte) {
+            found += check_wx_range(pte_paddr(pte), level+1, va);
+        } else if (pte & (_PAGE_WRITE | _PAGE_EXEC)) ==
+                  (_PAGE_WRITE | _PAGE_EXEC) {
+            dprint!(CRITICAL, "mmu: W+X mapping [{:x}, {:x}) -> {:x}\n",
+                    va, va + LEVEL_SIZE!(level), pte_paddr(pte));
+            found += 1;
+        }
+    }
+    found
+}
+
+/* Make sure no kernel mapping is both writable and executable */
+pub fn arch_kernel_wx_check() -> Result<(), ErrNO> {
+    let found = check_wx_range(swapper_pg_dir_pa(), 0, 0);
+    if found != 0 {
+        dprint!(CRITICAL, "mmu: {} W+X kernel mappings\n", found);
+        return Err(ErrNO::BadState);
+    }
+    dprint!(INFO, "mmu: no W+X kernel mappings\n");
+    Ok(())
+}
+
+/*
+ * Switch this hart to the aspace of |to|, or to the kernel alone.
+ * A user aspace gets an ASID of the current generation on the way.
+ */
+pub fn arch_mmu_context_switch(to: Option<&mut ArchMmu>) {
+    let (satp, flush) = match to {
+        Some(mmu) if !mmu.is_kernel() => {
+            let (asid, flush) = asid_context_switch(&mut mmu.asid_ctx);
+            let satp = mmu_mode().satp_mode |
+                (asid << SATP_ASID_SHIFT) | PA_TO_PFN!(mmu.pt_phys);
+            (satp, flush)
+        },
+        _ => {
+            asid_switch_to_kernel();
+            (unsafe { SWAPPER_SATP }, false)
+        },
+    };
+
+    unsafe { asm!("csrw satp, {0}", in(reg) satp); }
+    if flush {
+        local_flush_tlb_all();
+    }
+}
+
+/*
+ * Pick the deepest paging mode the hart supports, trying Sv57,
+ * then Sv48 and Sv39. satp ignores a write of a mode it doesn't
+ * support, so each one is written and read back. This runs with
+ * the MMU off, from the physical address of the kernel, so the
+ * root used for the probe identity maps the region the kernel
+ * runs from with a single leaf.
+ */
+pub unsafe fn riscv64_probe_mmu_mode()
+{
+    let root = (&SWAPPER_PG_DIR) as *const PageTable as usize;
+    let here = riscv64_probe_mmu_mode as usize;
+
+    for (i, mode) in MMU_MODES.iter().enumerate() {
+        MMU_MODE = i;
+        let index = vaddr_to_index(here, 0);
+        let satp = mode.satp_mode | PA_TO_PFN!(root);
+        SWAPPER_PG_DIR.mk_item(index, PA_TO_PFN!(here & LEVEL_MASK!(0)),
+                               PAGE_KERNEL_EXEC);
+
+        let val: usize;
+        asm!(
+            "sfence.vma",
+            "csrw satp, {satp}",
+            "csrr {val}, satp",
+            "csrw satp, zero",
+            "sfence.vma",
+            satp = in(reg) satp,
+            val = out(reg) val,
+        );
+        SWAPPER_PG_DIR.set_entry(index, 0);
+
+        if val == satp {
+            return;
+        }
+    }
+
+    /* Sv39 is the least a hart with paging has */
+}
+
 pub unsafe fn riscv64_setup_mmu_mode()
 {
     let ptr = (&SWAPPER_PG_DIR) as *const PageTable;
     let pfn = (ptr as usize) >> PAGE_SHIFT;
 
-    let mode = match MMU_LEVELS {
-        5 => SATP_MODE_57,
-        4 => SATP_MODE_48,
-        3 => SATP_MODE_39,
-        _ => 0,
-    };
-
-    SWAPPER_SATP = mode | pfn;
+    SWAPPER_SATP = mmu_mode().satp_mode | pfn;
 }
@@ -1,7 +1,10 @@
 mod start;
 mod csr;
-mod mmu;
-mod mp;
+pub mod mmu;
+pub mod asid;
+pub mod tlb;
+pub mod exceptions;
+pub mod mp;
 pub mod sbi;
 pub mod defines;
 pub mod periphmap;
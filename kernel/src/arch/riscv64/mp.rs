@@ -4,7 +4,9 @@
  * at https://opensource.org/licenses/MIT
  */
 
-//use super::defines::*;
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::defines::*;
 
 //struct PerCPU {
 //    cpuid: usize,       /* Logical ID in kernel */
@@ -16,3 +18,38 @@
 static riscv64_percpu_array: [riscv64_percpu; NR_CPUS] =
     [riscv64_percpu{cpuid: 0, hartid: 0}; NR_CPUS];
 */
+
+/* Only the boot hart runs so far, as cpu 0. */
+pub fn arch_curr_cpu_num() -> usize {
+    0
+}
+
+/* The hart id of each cpu number, valid for the online ones */
+static CPU_HART_IDS: [AtomicUsize; NR_CPUS] = {
+    /* only ever copied into the array, never shared */
+    #[allow(clippy::declare_interior_mutable_const)]
+    const HART_ID_INIT: AtomicUsize = AtomicUsize::new(0);
+    [HART_ID_INIT; NR_CPUS]
+};
+
+/* One bit per cpu number that is up */
+static CPU_ONLINE_MASK: AtomicUsize = AtomicUsize::new(0);
+
+/* Called on each hart as it comes up, with the MMU on */
+pub fn riscv64_init_percpu(cpu_num: usize, hartid: usize) {
+    CPU_HART_IDS[cpu_num].store(hartid, Ordering::Relaxed);
+    CPU_ONLINE_MASK.fetch_or(1 << cpu_num, Ordering::Release);
+}
+
+pub fn arch_cpu_online_mask() -> usize {
+    CPU_ONLINE_MASK.load(Ordering::Acquire)
+}
+
+pub fn arch_cpu_num_to_hartid(cpu_num: usize) -> usize {
+    CPU_HART_IDS[cpu_num].load(Ordering::Relaxed)
+}
+
+/* Wait for an interrupt with nothing else to do */
+pub fn arch_idle() {
+    unsafe { asm!("wfi"); }
+}
@@ -9,9 +9,11 @@ use crate::{
     PAGE_SIZE, IS_PAGE_ALIGNED,
 };
 use crate::errors::ErrNO;
-use crate::arch::mmu::{PAGE_IOREMAP, riscv64_boot_map_v};
+use crate::arch::mmu::{page_ioremap, riscv64_boot_map_v};
+use crate::vm::bootreserve::boot_table_reserve;
+use crate::config_generated::*;
 
-pub const MAX_PERIPH_RANGES : usize = 4;
+pub const MAX_PERIPH_RANGES : usize = _CONFIG_MAX_PERIPH_RANGES;
 
 pub struct PeriphRange {
     pub base_phys:  usize,
@@ -23,18 +25,17 @@ pub fn add_periph_range(ctx: &mut BootContext,
                         base_phys: usize, length: usize)
     -> Result<(), ErrNO> {
 
-    if ctx.periph_ranges.len() >= MAX_PERIPH_RANGES {
-        return Err(ErrNO::OutOfRange);
-    }
-
     if !IS_PAGE_ALIGNED!(base_phys) || !IS_PAGE_ALIGNED!(length) {
         return Err(ErrNO::BadAlign);
     }
 
+    boot_table_reserve(&mut ctx.periph_ranges, MAX_PERIPH_RANGES,
+                       "periph")?;
+
     dprint!(INFO, "periphmap: {:x}\n", ctx.periph_base_virt);
 
     riscv64_boot_map_v(ctx.periph_base_virt, base_phys, length,
-                       PAGE_IOREMAP)?;
+                       page_ioremap())?;
 
     ctx.periph_ranges.push(
         PeriphRange {
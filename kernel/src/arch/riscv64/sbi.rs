@@ -7,6 +7,7 @@
 #![allow(dead_code)]
 
 use core::arch::asm;
+use crate::errors::ErrNO;
 
 /* Legacy Extensions (EIDs 0x00 - 0x0F) */
 const SBI_SET_TIMER         : usize = 0x0;
@@ -21,6 +22,18 @@ const SBI_SHUTDOWN          : usize = 0x8;
 
 const SBI_HSM : usize = 0x48534D;
 
+/* Base Extension (EID 0x10) */
+const SBI_EXT_BASE              : usize = 0x10;
+const SBI_EXT_BASE_PROBE_EXT    : usize = 3;
+
+/* RFENCE Extension (EID "RFNC") */
+const SBI_EXT_RFENCE            : usize = 0x52464E43;
+const SBI_EXT_RFENCE_REMOTE_FENCE_I         : usize = 0;
+const SBI_EXT_RFENCE_REMOTE_SFENCE_VMA      : usize = 1;
+const SBI_EXT_RFENCE_REMOTE_SFENCE_VMA_ASID : usize = 2;
+
+const SBI_SUCCESS: usize = 0;
+
 #[inline(always)]
 fn sbi_call(eid: usize, fid: usize,
             arg0: usize, arg1: usize, arg2: usize) -> (usize, usize) {
@@ -41,6 +54,87 @@ fn sbi_call(eid: usize, fid: usize,
     (ret0, ret1)
 }
 
+/* A call of the v0.2 calling convention, which returns an error
+ * code in a0 and a value in a1. */
+#[inline(always)]
+fn sbi_ecall(eid: usize, fid: usize,
+             arg0: usize, arg1: usize, arg2: usize,
+             arg3: usize, arg4: usize) -> Result<usize, ErrNO> {
+    let error: usize;
+    let value: usize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a2") arg2,
+            in("a3") arg3,
+            in("a4") arg4,
+            in("a6") fid,
+            in("a7") eid,
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+        );
+    }
+    if error != SBI_SUCCESS {
+        return Err(ErrNO::NotSupported);
+    }
+    Ok(value)
+}
+
+/* Whether the SBI implementation provides the extension |eid| */
+pub fn sbi_probe_extension(eid: usize) -> bool {
+    match sbi_ecall(SBI_EXT_BASE, SBI_EXT_BASE_PROBE_EXT, eid,
+                    0, 0, 0, 0) {
+        Ok(value) => value != 0,
+        Err(_) => false,
+    }
+}
+
+pub fn sbi_has_rfence() -> bool {
+    sbi_probe_extension(SBI_EXT_RFENCE)
+}
+
+/*
+ * Flush the translations of [start, start + size) on the harts
+ * of |hart_mask|, whose bit 0 is hart |hart_mask_base|.
+ * A size of usize::MAX flushes all of them.
+ */
+pub fn sbi_remote_sfence_vma(hart_mask: usize, hart_mask_base: usize,
+                             start: usize, size: usize)
+    -> Result<(), ErrNO> {
+    sbi_ecall(SBI_EXT_RFENCE, SBI_EXT_RFENCE_REMOTE_SFENCE_VMA,
+              hart_mask, hart_mask_base, start, size, 0)?;
+    Ok(())
+}
+
+/* Same as sbi_remote_sfence_vma, only for the address space |asid| */
+pub fn sbi_remote_sfence_vma_asid(hart_mask: usize, hart_mask_base: usize,
+                                  start: usize, size: usize, asid: usize)
+    -> Result<(), ErrNO> {
+    sbi_ecall(SBI_EXT_RFENCE, SBI_EXT_RFENCE_REMOTE_SFENCE_VMA_ASID,
+              hart_mask, hart_mask_base, start, size, asid)?;
+    Ok(())
+}
+
+/*
+ * The legacy call takes the address of the hart mask and
+ * has no base, so it only reaches the first harts.
+ */
+pub fn sbi_legacy_remote_sfence_vma(hart_mask: usize, start: usize,
+                                    size: usize)
+    -> Result<(), ErrNO> {
+    let ptr = &hart_mask as *const usize as usize;
+    sbi_ecall(SBI_REMOTE_SFENCE_VMA, 0, ptr, start, size, 0, 0)?;
+    Ok(())
+}
+
+pub fn sbi_legacy_remote_sfence_vma_asid(hart_mask: usize, start: usize,
+                                         size: usize, asid: usize)
+    -> Result<(), ErrNO> {
+    let ptr = &hart_mask as *const usize as usize;
+    sbi_ecall(SBI_REMOTE_SFENCE_VMA_ASID, 0, ptr, start, size, asid, 0)?;
+    Ok(())
+}
+
 pub fn console_putchar(ch: char) -> (usize, usize) {
     sbi_call(SBI_CONSOLE_PUTCHAR, 0, ch as usize, 0, 0)
 }
@@ -8,11 +8,16 @@ use core::arch::asm;
 use super::csr::*;
 use super::defines::*;
 use super::mmu::{
-    riscv64_boot_map, riscv64_setup_mmu_mode,
-    SWAPPER_SATP,
-    PAGE_KERNEL, PAGE_KERNEL_EXEC
+    riscv64_boot_map, riscv64_setup_mmu_mode, riscv64_probe_mmu_mode,
+    kernel_aspace_base, arch_physmap_size, SWAPPER_SATP,
+    PAGE_KERNEL, PAGE_KERNEL_READ, PAGE_KERNEL_READ_EXEC,
 };
+use super::mp::riscv64_init_percpu;
+use super::asid::riscv64_asid_init;
+use super::tlb::riscv64_tlb_init;
+use super::exceptions::riscv64_exception_init;
 use crate::{lk_main, HART_LOTTERY};
+use crate::kernel::thread::thread_become_idle;
 use crate::config_generated::*;
 
 #[link_section = ".bss..page_aligned"]
@@ -183,25 +188,39 @@ fn relocate_enable_mmu() {
 
 unsafe extern "C"
 fn start_kernel(hartid: usize, dtb_pa: usize) {
+    /* the layout of the kernel aspace follows the paging mode */
+    riscv64_probe_mmu_mode();
+
     /* map a large run of physical memory
      * at the base of the kernel's address space */
-    let ret = riscv64_boot_map(KERNEL_ASPACE_BASE, 0, ARCH_PHYSMAP_SIZE,
+    let ret = riscv64_boot_map(kernel_aspace_base(), 0, arch_physmap_size(),
                                PAGE_KERNEL);
     if let Err(_) = ret {
         return;
     }
 
+    /* map the kernel to a fixed address, each part of it
+     * with no more permissions than it needs:
+     * text R-X, rodata R--, data and bss RW- */
+    /* the boot heap that just follows the kernel is RW- too */
     let kernel_base_phys = __code_start as usize;
-    let kernel_size = (_end as usize) - kernel_base_phys;
-
-    /* map the kernel to a fixed address */
-    /* map the boot heap that just follows kernel address */
-    let ret = riscv64_boot_map(KERNEL_BASE,
-                               kernel_base_phys,
-                               kernel_size+BOOT_HEAP_SIZE,
-                               PAGE_KERNEL_EXEC);
-    if let Err(_) = ret {
-        return;
+    let sections = [
+        (__code_start as usize, __rodata_start as usize,
+         PAGE_KERNEL_READ_EXEC),
+        (__rodata_start as usize, __rodata_end as usize,
+         PAGE_KERNEL_READ),
+        (__data_start as usize, (_end as usize) + BOOT_HEAP_SIZE,
+         PAGE_KERNEL),
+    ];
+    for (start, end, prot) in sections {
+        if start == end {
+            continue;
+        }
+        let ret = riscv64_boot_map(KERNEL_BASE + (start - kernel_base_phys),
+                                   start, end - start, prot);
+        if let Err(_) = ret {
+            return;
+        }
     }
 
     /* Setup value for register satp */
@@ -211,6 +230,13 @@ fn start_kernel(hartid: usize, dtb_pa: usize) {
     relocate_enable_mmu();
 
     /* Set the per cpu pointer for cpu 0 */
+    riscv64_init_percpu(0, hartid);
+
+    riscv64_asid_init();
+    riscv64_tlb_init();
+
+    /* take traps from here on, instead of spinning on them */
+    riscv64_exception_init();
 
     /* Enter main */
     let ret = lk_main(hartid, dtb_pa);
@@ -218,6 +244,9 @@ fn start_kernel(hartid: usize, dtb_pa: usize) {
         crate::dprint!(crate::CRITICAL, "Fatal: errno = {:?}\n", errno);
         /* Todo: panic */
     }
+
+    /* nothing but the idle loop is left for the boot hart */
+    thread_become_idle();
 }
 
 //unsafe extern "C"
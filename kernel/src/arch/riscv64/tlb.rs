@@ -0,0 +1,208 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * TLB maintenance
+ *
+ * sfence.vma only reaches the hart that runs it, the others are
+ * asked through the SBI: the RFENCE extension when it is there,
+ * the legacy call otherwise.
+ */
+
+use core::arch::asm;
+use core::cmp::{min, max};
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::defines::*;
+use super::mp::{
+    arch_curr_cpu_num, arch_cpu_online_mask, arch_cpu_num_to_hartid,
+};
+use super::sbi::{
+    sbi_has_rfence, sbi_remote_sfence_vma, sbi_remote_sfence_vma_asid,
+    sbi_legacy_remote_sfence_vma, sbi_legacy_remote_sfence_vma_asid,
+};
+use crate::{vaddr_t, dprint, WARN, CRITICAL};
+use crate::errors::ErrNO;
+
+/* Beyond this many pages, flush the whole TLB instead */
+const MAX_TLB_FLUSH_PAGES: usize = 32;
+
+/* The size the SBI takes as all of the address space */
+const SBI_FLUSH_ALL: usize = usize::MAX;
+
+static HAS_RFENCE: AtomicBool = AtomicBool::new(false);
+
+pub fn riscv64_tlb_init() {
+    HAS_RFENCE.store(sbi_has_rfence(), Ordering::Relaxed);
+}
+
+pub fn local_flush_tlb_all() {
+    unsafe { asm!("sfence.vma"); }
+}
+
+/* The non-global translations of |asid| */
+pub fn local_flush_tlb_asid(asid: usize) {
+    unsafe { asm!("sfence.vma zero, {0}", in(reg) asid); }
+}
+
+/* The translations of |vaddr|, of |asid| or of any aspace */
+pub fn local_flush_tlb_page(vaddr: vaddr_t, asid: Option<usize>) {
+    unsafe {
+        match asid {
+            Some(asid) => asm!("sfence.vma {0}, {1}",
+                               in(reg) vaddr, in(reg) asid),
+            None => asm!("sfence.vma {0}, zero", in(reg) vaddr),
+        }
+    }
+}
+
+/* Ask the harts of |hart_mask| from |hart_mask_base| to flush.
+ * A call the SBI turns down is retried as a flush of everything,
+ * then with the legacy call, rather than leave anything stale. */
+fn remote_flush(hart_mask: usize, hart_mask_base: usize,
+                start: vaddr_t, size: usize, asid: Option<usize>) {
+    if HAS_RFENCE.load(Ordering::Relaxed) {
+        let ret = match asid {
+            Some(asid) => sbi_remote_sfence_vma_asid(hart_mask,
+                                                     hart_mask_base,
+                                                     start, size, asid),
+            None => sbi_remote_sfence_vma(hart_mask, hart_mask_base,
+                                          start, size),
+        };
+        if ret.is_ok() {
+            return;
+        }
+
+        dprint!(WARN, "tlb: remote sfence failed: {:?}, flushing all\n",
+                ret);
+        if sbi_remote_sfence_vma(hart_mask, hart_mask_base,
+                                 0, SBI_FLUSH_ALL).is_ok() {
+            return;
+        }
+    }
+
+    /* the legacy calls have no base */
+    let ret = if hart_mask_base < usize::BITS as usize {
+        let hart_mask = hart_mask << hart_mask_base;
+        match asid {
+            Some(asid) => sbi_legacy_remote_sfence_vma_asid(hart_mask,
+                                                            start, size,
+                                                            asid),
+            None => sbi_legacy_remote_sfence_vma(hart_mask, start, size),
+        }
+    } else {
+        Err(ErrNO::OutOfRange)
+    };
+    if let Err(e) = ret {
+        dprint!(CRITICAL, "tlb: harts {:x} from {} not flushed: {:?}\n",
+                hart_mask, hart_mask_base, e);
+    }
+}
+
+/* Flush on every online hart but this one,
+ * with one call for each run of harts that fits in a mask */
+fn remote_flush_others(start: vaddr_t, size: usize, asid: Option<usize>) {
+    let online = arch_cpu_online_mask() & !(1 << arch_curr_cpu_num());
+    let mut hart_mask = 0;
+    let mut hart_mask_base = 0;
+
+    for cpu in 0..NR_CPUS {
+        if (online & (1 << cpu)) == 0 {
+            continue;
+        }
+        let hartid = arch_cpu_num_to_hartid(cpu);
+        if hart_mask != 0 &&
+           (hartid < hart_mask_base ||
+            hartid - hart_mask_base >= usize::BITS as usize) {
+            remote_flush(hart_mask, hart_mask_base, start, size, asid);
+            hart_mask = 0;
+        }
+        if hart_mask == 0 {
+            hart_mask_base = hartid;
+        }
+        hart_mask |= 1 << (hartid - hart_mask_base);
+    }
+
+    if hart_mask != 0 {
+        remote_flush(hart_mask, hart_mask_base, start, size, asid);
+    }
+}
+
+/* Drop all of the translations of |asid| on every hart */
+pub fn flush_tlb_asid(asid: usize) {
+    local_flush_tlb_asid(asid);
+    remote_flush_others(0, SBI_FLUSH_ALL, Some(asid));
+}
+
+/*
+ * The translations an operation on an aspace leaves stale,
+ * gathered while it walks the tables and flushed once at the end,
+ * on this hart and with one shootdown to the others.
+ */
+pub struct TlbBatch {
+    /* None for the global mappings of the kernel */
+    asid: Option<usize>,
+    start: vaddr_t,
+    end: vaddr_t,
+}
+
+impl TlbBatch {
+    pub const fn new(asid: Option<usize>) -> Self {
+        Self {
+            asid,
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /* [vaddr, vaddr + len) has to be flushed */
+    pub fn add(&mut self, vaddr: vaddr_t, len: usize) {
+        if self.start == self.end {
+            self.start = vaddr;
+            self.end = vaddr + len;
+        } else {
+            self.start = min(self.start, vaddr);
+            self.end = max(self.end, vaddr + len);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /* Flush everything of the ASID, whatever was added. Flushing
+     * by address may leave the upper levels of the walk cached,
+     * so this is a must before a page table is freed. */
+    pub fn flush_all(&mut self) {
+        match self.asid {
+            Some(asid) => local_flush_tlb_asid(asid),
+            None => local_flush_tlb_all(),
+        }
+        remote_flush_others(0, SBI_FLUSH_ALL, self.asid);
+
+        self.start = 0;
+        self.end = 0;
+    }
+
+    pub fn flush(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+
+        let count = (self.end - self.start) / PAGE_SIZE;
+        if count > MAX_TLB_FLUSH_PAGES {
+            self.flush_all();
+        } else {
+            for i in 0..count {
+                local_flush_tlb_page(self.start + i * PAGE_SIZE, self.asid);
+            }
+            remote_flush_others(self.start, self.end - self.start,
+                                self.asid);
+        }
+
+        self.start = 0;
+        self.end = 0;
+    }
+}
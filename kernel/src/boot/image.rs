@@ -4,7 +4,9 @@
  * at https://opensource.org/licenses/MIT
  */
 
-pub const MAX_ZBI_MEM_RANGES: usize = 32;
+use crate::config_generated::*;
+
+pub const MAX_ZBI_MEM_RANGES: usize = _CONFIG_MAX_ZBI_MEM_RANGES;
 
 pub enum ZBIMemRangeType {
     RAM,
@@ -17,6 +19,7 @@ pub struct ZBIMemRange {
     pub paddr:      usize,
     pub length:     usize,
     pub reserved:   u32,
+    pub numa_id:    u32,
 }
 
 impl ZBIMemRange {
@@ -28,6 +31,7 @@ impl ZBIMemRange {
             paddr,
             length,
             reserved: 0,
+            numa_id: 0,
         }
     }
 }
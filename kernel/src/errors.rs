@@ -4,13 +4,16 @@
  * at https://opensource.org/licenses/MIT
  */
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum ErrNO {
     /* Indicates an operation was successful. */
     _OK,
 
     NotFound,
 
+    /* An argument is invalid. */
+    InvalidArgs,
+
     //NoDev,
 
     NoMem,
@@ -29,4 +32,16 @@ pub enum ErrNO {
     BadState,
 
     BadRange,
+
+    /* The caller did not have permission to perform
+     * the specified operation. */
+    AccessDenied,
+
+    /* The operation could not complete right now; the caller
+     * should wait (e.g. for free pages) and try again. */
+    ShouldWait,
+
+    /* The operation is not implemented, supported,
+     * or enabled. */
+    NotSupported,
 }
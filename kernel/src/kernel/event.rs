@@ -0,0 +1,70 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * A simple event object.
+ * There is no scheduler yet, so instead of blocking threads,
+ * waiters register a callback which is run (once) when the event
+ * becomes signaled.
+ * Waiting never allocates, so an event may live under a lock
+ * the heap depends on; there is room for MAX_EVENT_WAITERS.
+ */
+
+use crate::ErrNO;
+
+pub const MAX_EVENT_WAITERS: usize = 8;
+
+pub type EventCallback = fn();
+
+pub struct Event {
+    signaled: bool,
+    waiters: [Option<EventCallback>; MAX_EVENT_WAITERS],
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Self {
+            signaled: false,
+            waiters: [None; MAX_EVENT_WAITERS],
+        }
+    }
+
+    /* Signal the event and run all of the pending waiters. */
+    pub fn signal(&mut self) {
+        if self.signaled {
+            return;
+        }
+
+        self.signaled = true;
+        for waiter in self.waiters.iter_mut() {
+            if let Some(waiter) = waiter.take() {
+                waiter();
+            }
+        }
+    }
+
+    pub fn unsignal(&mut self) {
+        self.signaled = false;
+    }
+
+    pub fn is_signaled(&self) -> bool {
+        self.signaled
+    }
+
+    /* If the event is already signaled, |waiter| runs immediately;
+     * otherwise it runs the next time the event is signaled. */
+    pub fn wait(&mut self, waiter: EventCallback) -> Result<(), ErrNO> {
+        if self.signaled {
+            waiter();
+            return Ok(());
+        }
+
+        let slot = self.waiters.iter_mut().find(|w| w.is_none())
+            .ok_or_else(|| ErrNO::NoMem)?;
+        *slot = Some(waiter);
+        Ok(())
+    }
+}
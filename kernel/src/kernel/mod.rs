@@ -1 +1,4 @@
 pub mod thread;
+pub mod event;
+pub mod spinlock;
+pub mod once;
@@ -0,0 +1,64 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * A value that is set up once and read-only after.
+ * It starts out as the value given to new() and is filled in
+ * place by the first init(), so that a large value needs no
+ * copy on the stack. get() only hands it out once that is done.
+ */
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const ONCE_INCOMPLETE: u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_COMPLETE: u8 = 2;
+
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU8::new(ONCE_INCOMPLETE),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /* Let |f| fill in the value, unless that was done before;
+     * returns whether |f| ran. A caller that races with a
+     * running |f| waits for it to finish. */
+    pub fn init<F: FnOnce(&mut T)>(&self, f: F) -> bool {
+        if self.state.compare_exchange(ONCE_INCOMPLETE, ONCE_RUNNING,
+                                       Ordering::Acquire,
+                                       Ordering::Acquire)
+            .is_err() {
+            while self.state.load(Ordering::Acquire) != ONCE_COMPLETE {
+                spin_loop();
+            }
+            return false;
+        }
+
+        f(unsafe { &mut *self.value.get() });
+        self.state.store(ONCE_COMPLETE, Ordering::Release);
+        true
+    }
+
+    /* The value, once init() is done with it */
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) != ONCE_COMPLETE {
+            return None;
+        }
+        Some(unsafe { &*self.value.get() })
+    }
+}
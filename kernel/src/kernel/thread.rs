@@ -1,6 +1,25 @@
+use crate::arch::mp::arch_idle;
+use crate::vm::pmm::pmm_zero_free_pages;
+
+/* Free pages the idle loop zeroes before it looks around again */
+const IDLE_ZERO_PAGES: usize = 64;
+
 /*
  * Initialize threading system
  * This function is called once, from lk_main()
  */
 pub fn thread_init_early() {
 }
+
+/*
+ * Turn the boot hart into the idle thread once lk_main() is done.
+ * While the zeroed page pool is below its target it is topped up
+ * a few pages at a time; otherwise the hart waits for interrupts.
+ */
+pub fn thread_become_idle() -> ! {
+    loop {
+        if pmm_zero_free_pages(IDLE_ZERO_PAGES) == 0 {
+            arch_idle();
+        }
+    }
+}
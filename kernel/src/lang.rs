@@ -4,9 +4,29 @@
  * at https://opensource.org/licenses/MIT
  */
 
+use core::alloc::Layout;
 use core::panic::PanicInfo;
+use crate::{dprint, CRITICAL};
+use crate::lib::heap::heap_dump;
+use crate::vm::page::vm_page;
+use crate::vm::pmm::pmm_dump_node_stats;
+use crate::platform::platform_halt;
 
 #[panic_handler]
-fn panic(_: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    dprint!(CRITICAL, "\nPANIC: {}\n", info);
+    platform_halt();
+}
+
+/* The heap already reported what it failed to get;
+ * dump where all the memory went before giving up. */
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    dprint!(CRITICAL, "\nOOM: out of memory allocating {} bytes \
+            (align {})\n", layout.size(), layout.align());
+
+    heap_dump();
+    pmm_dump_node_stats();
+    vm_page::dump_state_counts();
+    platform_halt();
 }
@@ -0,0 +1,80 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * The kernel command line: space separated options, each of
+ * which is either "key=value" or a plain "key".
+ * It is copied in once during early boot and read-only after.
+ */
+
+use crate::{dprint, WARN};
+use crate::kernel::once::Once;
+
+pub const CMDLINE_MAX: usize = 4096;
+
+struct Cmdline {
+    buf: [u8; CMDLINE_MAX],
+    len: usize,
+}
+
+static CMDLINE: Once<Cmdline> = Once::new(Cmdline {
+    buf: [0; CMDLINE_MAX],
+    len: 0,
+});
+
+/* Keep a copy of |cmdline|; an overlong one is cut short
+ * at the last option that fits, or within the first option
+ * if that alone is too long. */
+pub fn cmdline_init(cmdline: &str) {
+    let mut len = cmdline.len();
+    if len > CMDLINE_MAX {
+        dprint!(WARN, "cmdline: too long, truncating to {} bytes\n",
+                CMDLINE_MAX);
+        len = CMDLINE_MAX;
+        while !cmdline.is_char_boundary(len) {
+            len -= 1;
+        }
+        if let Some(end) = cmdline[..len].rfind(' ') {
+            if end > 0 {
+                len = end;
+            }
+        }
+    }
+
+    let done = CMDLINE.init(|c| {
+        c.buf[..len].copy_from_slice(&cmdline.as_bytes()[..len]);
+        c.len = len;
+    });
+    if !done {
+        dprint!(WARN, "cmdline: already set, ignoring the new one\n");
+    }
+}
+
+fn cmdline() -> &'static str {
+    CMDLINE.get().map_or("", |c| {
+        core::str::from_utf8(&c.buf[..c.len]).unwrap_or("")
+    })
+}
+
+/* The value of the last option |key|; "" for a plain "key". */
+pub fn cmdline_get(key: &str) -> Option<&'static str> {
+    cmdline().split(' ').filter_map(|opt| {
+        match opt.split_once('=') {
+            Some((k, v)) if k == key => Some(v),
+            None if opt == key => Some(""),
+            _ => None,
+        }
+    }).last()
+}
+
+/* A plain "key" is true, as is anything but "0", "false" and "off". */
+pub fn cmdline_get_bool(key: &str, default: bool) -> bool {
+    match cmdline_get(key) {
+        Some("0") | Some("false") | Some("off") => false,
+        Some(_) => true,
+        None => default,
+    }
+}
@@ -0,0 +1,96 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * Debug console commands. A command gets the words of its
+ * command line, its own name first.
+ */
+
+use crate::{dprint, ALWAYS, WARN};
+use crate::errors::ErrNO;
+use crate::lib::cmdline::cmdline_get;
+use crate::vm::pmm::cmd_pmm;
+use crate::vm::vm::cmd_vm;
+
+/* The most words of a command line that are passed on */
+const MAX_ARGS: usize = 16;
+
+/* Commands to run once boot is done, separated by ';', with ','
+ * between words, e.g. kernel.console.run=pmm,free;vm,aspaces */
+const CONSOLE_RUN_OPTION: &str = "kernel.console.run";
+
+pub type ConsoleCmdFn = fn(&[&str]) -> Result<(), ErrNO>;
+
+pub struct ConsoleCmd {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub func: ConsoleCmdFn,
+}
+
+static COMMANDS: &[ConsoleCmd] = &[
+    ConsoleCmd {
+        name: "help",
+        help: "this list",
+        func: cmd_help,
+    },
+    ConsoleCmd {
+        name: "pmm",
+        help: "physical memory manager",
+        func: cmd_pmm,
+    },
+    ConsoleCmd {
+        name: "vm",
+        help: "virtual memory",
+        func: cmd_vm,
+    },
+];
+
+fn cmd_help(_argv: &[&str]) -> Result<(), ErrNO> {
+    dprint!(ALWAYS, "command list:\n");
+    for cmd in COMMANDS {
+        dprint!(ALWAYS, "\t{:<16}: {}\n", cmd.name, cmd.help);
+    }
+    Ok(())
+}
+
+/* Runs the command |line|, as typed on the debug console. */
+pub fn console_run_command(line: &str) -> Result<(), ErrNO> {
+    let mut argv = [""; MAX_ARGS];
+    let mut argc = 0;
+    for word in line.split_whitespace().take(MAX_ARGS) {
+        argv[argc] = word;
+        argc += 1;
+    }
+
+    if argc == 0 {
+        return Ok(());
+    }
+
+    match COMMANDS.iter().find(|c| c.name == argv[0]) {
+        Some(cmd) => (cmd.func)(&argv[..argc]),
+        None => {
+            dprint!(ALWAYS, "command not found: {}\n", argv[0]);
+            Err(ErrNO::NotFound)
+        }
+    }
+}
+
+/* Runs the commands of the kernel.console.run option, if any.
+ * Called at the end of boot, once the heap is up. */
+pub fn console_run_boot_commands() {
+    let commands = match cmdline_get(CONSOLE_RUN_OPTION) {
+        Some(commands) => commands,
+        None => return,
+    };
+
+    for command in commands.split(';') {
+        let line = command.replace(',', " ");
+        if let Err(errno) = console_run_command(&line) {
+            dprint!(WARN, "console: '{}' failed: {:?}\n",
+                    command, errno);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * The kernel heap.
+ * Until heap_init() every request is served by the boot allocator.
+ * From then on, requests of up to half a page come from power of
+ * two size classes, each made of whole pages taken from the PMM;
+ * larger requests, and larger alignments, get their own run of
+ * contiguous pages. All of it is reached through the physmap,
+ * so the heap never has to map anything.
+ */
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::max;
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{
+    dprint, INFO, CRITICAL, PAGE_SIZE, PAGE_SHIFT, ROUNDUP,
+    ROUNDUP_PAGE_SIZE,
+};
+use crate::kernel::spinlock::SpinLock;
+use crate::lib::list::List;
+use crate::vm::page::vm_page_t;
+use crate::vm::vm_page_state;
+use crate::vm::pmm::{
+    PMM_ALLOC_FLAG_ANY, pmm_alloc_page, pmm_alloc_contiguous,
+    pmm_free_page, pmm_free, pmm_physmap_to_page,
+    pmm_count_free_pages, pmm_count_loaned_free_pages, pmm_count_total_bytes,
+};
+use crate::vm::physmap::paddr_to_physmap;
+use crate::vm::bootalloc::{boot_alloc, boot_alloc_owns, boot_alloc_usage};
+
+/* The smallest object has room for the free list link */
+const MIN_SHIFT: usize = 4;
+/* Anything above half a page gets whole pages */
+const MAX_SMALL_SHIFT: usize = PAGE_SHIFT - 1;
+const NUM_SIZE_CLASSES: usize = MAX_SMALL_SHIFT - MIN_SHIFT + 1;
+
+/* The heap isn't numa aware; start with the first node. */
+const HEAP_NUMA_ID: u32 = 0;
+
+#[derive(Clone, Copy)]
+pub struct HeapInfo {
+    /* bytes taken from the PMM */
+    pub size_bytes: usize,
+    /* bytes handed out, rounded up to their size class or page */
+    pub used_bytes: usize,
+    /* the part of the above in large allocations */
+    pub large_bytes: usize,
+    /* live allocations */
+    pub alloc_count: usize,
+    /* pages held by the heap */
+    pub page_count: usize,
+}
+
+impl HeapInfo {
+    const fn new() -> Self {
+        Self {
+            size_bytes: 0,
+            used_bytes: 0,
+            large_bytes: 0,
+            alloc_count: 0,
+            page_count: 0,
+        }
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.size_bytes - self.used_bytes
+    }
+}
+
+struct SizeClass {
+    /* pages with at least one free object */
+    partial: List<vm_page_t>,
+}
+
+impl SizeClass {
+    const fn new() -> Self {
+        Self { partial: List::new() }
+    }
+}
+
+struct Heap {
+    classes: [SizeClass; NUM_SIZE_CLASSES],
+    info: HeapInfo,
+}
+
+/* The heap pages are only ever touched with the heap lock held. */
+unsafe impl Send for Heap {}
+
+/* The size class for |layout|, or None if it takes whole pages. */
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = max(max(layout.size(), layout.align()), 1 << MIN_SHIFT)
+        .next_power_of_two();
+
+    let shift = size.trailing_zeros() as usize;
+    if shift > MAX_SMALL_SHIFT {
+        return None;
+    }
+    Some(shift - MIN_SHIFT)
+}
+
+fn size_class_obj_size(index: usize) -> usize {
+    1 << (index + MIN_SHIFT)
+}
+
+fn large_page_count(layout: &Layout) -> usize {
+    ROUNDUP_PAGE_SIZE!(layout.size()) / PAGE_SIZE
+}
+
+/* The page holding the heap address |va| */
+fn vaddr_to_page(va: usize) -> NonNull<vm_page_t> {
+    match pmm_physmap_to_page(va) {
+        Some(page) => page,
+        None => panic!("heap: {:x} isn't in any arena", va),
+    }
+}
+
+impl Heap {
+    const fn new() -> Self {
+        const EMPTY: SizeClass = SizeClass::new();
+        Self {
+            classes: [EMPTY; NUM_SIZE_CLASSES],
+            info: HeapInfo::new(),
+        }
+    }
+
+    /* Take a page from the PMM and cut it into objects of
+     * the size class |index|. */
+    fn grow(&mut self, index: usize) -> Option<NonNull<vm_page_t>> {
+        let mut page = pmm_alloc_page(PMM_ALLOC_FLAG_ANY, HEAP_NUMA_ID)
+            .ok()?;
+
+        let obj_size = size_class_obj_size(index);
+        let va = paddr_to_physmap(unsafe { page.as_ref().paddr() });
+
+        /* chain the objects, lowest address first */
+        let count = PAGE_SIZE / obj_size;
+        for i in 0..count {
+            let obj = va + i * obj_size;
+            let next = if i + 1 < count { obj + obj_size } else { 0 };
+            unsafe { *(obj as *mut usize) = next; }
+        }
+
+        unsafe {
+            page.as_mut().set_state(vm_page_state::HEAP);
+            let heap = page.as_mut().heap_mut();
+            heap.free_list = va;
+            heap.in_use = 0;
+        }
+        self.classes[index].partial.add_tail(page);
+
+        self.info.size_bytes += PAGE_SIZE;
+        self.info.page_count += 1;
+        Some(page)
+    }
+
+    fn alloc_small(&mut self, index: usize) -> *mut u8 {
+        let page = match self.classes[index].partial.iter().next() {
+            Some(page) => Some(page),
+            None => self.grow(index),
+        };
+        let mut page = match page {
+            Some(page) => page,
+            None => return null_mut(),
+        };
+
+        let heap = unsafe { page.as_mut().heap_mut() };
+        let obj = heap.free_list;
+        heap.free_list = unsafe { *(obj as *const usize) };
+        heap.in_use += 1;
+
+        /* full pages aren't kept anywhere, the free path
+         * finds them again from the object address */
+        if heap.free_list == 0 {
+            self.classes[index].partial.delete(page);
+        }
+
+        self.info.used_bytes += size_class_obj_size(index);
+        self.info.alloc_count += 1;
+        obj as *mut u8
+    }
+
+    fn free_small(&mut self, ptr: *mut u8, index: usize) {
+        let mut page = vaddr_to_page(ptr as usize);
+        let heap = unsafe { page.as_mut().heap_mut() };
+        debug_assert!(heap.in_use > 0);
+
+        let was_full = heap.free_list == 0;
+        unsafe { *(ptr as *mut usize) = heap.free_list; }
+        heap.free_list = ptr as usize;
+        heap.in_use -= 1;
+        let is_empty = heap.in_use == 0;
+
+        self.info.used_bytes -= size_class_obj_size(index);
+        self.info.alloc_count -= 1;
+
+        let partial = &mut self.classes[index].partial;
+        if was_full {
+            partial.add_tail(page);
+        }
+
+        /* keep one empty page per class around,
+         * give the others back */
+        if is_empty && partial.len() > 1 {
+            partial.delete(page);
+            pmm_free_page(page);
+
+            self.info.size_bytes -= PAGE_SIZE;
+            self.info.page_count -= 1;
+        }
+    }
+
+    fn alloc_large(&mut self, layout: &Layout) -> *mut u8 {
+        let count = large_page_count(layout);
+        let alignment_log2 =
+            max(layout.align().trailing_zeros() as usize, PAGE_SHIFT);
+
+        let mut list = List::<vm_page_t>::new();
+        let pa = match pmm_alloc_contiguous(count, PMM_ALLOC_FLAG_ANY,
+                                            alignment_log2 as u8,
+                                            HEAP_NUMA_ID, &mut list) {
+            Ok(pa) => pa,
+            Err(_) => return null_mut(),
+        };
+
+        for mut page in list.iter() {
+            unsafe { page.as_mut().set_state(vm_page_state::HEAP); }
+        }
+
+        let len = count * PAGE_SIZE;
+        self.info.size_bytes += len;
+        self.info.used_bytes += len;
+        self.info.large_bytes += len;
+        self.info.alloc_count += 1;
+        self.info.page_count += count;
+        paddr_to_physmap(pa) as *mut u8
+    }
+
+    fn free_large(&mut self, ptr: *mut u8, layout: &Layout) {
+        let count = large_page_count(layout);
+
+        let mut list = List::<vm_page_t>::new();
+        for i in 0..count {
+            list.add_tail(vaddr_to_page(ptr as usize + i * PAGE_SIZE));
+        }
+        pmm_free(&mut list);
+
+        let len = count * PAGE_SIZE;
+        self.info.size_bytes -= len;
+        self.info.used_bytes -= len;
+        self.info.large_bytes -= len;
+        self.info.alloc_count -= 1;
+        self.info.page_count -= count;
+    }
+}
+
+static HEAP: SpinLock<Heap> = SpinLock::new(Heap::new());
+static HEAP_READY: AtomicBool = AtomicBool::new(false);
+
+struct KernelHeap;
+
+/* Say why an allocation fails; the caller usually goes
+ * on to the alloc error handler, which dumps the rest. */
+fn report_alloc_failure(layout: &Layout, info: Option<&HeapInfo>) {
+    dprint!(CRITICAL, "HEAP: failed to allocate {} bytes, align {}\n",
+            layout.size(), layout.align());
+
+    match info {
+        Some(info) => {
+            dprint!(CRITICAL, "HEAP: size {:x} used {:x} free {:x}, \
+                    {} allocations\n",
+                    info.size_bytes, info.used_bytes, info.free_bytes(),
+                    info.alloc_count);
+        },
+        None => {
+            let (used, limit) = boot_alloc_usage();
+            dprint!(CRITICAL, "HEAP: boot allocator used {:x} of {:x}\n",
+                    used, limit);
+        },
+    }
+
+    dprint!(CRITICAL, "HEAP: pmm free pages {} (loaned {}), \
+            total {:x} bytes\n",
+            pmm_count_free_pages(), pmm_count_loaned_free_pages(),
+            pmm_count_total_bytes());
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !HEAP_READY.load(Ordering::Acquire) {
+            let ptr = boot_alloc(layout);
+            if ptr.is_null() {
+                report_alloc_failure(&layout, None);
+            }
+            return ptr;
+        }
+
+        let mut heap = HEAP.lock();
+        let ptr = match size_class(&layout) {
+            Some(index) => heap.alloc_small(index),
+            None => heap.alloc_large(&layout),
+        };
+        if ptr.is_null() {
+            report_alloc_failure(&layout, Some(&heap.info));
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        /* what the boot allocator handed out stays allocated */
+        if boot_alloc_owns(ptr) {
+            return;
+        }
+
+        let mut heap = HEAP.lock();
+        match size_class(&layout) {
+            Some(index) => heap.free_small(ptr, index),
+            None => heap.free_large(ptr, &layout),
+        }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap;
+
+/* Switch over from the boot allocator. The PMM must own
+ * all of memory by now, see vm_init_preheap(). */
+pub fn heap_init() {
+    HEAP_READY.store(true, Ordering::Release);
+
+    dprint!(INFO, "HEAP: size classes {} to {} bytes\n",
+            size_class_obj_size(0),
+            size_class_obj_size(NUM_SIZE_CLASSES - 1));
+}
+
+pub fn heap_get_info() -> HeapInfo {
+    HEAP.lock().info
+}
+
+pub fn heap_dump() {
+    let info = heap_get_info();
+    dprint!(INFO, "HEAP: size {:x} used {:x} free {:x} large {:x}\n",
+            info.size_bytes, info.used_bytes, info.free_bytes(),
+            info.large_bytes);
+    dprint!(INFO, "HEAP: {} allocations in {} pages\n",
+            info.alloc_count, info.page_count);
+}
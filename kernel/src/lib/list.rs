@@ -8,6 +8,8 @@
  * A doubly-linked list with outside nodes.
  * The `LinkedList` allows pushing and popping elements
  * at either end in constant time.
+ * The list only keeps pointers to its first and last node,
+ * so it can be moved freely and never allocates.
  */
 
 use core::mem;
@@ -17,8 +19,6 @@ use core::marker::PhantomData;
 pub trait Linked {
     fn from_node(ptr: NonNull<ListNode>) -> Option<NonNull<Self>>;
     fn into_node(&mut self) -> &mut ListNode;
-
-    fn delete_from_list(&mut self);
 }
 
 pub struct ListNode {
@@ -30,25 +30,11 @@ impl ListNode {
     pub fn new() -> Self {
         ListNode {next: None, prev: None}
     }
-
-    pub fn delete_from_list(&mut self) {
-        if self.prev.is_none() || self.next.is_none() {
-            return;
-        }
-
-        if let Some(next) = self.next {
-            unsafe {(*next.as_ptr()).prev = self.prev.take();}
-        }
-
-        if let Some(prev) = self.prev {
-            unsafe {(*prev.as_ptr()).next = self.next.take();}
-        }
-    }
 }
 
 pub struct List<T: Linked> {
-    node: ListNode,
-    ref_node: Option<NonNull<ListNode>>,    /* ref to node */
+    head: Option<NonNull<ListNode>>,
+    tail: Option<NonNull<ListNode>>,
     len: usize,
     marker: PhantomData<NonNull<T>>,
 }
@@ -57,39 +43,27 @@ impl<T: Linked> List<T> {
     /* Creates an empty `LinkedList`. */
     #[inline]
     #[must_use]
-    pub fn new() -> Self {
-        let mut list = List {
-            node: ListNode::new(),
-            ref_node: None,
+    pub const fn new() -> Self {
+        List {
+            head: None,
+            tail: None,
             len: 0,
             marker: PhantomData
-        };
-
-        //list.node = NonNull::new(&mut list._node as *mut ListNode);
-        list.ref_node = NonNull::new(&mut list.node);
-        list.node.next = list.ref_node;
-        list.node.prev = list.ref_node;
-
-        /*
-        let node = NonNull::new(&mut list.node as *mut ListNode);
-        list.node.next = node;
-        list.node.prev = node;
-        */
-
-        list
+        }
     }
 
     /* Adds the given node to the tail of the list. */
     #[inline]
     fn add_tail_node(&mut self, node: &mut ListNode) {
-        node.prev = self.node.prev;
-        node.next = self.ref_node;
+        node.next = None;
+        node.prev = self.tail;
         let node = Some(node.into());
 
-        if let Some(prev) = self.node.prev {
-            unsafe {(*prev.as_ptr()).next = node;}
+        match self.tail {
+            Some(tail) => unsafe {(*tail.as_ptr()).next = node;},
+            None => self.head = node,
         }
-        self.node.prev = node;
+        self.tail = node;
 
         self.len += 1;
     }
@@ -98,41 +72,89 @@ impl<T: Linked> List<T> {
         unsafe {self.add_tail_node(elt.as_mut().into_node());}
     }
 
-    /* Removes and returns the node at the back of the list. */
+    /* Unlinks |node|, which must be on this list. */
     #[inline]
-    fn remove_tail_node(&mut self) -> Option<NonNull<ListNode>> {
-        if self.node.prev == self.ref_node {
-            return None;
-        }
+    fn delete_node(&mut self, node: NonNull<ListNode>) {
+        let (next, prev) = unsafe {
+            let node = &mut *node.as_ptr();
+            (node.next.take(), node.prev.take())
+        };
 
-        let node = self.node.prev;
-        if let Some(mut prev) = self.node.prev {
-            unsafe {prev.as_mut().delete_from_list();}
+        match prev {
+            Some(prev) => unsafe {(*prev.as_ptr()).next = next;},
+            None => self.head = next,
         }
+        match next {
+            Some(next) => unsafe {(*next.as_ptr()).prev = prev;},
+            None => self.tail = prev,
+        }
+
         self.len -= 1;
-        node
+    }
+
+    /* Removes and returns the node at the back of the list. */
+    #[inline]
+    fn remove_tail_node(&mut self) -> Option<NonNull<ListNode>> {
+        let node = self.tail?;
+        self.delete_node(node);
+        Some(node)
     }
 
     pub fn remove_tail(&mut self) -> Option<NonNull<T>> {
         T::from_node(self.remove_tail_node()?)
     }
 
-    pub fn append(&mut self, other: &mut Self) {
-        if other.node.prev == other.ref_node {
-            return;
-        }
+    /* Removes and returns the node at the front of the list. */
+    #[inline]
+    fn remove_head_node(&mut self) -> Option<NonNull<ListNode>> {
+        let node = self.head?;
+        self.delete_node(node);
+        Some(node)
+    }
 
-        if let Some(next) = other.node.next {
-            unsafe {(*next.as_ptr()).prev = self.node.prev;}
-        }
-        if let Some(prev) = other.node.prev {
-            unsafe {(*prev.as_ptr()).next = self.ref_node;}
-        }
+    pub fn remove_head(&mut self) -> Option<NonNull<T>> {
+        T::from_node(self.remove_head_node()?)
+    }
+
+    /* Whether the neighbours of |node| link back to it, as they do
+     * for a node on this list. It can't tell the middle of one list
+     * from another, but catches nodes on no list at all. */
+    fn is_linked_here(&self, node: NonNull<ListNode>) -> bool {
+        let n = unsafe { node.as_ref() };
+        let prev_ok = match n.prev {
+            Some(prev) => unsafe { prev.as_ref().next == Some(node) },
+            None => self.head == Some(node),
+        };
+        let next_ok = match n.next {
+            Some(next) => unsafe { next.as_ref().prev == Some(node) },
+            None => self.tail == Some(node),
+        };
+        prev_ok && next_ok
+    }
+
+    /* Removes the given element, which must be on this list. */
+    pub fn delete(&mut self, mut elt: NonNull<T>) {
+        let node = unsafe { NonNull::from(elt.as_mut().into_node()) };
+        assert!(self.len != 0 && self.is_linked_here(node),
+                "deleting a node that is not on the list");
+        self.delete_node(node);
+    }
+
+    /* Moves all of the elements of |other| to the tail of this list. */
+    pub fn append(&mut self, other: &mut Self) {
+        let other_head = match other.head.take() {
+            Some(head) => head,
+            None => return,
+        };
 
-        if let Some(prev) = self.node.prev {
-            unsafe {(*prev.as_ptr()).next = other.node.next.take();}
+        match self.tail {
+            Some(tail) => unsafe {
+                (*tail.as_ptr()).next = Some(other_head);
+                (*other_head.as_ptr()).prev = Some(tail);
+            },
+            None => self.head = Some(other_head),
         }
-        self.node.prev = other.node.prev.take();
+        self.tail = other.tail.take();
 
         self.len += mem::replace(&mut other.len, 0);
     }
@@ -140,4 +162,32 @@ impl<T: Linked> List<T> {
     pub fn len(&self) -> usize {
         self.len
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /* Walks the list from head to tail. The element handed out
+     * by the iterator may be removed from the list, but no other. */
+    pub fn iter(&self) -> Iter<T> {
+        Iter {
+            next: self.head,
+            marker: PhantomData,
+        }
+    }
+}
+
+pub struct Iter<T: Linked> {
+    next: Option<NonNull<ListNode>>,
+    marker: PhantomData<NonNull<T>>,
+}
+
+impl<T: Linked> Iterator for Iter<T> {
+    type Item = NonNull<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.next?;
+        self.next = unsafe { node.as_ref().next };
+        T::from_node(node)
+    }
 }
@@ -1,3 +1,7 @@
 pub mod libc;
 pub mod debuglog;
 pub mod list;
+pub mod heap;
+pub mod slab;
+pub mod cmdline;
+pub mod console;
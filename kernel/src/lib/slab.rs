@@ -0,0 +1,400 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * Slab caches for hot kernel objects of a fixed type.
+ * A slab is a single page taken from the PMM and tagged SLAB,
+ * cut into objects of one size. Each slab of a cache sits on
+ * its partial, full or empty list; allocations are served from
+ * a partial slab first, then from an empty one, and only then
+ * from a new page.
+ * Optionally each cpu gets a magazine, a small stack of free
+ * objects in front of the slabs, exchanged with them in batches.
+ * A cache isn't locked itself; share it behind a SpinLock.
+ * SlabBox owns an object of such a shared cache, as a Box does
+ * one of the heap.
+ *
+ * The VMAR tree nodes come from slab caches. The other hot
+ * objects don't, for now:
+ * - page table pages are whole pages straight from the PMM;
+ *   they need the MMU state to count their entries and their
+ *   list node to be freed in batches after a TLB flush, which
+ *   a slab page uses for itself. They never went through the
+ *   heap either.
+ * - a VmAspace lives in an Arc that its VMO mappings refer to
+ *   weakly; Arc can't take another allocator on our toolchain,
+ *   so it stays on the heap until it has a refcount of its own.
+ * - there are no threads yet.
+ */
+
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use crate::{
+    dprint, INFO, ErrNO, NR_CPUS, PAGE_SIZE, ROUNDUP,
+};
+use crate::arch::mp::arch_curr_cpu_num;
+use crate::kernel::spinlock::SpinLock;
+use crate::lib::list::List;
+use crate::vm::page::vm_page_t;
+use crate::vm::vm_page_state;
+use crate::vm::pmm::{
+    PMM_ALLOC_FLAG_ANY, pmm_alloc_page, pmm_free_page, pmm_physmap_to_page,
+};
+use crate::vm::physmap::paddr_to_physmap;
+
+/* objects a magazine holds at most */
+const MAGAZINE_SIZE: usize = 16;
+
+/* empty slabs kept before pages go back to the PMM */
+const MAX_EMPTY_SLABS: usize = 1;
+
+/* Slab pages aren't numa aware; start with the first node. */
+const SLAB_NUMA_ID: u32 = 0;
+
+struct Magazine {
+    count: usize,
+    objs: [usize; MAGAZINE_SIZE],
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            objs: [0; MAGAZINE_SIZE],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.count == MAGAZINE_SIZE
+    }
+
+    fn push(&mut self, obj: usize) {
+        self.objs[self.count] = obj;
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        self.count -= 1;
+        Some(self.objs[self.count])
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct SlabCacheInfo {
+    pub name: &'static str,
+    pub obj_size: usize,
+    pub objs_per_slab: usize,
+    /* pages held by the cache */
+    pub slab_count: usize,
+    /* objects handed out */
+    pub alloc_count: usize,
+    /* free objects parked in the magazines */
+    pub magazine_count: usize,
+}
+
+pub struct SlabCache<T> {
+    name: &'static str,
+
+    partial: List<vm_page_t>,
+    full: List<vm_page_t>,
+    empty: List<vm_page_t>,
+
+    use_magazines: bool,
+    magazines: [Magazine; NR_CPUS],
+
+    slab_count: usize,
+    alloc_count: usize,
+
+    marker: PhantomData<T>,
+}
+
+/* The slab pages are only ever touched through the cache. */
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /* Free objects are chained through their first word. */
+    const OBJ_ALIGN: usize =
+        if mem::align_of::<T>() > mem::size_of::<usize>() {
+            mem::align_of::<T>()
+        } else {
+            mem::size_of::<usize>()
+        };
+
+    const OBJ_SIZE: usize =
+        if mem::size_of::<T>() > mem::size_of::<usize>() {
+            ROUNDUP!(mem::size_of::<T>(), Self::OBJ_ALIGN)
+        } else {
+            Self::OBJ_ALIGN
+        };
+
+    /* A cache of objects larger than a page doesn't build */
+    const OBJS_PER_SLAB: usize = {
+        let count = PAGE_SIZE / Self::OBJ_SIZE;
+        assert!(count > 0, "slab objects larger than a page");
+        count
+    };
+
+    pub const fn new(name: &'static str) -> Self {
+        const EMPTY: Magazine = Magazine::new();
+        let _ = Self::OBJS_PER_SLAB;
+        Self {
+            name,
+            partial: List::new(),
+            full: List::new(),
+            empty: List::new(),
+            use_magazines: false,
+            magazines: [EMPTY; NR_CPUS],
+            slab_count: 0,
+            alloc_count: 0,
+            marker: PhantomData,
+        }
+    }
+
+    /* Put a magazine in front of the slabs on every cpu. */
+    pub fn enable_magazines(&mut self) {
+        self.use_magazines = true;
+    }
+
+    /* A new slab, not on any list yet */
+    fn grow(&mut self) -> Result<NonNull<vm_page_t>, ErrNO> {
+        let mut page = pmm_alloc_page(PMM_ALLOC_FLAG_ANY, SLAB_NUMA_ID)?;
+        let va = paddr_to_physmap(unsafe { page.as_ref().paddr() });
+
+        /* chain the objects, lowest address first */
+        for i in 0..Self::OBJS_PER_SLAB {
+            let obj = va + i * Self::OBJ_SIZE;
+            let next = if i + 1 < Self::OBJS_PER_SLAB {
+                obj + Self::OBJ_SIZE
+            } else {
+                0
+            };
+            unsafe { *(obj as *mut usize) = next; }
+        }
+
+        unsafe {
+            page.as_mut().set_state(vm_page_state::SLAB);
+            let slab = page.as_mut().slab_mut();
+            slab.free_list = va;
+            slab.in_use = 0;
+        }
+
+        self.slab_count += 1;
+        Ok(page)
+    }
+
+    fn alloc_from_slabs(&mut self) -> Result<usize, ErrNO> {
+        let mut page = match self.partial.iter().next() {
+            Some(page) => page,
+            None => {
+                let page = match self.empty.remove_head() {
+                    Some(page) => page,
+                    None => self.grow()?,
+                };
+                self.partial.add_tail(page);
+                page
+            }
+        };
+
+        let slab = unsafe { page.as_mut().slab_mut() };
+        let obj = slab.free_list;
+        slab.free_list = unsafe { *(obj as *const usize) };
+        slab.in_use += 1;
+
+        if slab.free_list == 0 {
+            self.partial.delete(page);
+            self.full.add_tail(page);
+        }
+        Ok(obj)
+    }
+
+    fn free_to_slabs(&mut self, obj: usize) {
+        let mut page = match pmm_physmap_to_page(obj) {
+            Some(page) => page,
+            None => panic!("slab {}: {:x} isn't in any arena",
+                           self.name, obj),
+        };
+
+        let slab = unsafe { page.as_mut().slab_mut() };
+        debug_assert!(slab.in_use > 0);
+
+        let was_full = slab.free_list == 0;
+        unsafe { *(obj as *mut usize) = slab.free_list; }
+        slab.free_list = obj;
+        slab.in_use -= 1;
+
+        if was_full {
+            self.full.delete(page);
+            self.partial.add_tail(page);
+        }
+
+        if slab.in_use == 0 {
+            self.partial.delete(page);
+            self.empty.add_tail(page);
+            self.trim_empty(MAX_EMPTY_SLABS);
+        }
+    }
+
+    /* Give back the empty slabs beyond |keep|.
+     * Returns the number of pages freed. */
+    fn trim_empty(&mut self, keep: usize) -> usize {
+        let mut freed = 0;
+        while self.empty.len() > keep {
+            if let Some(page) = self.empty.remove_head() {
+                pmm_free_page(page);
+                self.slab_count -= 1;
+                freed += 1;
+            }
+        }
+        freed
+    }
+
+    fn alloc_obj(&mut self) -> Result<usize, ErrNO> {
+        if !self.use_magazines {
+            return self.alloc_from_slabs();
+        }
+
+        /* refill half of an empty magazine at once */
+        let cpu = arch_curr_cpu_num();
+        if self.magazines[cpu].is_empty() {
+            for _ in 0..(MAGAZINE_SIZE / 2) {
+                match self.alloc_from_slabs() {
+                    Ok(obj) => self.magazines[cpu].push(obj),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        self.magazines[cpu].pop().ok_or_else(|| ErrNO::NoMem)
+    }
+
+    fn free_obj(&mut self, obj: usize) {
+        if !self.use_magazines {
+            return self.free_to_slabs(obj);
+        }
+
+        /* flush half of a full magazine at once */
+        let cpu = arch_curr_cpu_num();
+        if self.magazines[cpu].is_full() {
+            for _ in 0..(MAGAZINE_SIZE / 2) {
+                if let Some(obj) = self.magazines[cpu].pop() {
+                    self.free_to_slabs(obj);
+                }
+            }
+        }
+
+        self.magazines[cpu].push(obj);
+    }
+
+    /* Move |value| into a new object of the cache. */
+    pub fn alloc(&mut self, value: T) -> Result<NonNull<T>, ErrNO> {
+        let obj = self.alloc_obj()? as *mut T;
+        unsafe { ptr::write(obj, value); }
+
+        self.alloc_count += 1;
+        NonNull::new(obj).ok_or_else(|| ErrNO::NoMem)
+    }
+
+    /* Drop the object and give it back to the cache. */
+    pub fn free(&mut self, obj: NonNull<T>) {
+        debug_assert!(self.alloc_count > 0);
+        unsafe { ptr::drop_in_place(obj.as_ptr()); }
+
+        self.alloc_count -= 1;
+        self.free_obj(obj.as_ptr() as usize);
+    }
+
+    /* Move the value out of the object and give the object back
+     * to the cache, so that the value can be dropped later. */
+    pub fn take(&mut self, obj: NonNull<T>) -> T {
+        debug_assert!(self.alloc_count > 0);
+        let value = unsafe { ptr::read(obj.as_ptr()) };
+
+        self.alloc_count -= 1;
+        self.free_obj(obj.as_ptr() as usize);
+        value
+    }
+
+    /* Flush the magazines and give all the empty slabs back.
+     * Returns the number of pages freed. */
+    pub fn reap(&mut self) -> usize {
+        for cpu in 0..NR_CPUS {
+            while let Some(obj) = self.magazines[cpu].pop() {
+                self.free_to_slabs(obj);
+            }
+        }
+        self.trim_empty(0)
+    }
+
+    pub fn info(&self) -> SlabCacheInfo {
+        SlabCacheInfo {
+            name: self.name,
+            obj_size: Self::OBJ_SIZE,
+            objs_per_slab: Self::OBJS_PER_SLAB,
+            slab_count: self.slab_count,
+            alloc_count: self.alloc_count,
+            magazine_count: self.magazines.iter().map(|m| m.count).sum(),
+        }
+    }
+
+    pub fn dump(&self) {
+        let info = self.info();
+        dprint!(INFO, "SLAB {}: obj size {} ({} per slab)\n",
+                info.name, info.obj_size, info.objs_per_slab);
+        dprint!(INFO, "SLAB {}: {} slabs ({} partial, {} full, {} empty), \
+                {} objects in use, {} in magazines\n",
+                info.name, info.slab_count, self.partial.len(),
+                self.full.len(), self.empty.len(), info.alloc_count,
+                info.magazine_count);
+    }
+}
+
+/* An object of |cache|, dropped and given back along with the box */
+pub struct SlabBox<T: 'static> {
+    obj: NonNull<T>,
+    cache: &'static SpinLock<SlabCache<T>>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+
+impl<T> SlabBox<T> {
+    pub fn new(cache: &'static SpinLock<SlabCache<T>>, value: T)
+        -> Result<Self, ErrNO> {
+        let obj = cache.lock().alloc(value)?;
+        Ok(Self { obj, cache })
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.obj.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.obj.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        /* the value goes once the cache is unlocked, as dropping it
+         * may free more objects of the same cache */
+        let value = self.cache.lock().take(self.obj);
+        drop(value);
+    }
+}
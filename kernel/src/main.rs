@@ -7,7 +7,7 @@
 #![no_std]
 #![no_main]
 #![feature(naked_functions, asm_sym, asm_const)]
-#![feature(default_alloc_error_handler)]
+#![feature(alloc_error_handler)]
 #![feature(fn_align)]
 #![feature(repr_simd)]
 #![feature(allow_internal_unstable)]
@@ -18,7 +18,7 @@ mod errors;
 mod types;
 mod lib;
 mod boot;
-//mod kernel;
+mod kernel;
 mod vm;
 mod config_generated;
 
@@ -38,7 +38,7 @@ use core::sync::atomic::AtomicI32;
 use crate::arch::sbi::*;
 use crate::arch::defines::*;
 use crate::platform::platform_early_init;
-//use crate::kernel::thread::thread_init_early;
+use crate::kernel::thread::thread_init_early;
 use crate::lib::debuglog::debuglog::*;
 use alloc::vec::Vec;
 use crate::vm::bootreserve::{MAX_RESERVES, BootReserveRange};
@@ -47,8 +47,8 @@ use crate::vm::pmm_node::PmmNode;
 use crate::errors::ErrNO;
 use crate::arch::periphmap::{PeriphRange, MAX_PERIPH_RANGES};
 use crate::vm::vm::vm_init_preheap;
-use crate::vm::vm_aspace::VmAspace;
-use crate::lib::list::List;
+use crate::lib::heap::heap_init;
+use crate::lib::console::console_run_boot_commands;
 
 pub struct BootContext {
     hartid: usize,
@@ -57,9 +57,8 @@ pub struct BootContext {
     /* peripheral ranges are allocated below the kernel image. */
     periph_ranges: Vec<PeriphRange>,
     periph_base_virt: vaddr_t,
-    /* The (currently) one and only pmm node */
-    pmm_node: PmmNode,
-    aspaces: List<VmAspace>,
+    /* The numa node of the boot hart */
+    numa_id: u32,
 }
 
 impl BootContext {
@@ -72,8 +71,7 @@ impl BootContext {
             periph_ranges:
                 Vec::<PeriphRange>::with_capacity(MAX_PERIPH_RANGES),
             periph_base_virt: 0,
-            pmm_node: PmmNode::new(),
-            aspaces: List::<VmAspace>::new(),
+            numa_id: 0,
         }
     }
 
@@ -99,7 +97,7 @@ fn lk_main(hartid: usize, dtb_pa: paddr_t) -> Result<(), ErrNO> {
     let mut ctx = BootContext::new(hartid, dtb_pa);
 
     /* get us into some sort of thread context so Thread::Current works. */
-    //thread_init_early();
+    thread_init_early();
 
     /* bring the debuglog up early so we can safely printf */
     dlog_init_early();
@@ -136,9 +134,17 @@ fn lk_main(hartid: usize, dtb_pa: paddr_t) -> Result<(), ErrNO> {
 
     /* perform basic virtual memory setup */
     dprint!(SPEW, "initializing vm pre-heap\n");
-    vm_init_preheap();
+    vm_init_preheap()?;
     // lk_primary_cpu_init_level(LK_INIT_LEVEL_VM_PREHEAP,
     //                           LK_INIT_LEVEL_HEAP - 1);
 
+    /* bring up the kernel heap */
+    dprint!(SPEW, "initializing heap\n");
+    heap_init();
+    // lk_primary_cpu_init_level(LK_INIT_LEVEL_HEAP, LK_INIT_LEVEL_VM - 1);
+
+    /* debug console commands asked for on the command line */
+    console_run_boot_commands();
+
     Ok(())
 }
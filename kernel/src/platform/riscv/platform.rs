@@ -5,22 +5,32 @@
  */
 
 use core::slice;
+use core::arch::asm;
 use crate::{
     BootContext, dprint, CRITICAL, INFO, WARN,
-    PAGE_SIZE, ROUNDUP_PAGE_SIZE,
+    PAGE_SIZE, ROUNDUP_PAGE_SIZE, BOOT_HEAP_SIZE,
     kernel_base_phys, kernel_size,
 };
 use crate::errors::ErrNO;
-use crate::vm::bootreserve::boot_reserve_init;
+use crate::vm::bootreserve::{
+    boot_reserve_init, boot_reserve_add_range, boot_table_reserve,
+};
 use crate::vm::physmap::paddr_to_physmap;
 use crate::vm::pmm::{
     MAX_ARENAS, ArenaInfo, pmm_add_arena, pmm_alloc_range,
+    pmm_init_default_reclamation, pmm_dump_node_stats, pmm_checker_init,
+    pmm_dump_map, pmm_set_numa_distance,
 };
+use crate::lib::cmdline::{cmdline_init, cmdline_get_bool};
+use crate::vm::pmm_arena::MAX_SPARSE_ARENA_SIZE;
 use crate::vm::page::vm_page_t;
+use crate::vm::vm_page_state;
+use crate::vm::bootalloc::boot_alloc_start_phys;
 use alloc::vec::Vec;
-use device_tree::DeviceTree;
+use device_tree::{DeviceTree, Node};
 use crate::boot::image::*;
 use crate::arch::periphmap::add_periph_range;
+use crate::arch::mmu::{mmu_mode_name, riscv64_mmu_enable_svpbmt};
 use crate::lib::list::List;
 
 type ZBIMemRangeVec = Vec<ZBIMemRange>;
@@ -34,7 +44,7 @@ fn process_phys_handoff(ctx: &mut BootContext)
     /* discover memory ranges */
     let mut mem_config = parse_dtb(ctx)?;
 
-    init_mem_config_arch(&mut mem_config);
+    init_mem_config_arch(&mut mem_config)?;
 
     process_mem_ranges(ctx, mem_config)
 }
@@ -46,28 +56,15 @@ fn boot_reserve_wire(ctx: &mut BootContext) -> Result<(), ErrNO> {
 
         let pages = ROUNDUP_PAGE_SIZE!(r.len) / PAGE_SIZE;
         let mut alloc_page_list = List::<vm_page_t>::new();
-        pmm_alloc_range(r.pa, pages,
-                        &mut ctx.pmm_node,
-                        &mut alloc_page_list)?;
+        pmm_alloc_range(r.pa, pages, &mut alloc_page_list)?;
+
+        /* mark all of the pages we allocated as WIRED */
+        for mut p in alloc_page_list.iter() {
+            unsafe { p.as_mut().set_state(vm_page_state::WIRED); }
+        }
     }
 
     Ok(())
-
-    /*
-    if (list_is_empty(&reserved_page_list)) {
-      list_move(&alloc_page_list, &reserved_page_list);
-    } else {
-      list_splice_after(&alloc_page_list, list_peek_tail(&reserved_page_list));
-    }
-  */
-
-  // mark all of the pages we allocated as WIRED
-    /*
-  vm_page_t* p;
-  list_for_every_entry (&reserved_page_list, p, vm_page_t, queue_node) {
-    p->set_state(vm_page_state::WIRED);
-  }
-  */
 }
 
 fn process_mem_ranges(ctx: &mut BootContext,
@@ -75,6 +72,7 @@ fn process_mem_ranges(ctx: &mut BootContext,
     -> Result<Vec<ArenaInfo>, ErrNO> {
 
     let mut mem_arenas = Vec::<ArenaInfo>::with_capacity(MAX_ARENAS);
+    let mut reserved = Vec::<ZBIMemRange>::new();
 
     for range in mem_config {
         match &(range.mtype) {
@@ -82,15 +80,11 @@ fn process_mem_ranges(ctx: &mut BootContext,
                 dprint!(INFO, "ZBI: mem arena {:x} - {:x}\n",
                         range.paddr, range.length);
 
-                if mem_arenas.len() >= MAX_ARENAS {
-                    dprint!(CRITICAL,
-                            "ZBI: too many memory arenas,
-                             dropping additional\n");
-                    break;
-                }
-                mem_arenas.push(
-                    ArenaInfo::new("ram", 0, range.paddr, range.length)
-                );
+                boot_table_reserve(&mut mem_arenas, MAX_ARENAS, "arena")?;
+                let mut info =
+                    ArenaInfo::new("ram", 0, range.paddr, range.length);
+                info.numa_id = range.numa_id;
+                mem_arenas.push(info);
             },
             ZBIMemRangeType::PERIPHERAL => {
                 dprint!(INFO, "ZBI: peripheral range {:x} - {:x}\n",
@@ -100,17 +94,62 @@ fn process_mem_ranges(ctx: &mut BootContext,
             ZBIMemRangeType::_RESERVED => {
                 dprint!(WARN, "FIND RESERVED Memory Range {:x} {:x}!\n",
                         range.paddr, range.length);
+                reserved.push(range);
             }
         }
     }
 
+    let mut mem_arenas = merge_mem_arenas(mem_arenas);
+
+    /* reserved memory inside of ram is a hole in its arena */
+    for range in reserved {
+        let end = range.paddr + range.length;
+        if let Some(a) = mem_arenas.iter_mut()
+            .find(|a| range.paddr < a.base + a.size && a.base < end) {
+            a.add_hole(range.paddr, range.length);
+        }
+    }
+
     Ok(mem_arenas)
 }
 
-fn init_mem_config_arch(config: &mut Vec<ZBIMemRange>) {
+/* Ram ranges of a numa node that are close enough go into a single
+ * arena, with holes for the gaps between them: the page array of
+ * an arena only covers the parts with memory. */
+fn merge_mem_arenas(mut arenas: Vec<ArenaInfo>) -> Vec<ArenaInfo> {
+    arenas.sort_unstable_by_key(|a| a.base);
+
+    let mut merged = Vec::<ArenaInfo>::with_capacity(arenas.len());
+    for a in arenas {
+        if let Some(last) = merged.last_mut() {
+            let last_end = last.base + last.size;
+            let end = a.base + a.size;
+            if last.numa_id == a.numa_id && last.flags == a.flags &&
+               a.base >= last_end &&
+               end - last.base <= MAX_SPARSE_ARENA_SIZE {
+                dprint!(INFO, "merging ram {:x} - {:x} into arena at {:x}\n",
+                        a.base, end, last.base);
+                if a.base > last_end {
+                    last.add_hole(last_end, a.base - last_end);
+                }
+                last.size = end - last.base;
+                last.holes.extend(a.holes);
+                continue;
+            }
+        }
+        merged.push(a);
+    }
+    merged
+}
+
+fn init_mem_config_arch(config: &mut Vec<ZBIMemRange>)
+    -> Result<(), ErrNO> {
+
+    boot_table_reserve(config, MAX_ZBI_MEM_RANGES, "mem range")?;
     config.push(
         ZBIMemRange::new(ZBIMemRangeType::PERIPHERAL, 0, 0x40000000)
     );
+    Ok(())
 }
 
 fn fdt_get_u32(dtb_va: usize, offset: usize) -> u32 {
@@ -201,8 +240,55 @@ fn early_init_dt_scan_chosen(dt: &DeviceTree) -> &str {
 }
 
 fn early_init_dt_add_memory_arch(config: &mut Vec<ZBIMemRange>,
-                                 base: usize, size: usize) {
-    config.push(ZBIMemRange::new(ZBIMemRangeType::RAM, base, size));
+                                 base: usize, size: usize,
+                                 numa_id: u32)
+    -> Result<(), ErrNO> {
+
+    boot_table_reserve(config, MAX_ZBI_MEM_RANGES, "mem range")?;
+    let mut range = ZBIMemRange::new(ZBIMemRangeType::RAM, base, size);
+    range.numa_id = numa_id;
+    config.push(range);
+    Ok(())
+}
+
+/* The "numa-node-id" of a memory or cpu node, 0 if absent. */
+fn of_node_to_nid(node: &Node) -> u32 {
+    node.prop_u32("numa-node-id").unwrap_or(0)
+}
+
+/*
+ * early_init_dt_scan_distance_map - the numa distances of the
+ * "numa-distance-map-v1" node, as (from, to, distance) triplets
+ */
+fn early_init_dt_scan_distance_map(dt: &DeviceTree) {
+    let map = match dt.find("/distance-map") {
+        Some(node) => node,
+        None => return,
+    };
+    match map.prop_str("compatible") {
+        Ok("numa-distance-map-v1") => (),
+        _ => {
+            dprint!(WARN, "distance-map: unknown compatible\n");
+            return;
+        }
+    }
+
+    let len = map.prop_len("distance-matrix");
+    let mut pos = 0;
+    while pos + 12 <= len {
+        let entry = (map.prop_u32_at("distance-matrix", pos),
+                     map.prop_u32_at("distance-matrix", pos + 4),
+                     map.prop_u32_at("distance-matrix", pos + 8));
+        pos += 12;
+        if let (Ok(from), Ok(to), Ok(distance)) = entry {
+            dprint!(INFO, "numa distance {} -> {}: {}\n",
+                    from, to, distance);
+            if pmm_set_numa_distance(from, to, distance).is_err() {
+                dprint!(WARN, "distance-map: bad entry {} {} {}\n",
+                        from, to, distance);
+            }
+        }
+    }
 }
 
 /*
@@ -227,6 +313,8 @@ fn early_init_dt_scan_memory(dt: &DeviceTree,
             continue;
         }
 
+        let numa_id = of_node_to_nid(child);
+
         let mut pos = 0;
         let reg_len = child.prop_len("reg");
         while pos < reg_len {
@@ -247,15 +335,68 @@ fn early_init_dt_scan_memory(dt: &DeviceTree,
             if size == 0 {
                 continue;
             }
-            dprint!(INFO, " - 0x{:x}, 0x{:x}\n", base, size);
+            dprint!(INFO, " - 0x{:x}, 0x{:x} numa {}\n",
+                    base, size, numa_id);
 
-            early_init_dt_add_memory_arch(&mut mem_config, base, size);
+            early_init_dt_add_memory_arch(&mut mem_config, base, size,
+                                          numa_id)?;
         }
     }
 
     Ok(mem_config)
 }
 
+/* Whether the cpu node lists the ISA extension |ext|, in
+ * "riscv,isa-extensions" or else in the "riscv,isa" string */
+fn of_cpu_has_isa_ext(cpu: &Node, ext: &str) -> bool {
+    if let Some(raw) = cpu.prop_raw("riscv,isa-extensions") {
+        return raw.split(|&c| c == 0)
+            .any(|name| name == ext.as_bytes());
+    }
+    match cpu.prop_str("riscv,isa") {
+        Ok(isa) => isa.split('_').skip(1).any(|name| name == ext),
+        Err(_) => false,
+    }
+}
+
+/*
+ * early_init_dt_scan_cpus - find the numa node of the boot hart,
+ * and the extensions of the MMU it has
+ */
+fn early_init_dt_scan_cpus(dt: &DeviceTree, hartid: usize) -> u32 {
+    let cpus = match dt.find("/cpus") {
+        Some(node) => node,
+        None => return 0,
+    };
+
+    for cpu in &cpus.children {
+        if let Ok(t) = cpu.prop_str("device_type") {
+            if t != "cpu" {
+                continue;
+            }
+        } else {
+            continue;
+        }
+
+        /* The hart id of a riscv cpu is its "reg". */
+        if let Ok(reg) = cpu.prop_u32("reg") {
+            if reg as usize == hartid {
+                if let Ok(t) = cpu.prop_str("mmu-type") {
+                    dprint!(INFO, "boot hart mmu-type {}, running {}\n",
+                            t, mmu_mode_name());
+                }
+                if of_cpu_has_isa_ext(cpu, "svpbmt") {
+                    dprint!(INFO, "boot hart has svpbmt\n");
+                    riscv64_mmu_enable_svpbmt();
+                }
+                return of_node_to_nid(cpu);
+            }
+        }
+    }
+
+    0
+}
+
 fn early_init_dt_scan(dt: &DeviceTree)
     -> Result<ZBIMemRangeVec, ErrNO> {
 
@@ -265,6 +406,7 @@ fn early_init_dt_scan(dt: &DeviceTree)
     /* Retrieve various information from the /chosen node */
     let cmdline = early_init_dt_scan_chosen(dt);
     dprint!(INFO, "command line = {}\n", cmdline);
+    cmdline_init(cmdline);
 
     /* Setup memory, calling early_init_dt_add_memory_arch */
     early_init_dt_scan_memory(dt, addr_cells, size_cells)
@@ -280,6 +422,11 @@ pub fn parse_dtb(ctx: &mut BootContext)
 
     let dt = early_init_dt_load(dtb_va)?;
 
+    ctx.numa_id = early_init_dt_scan_cpus(&dt, ctx.hartid);
+    dprint!(INFO, "boot hart {} is on numa node {}\n",
+            ctx.hartid, ctx.numa_id);
+    early_init_dt_scan_distance_map(&dt);
+
     early_init_dt_scan(&dt)
 }
 
@@ -289,18 +436,25 @@ pub fn platform_early_init(ctx: &mut BootContext)
     boot_reserve_init(kernel_base_phys(), kernel_size(),
                       &mut ctx.reserve_ranges)?;
 
+    /* the boot heap just follows the kernel image;
+     * its unused part is given back in vm_init_preheap. */
+    boot_reserve_add_range(boot_alloc_start_phys(), BOOT_HEAP_SIZE,
+                           &mut ctx.reserve_ranges)?;
+
     let mut mem_arenas = process_phys_handoff(ctx)?;
 
     /* find memory ranges to use if one is found. */
     loop {
         if let Some(a) = mem_arenas.pop() {
-            pmm_add_arena(a, &mut (ctx.pmm_node),
-                          &(ctx.reserve_ranges))?;
+            pmm_add_arena(a, &(ctx.reserve_ranges))?;
         } else {
             break;
         }
     }
 
+    pmm_init_default_reclamation()?;
+    pmm_dump_node_stats();
+
     for range in &(ctx.periph_ranges) {
         dprint!(INFO, "PERIPH: {:x} -> {:x}, {:x}\n",
                 range.base_phys, range.base_virt, range.length);
@@ -309,5 +463,19 @@ pub fn platform_early_init(ctx: &mut BootContext)
     dprint!(INFO, "platform early init ok!\n");
 
     /* tell the boot allocator to mark ranges we've reserved. */
-    boot_reserve_wire(ctx)
+    boot_reserve_wire(ctx)?;
+
+    if cmdline_get_bool("kernel.pmm.dump-map", false) {
+        pmm_dump_map();
+    }
+
+    pmm_checker_init();
+    Ok(())
+}
+
+/* Stop this hart for good. */
+pub fn platform_halt() -> ! {
+    loop {
+        unsafe { asm!("wfi"); }
+    }
 }
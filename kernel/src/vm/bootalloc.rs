@@ -15,8 +15,18 @@ use core::sync::atomic::{
 
 const MAX_SUPPORTED_ALIGN: usize = 4096;
 
+/*
+ * The boot heap is the BOOT_HEAP_SIZE bytes that just follow
+ * the kernel image. It is reserved as a whole at boot; once
+ * the PMM is up the allocator is sealed and the unused tail
+ * is given back (see vm_init_preheap).
+ * The kernel heap takes over from there; what was allocated
+ * here is never freed.
+ */
 struct BootAllocator {
     allocated: AtomicUsize,
+    /* no allocation may go beyond this offset */
+    limit: AtomicUsize,
 }
 
 unsafe impl GlobalAlloc for BootAllocator {
@@ -33,7 +43,7 @@ unsafe impl GlobalAlloc for BootAllocator {
         if self.allocated.fetch_update(SeqCst, SeqCst, |mut allocated| {
             curr = ROUNDUP!(allocated, align);
             allocated = curr + size;
-            if allocated > BOOT_HEAP_SIZE {
+            if allocated > self.limit.load(SeqCst) {
                 return None;
             }
             Some(allocated)
@@ -48,7 +58,39 @@ unsafe impl GlobalAlloc for BootAllocator {
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
 }
 
-#[global_allocator]
 static ALLOCATOR: BootAllocator = BootAllocator {
     allocated: AtomicUsize::new(0),
+    limit: AtomicUsize::new(BOOT_HEAP_SIZE),
 };
+
+pub fn boot_alloc(layout: Layout) -> *mut u8 {
+    unsafe { ALLOCATOR.alloc(layout) }
+}
+
+/* Bytes handed out so far, and the most that may be */
+pub fn boot_alloc_usage() -> (usize, usize) {
+    (ALLOCATOR.allocated.load(SeqCst), ALLOCATOR.limit.load(SeqCst))
+}
+
+/* Whether |ptr| came from the boot allocator. */
+pub fn boot_alloc_owns(ptr: *mut u8) -> bool {
+    let start = _end as usize;
+    (ptr as usize) >= start && (ptr as usize) < start + BOOT_HEAP_SIZE
+}
+
+pub fn boot_alloc_start_phys() -> paddr_t {
+    kernel_base_phys() + kernel_size()
+}
+
+/* Stop the boot allocator from growing any further.
+ * Returns the physical end of the used range, page aligned. */
+pub fn boot_alloc_seal() -> paddr_t {
+    let mut used = 0;
+    let _ = ALLOCATOR.allocated.fetch_update(SeqCst, SeqCst, |allocated| {
+        used = ROUNDUP_PAGE_SIZE!(allocated);
+        Some(used)
+    });
+    ALLOCATOR.limit.store(used, SeqCst);
+
+    boot_alloc_start_phys() + used
+}
@@ -4,18 +4,49 @@
  * at https://opensource.org/licenses/MIT
  */
 
+/*
+ * Boot reserve ranges track the physical memory in use before
+ * the PMM is up: the kernel image, the boot heap, page arrays and
+ * whatever else is allocated from here. The list is kept sorted
+ * and ranges never overlap. Once the arenas are added, every
+ * range still reserved is wired in the PMM.
+ * The interval logic itself is in the boot_reserve crate, where
+ * it is tested on the host.
+ */
+
 use alloc::vec::Vec;
+use boot_reserve::BootReserveError;
 use crate::{
-    dprint, INFO, paddr_t,
+    dprint, INFO, WARN, paddr_t,
 };
 use crate::errors::ErrNO;
+use crate::config_generated::*;
 
-pub const MAX_RESERVES: usize = 64;
+pub use boot_reserve::{BootReserveRange, BootAllocPolicy};
 
-#[derive(Default)]
-pub struct BootReserveRange {
-    pub pa: usize,
-    pub len: usize,
+pub const MAX_RESERVES: usize = _CONFIG_MAX_RESERVES;
+
+impl From<BootReserveError> for ErrNO {
+    fn from(err: BootReserveError) -> Self {
+        match err {
+            BootReserveError::InvalidArgs => ErrNO::InvalidArgs,
+            BootReserveError::BadRange => ErrNO::BadRange,
+            BootReserveError::NotFound => ErrNO::NotFound,
+            BootReserveError::NoMem => ErrNO::NoMem,
+        }
+    }
+}
+
+/* Make room for one more entry in the boot table |table|.
+ * Boot tables are sized up front from config.ini; going past
+ * that only costs boot heap, and fails cleanly once it runs out. */
+pub fn boot_table_reserve<T>(table: &mut Vec<T>, max: usize, name: &str)
+    -> Result<(), ErrNO> {
+
+    if table.len() == max {
+        dprint!(WARN, "BOOT: {} table grows past {} entries\n", name, max);
+    }
+    table.try_reserve(1).map_err(|_| ErrNO::NoMem)
 }
 
 pub fn boot_reserve_init(pa: paddr_t, len: usize,
@@ -25,96 +56,68 @@ pub fn boot_reserve_init(pa: paddr_t, len: usize,
     boot_reserve_add_range(pa, len, ranges)
 }
 
-/* given two offset/length pairs, determine if they overlap at all */
-#[inline]
-fn intersects(offset1: usize, len1: usize,
-              offset2: usize, len2: usize) -> bool {
-    /* Can't overlap a zero-length region. */
-    if len1 == 0 || len2 == 0 {
-        return false;
-    }
-
-    if offset1 <= offset2 {
-        /* doesn't intersect, 1 is completely below 2 */
-        if offset1 + len1 <= offset2 {
-            return false;
-        }
-    } else if offset1 >= offset2 + len2 {
-        /* 1 is completely above 2 */
-        return false;
-    }
-
-    true
-}
-
-fn boot_reserve_add_range(pa: usize, len: usize,
-                          ranges: &mut Vec<BootReserveRange>)
+pub fn boot_reserve_add_range(pa: usize, len: usize,
+                              ranges: &mut Vec<BootReserveRange>)
     -> Result<(), ErrNO> {
 
+    boot_table_reserve(ranges, MAX_RESERVES, "reserve")?;
+    boot_reserve::add_range(pa, len, ranges)?;
+
     dprint!(INFO, "PMM: boot reserve add [0x{:x}, 0x{:x}]\n",
             pa, pa + len - 1);
-
-    let end = pa + len - 1;
-
-    /* insert into the list, sorted */
-    let mut i = 0;
-    while i < ranges.len() {
-        if intersects(ranges[i].pa, ranges[i].len, pa, len) {
-            return Err(ErrNO::BadRange);
-        }
-
-        if ranges[i].pa > end {
-            break;
-        }
-
-        i += 1;
-    }
-
-    let range = BootReserveRange{pa: pa, len: len};
-    ranges.insert(i, range);
-
     dprint!(INFO, "Boot reserve #range {}\n", ranges.len());
     Ok(())
 }
 
-fn upper_align(range_pa: paddr_t, range_len: usize,
-               alloc_len: usize) -> paddr_t {
-    range_pa + range_len - alloc_len
+/* Give back [pa, pa + len), which must lie within a single
+ * reserved range; that range shrinks or is split in two.
+ * Only makes sense before the reserves are wired in the PMM. */
+pub fn boot_reserve_free(pa: paddr_t, len: usize,
+                         ranges: &mut Vec<BootReserveRange>)
+    -> Result<(), ErrNO> {
+
+    boot_table_reserve(ranges, MAX_RESERVES, "reserve")?;
+    boot_reserve::free(pa, len, ranges)?;
+
+    dprint!(INFO, "PMM: boot reserve free [0x{:x}, 0x{:x}]\n",
+            pa, pa + len - 1);
+    Ok(())
 }
 
+/* Search [range_pa, range_pa + range_len) for |alloc_len| bytes,
+ * aligned to |align| (a power of two), that don't intersect any
+ * of the reserved |ranges|. Nothing gets reserved here. */
 pub fn boot_reserve_range_search(range_pa: paddr_t,
                                  range_len: usize,
                                  alloc_len: usize,
-                                 ranges: &Vec<BootReserveRange>,
+                                 align: usize,
+                                 policy: BootAllocPolicy,
+                                 ranges: &[BootReserveRange],
                                  alloc_range: &mut BootReserveRange)
     -> Result<(), ErrNO> {
 
-    dprint!(INFO, "range pa {:x} len {:x} alloc_len {:x}\n",
-            range_pa, range_len, alloc_len);
-
-    let mut alloc_pa = upper_align(range_pa, range_len, alloc_len);
+    dprint!(INFO, "range pa {:x} len {:x} alloc_len {:x} align {:x}\n",
+            range_pa, range_len, alloc_len, align);
 
-    /* see if it intersects any reserved range */
-    dprint!(INFO, "trying alloc range {:x} len {:x}\n",
-            alloc_pa, alloc_len);
-
-    'retry: loop {
-        for r in ranges {
-            if intersects(r.pa, r.len, alloc_pa, alloc_len) {
-                alloc_pa = r.pa - alloc_len;
-                /* make sure this still works with input constraints */
-                if alloc_pa < range_pa {
-                    return Err(ErrNO::NoMem);
-                }
+    *alloc_range = boot_reserve::range_search(range_pa, range_len,
+                                              alloc_len, align,
+                                              policy, ranges)?;
+    Ok(())
+}
 
-                continue 'retry;
-            }
-        }
+/* Reserve |len| bytes aligned to |align| anywhere in the memory
+ * ranges |mem|. Physically contiguous ranges are searched as one,
+ * so an allocation may span several of them. */
+pub fn boot_reserve_alloc(len: usize, align: usize,
+                          policy: BootAllocPolicy,
+                          mem: &[BootReserveRange],
+                          ranges: &mut Vec<BootReserveRange>)
+    -> Result<paddr_t, ErrNO> {
 
-        break;
-    }
+    boot_table_reserve(ranges, MAX_RESERVES, "reserve")?;
+    let pa = boot_reserve::alloc(len, align, policy, mem, ranges)?;
 
-    alloc_range.pa = alloc_pa;
-    alloc_range.len = alloc_len;
-    Ok(())
+    dprint!(INFO, "PMM: boot reserve alloc [0x{:x}, 0x{:x}]\n",
+            pa, pa + len - 1);
+    Ok(pa)
 }
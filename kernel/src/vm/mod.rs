@@ -4,7 +4,13 @@ pub mod physmap;
 pub mod pmm;
 pub mod pmm_node;
 pub mod pmm_arena;
+pub mod pmm_checker;
 pub mod page;
 pub mod vm;
 pub mod vm_page_state;
 pub mod vm_aspace;
+pub mod vm_address_region;
+pub mod vm_mapping;
+pub mod vm_object;
+pub mod vm_object_paged;
+pub mod vm_object_physical;
@@ -8,9 +8,9 @@
 #![allow(non_camel_case_types)]
 
 use core::ptr::NonNull;
-use core::sync::atomic::{AtomicU8, Ordering};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 
-use crate::paddr_t;
+use crate::{paddr_t, dprint, CRITICAL};
 use crate::lib::list::{ListNode, Linked};
 use crate::vm::vm_page_state;
 use crate::vm::vm_page_state::vm_page_state_t;
@@ -18,6 +18,71 @@ use crate::vm::vm_page_state::vm_page_state_t;
 const IsLoaned:         u8 = 1;
 const IsLoanCancelled:  u8 = 2;
 
+/* Number of pages in each state; |vm_page_counts.by_state| */
+static VM_PAGE_COUNTS: [AtomicUsize; vm_page_state::COUNT_] = {
+    /* only ever copied into the array, never shared */
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; vm_page_state::COUNT_]
+};
+
+/* Payload of a page in the FREE state */
+#[derive(Clone, Copy)]
+pub struct vm_page_free {
+    /* the page is known to be all zeroes */
+    pub is_zeroed: bool,
+}
+
+/* Payload of a page in the OBJECT state */
+#[derive(Clone, Copy)]
+pub struct vm_page_object {
+    /* back pointer to the vm object owning the page */
+    pub object: *mut u8,
+    /* offset of the page in the object */
+    pub page_offset: u64,
+    pub pin_count: u8,
+}
+
+/* Payload of a page in the MMU state */
+#[derive(Clone, Copy)]
+pub struct vm_page_mmu {
+    /* number of valid entries in this page table */
+    pub num_mappings: u32,
+}
+
+/* Payload of a page in the HEAP state */
+#[derive(Clone, Copy)]
+pub struct vm_page_heap {
+    /* first free object of a small size class page, 0 if none;
+     * the free objects are chained through their first word */
+    pub free_list: usize,
+    /* objects handed out from this page */
+    pub in_use: u32,
+}
+
+/* Payload of a page in the SLAB state */
+#[derive(Clone, Copy)]
+pub struct vm_page_slab {
+    /* first free object of the slab, 0 if none;
+     * the free objects are chained through their first word */
+    pub free_list: usize,
+    /* objects handed out from this slab */
+    pub in_use: u32,
+}
+
+/* The interpretation depends on the state of the page. */
+#[repr(C)]
+union vm_page_payload {
+    free: vm_page_free,
+    object: vm_page_object,
+    mmu: vm_page_mmu,
+    heap: vm_page_heap,
+    slab: vm_page_slab,
+    raw: [u64; 2],
+}
+
+/* |queue_node| must stay the first field, see |from_node()|. */
+#[repr(C)]
 pub struct vm_page {
     /* linked node */
     pub queue_node: ListNode,
@@ -34,6 +99,9 @@ pub struct vm_page {
 
     /* logically private, use loaned getters and setters below. */
     loaned_state_: AtomicU8,
+
+    /* logically private; use the per-state accessors below. */
+    payload_: vm_page_payload,
 }
 
 impl Linked for vm_page {
@@ -44,10 +112,6 @@ impl Linked for vm_page {
     fn into_node(&mut self) -> &mut ListNode {
         &mut (self.queue_node)
     }
-
-    fn delete_from_list(&mut self) {
-        self.into_node().delete_from_list();
-    }
 }
 
 impl vm_page {
@@ -55,23 +119,55 @@ impl vm_page {
         vm_page {
             queue_node: ListNode::new(),
             paddr_: 0,
-            state_: AtomicU8::new(vm_page_state::FREE),
+            state_: AtomicU8::new(vm_page_state::FREE as u8),
             loaned_state_: AtomicU8::new(0),
+            payload_: vm_page_payload { raw: [0; 2] },
         }
     }
 
-    pub fn init(&mut self, paddr: paddr_t) {
+    /* The page array lives in raw memory, so every field
+     * has to be set up here rather than just the address.
+     * Setting the initial |state| isn't a transition. */
+    pub fn init(&mut self, paddr: paddr_t, state: vm_page_state_t) {
+        *self = vm_page::new();
         self.paddr_ = paddr;
+        self.state_.store(state as u8, Ordering::Relaxed);
+    }
+
+    fn account_state_change(old_state: vm_page_state_t,
+                            new_state: vm_page_state_t) {
+        VM_PAGE_COUNTS[old_state.index()].fetch_sub(1, Ordering::Relaxed);
+        VM_PAGE_COUNTS[new_state.index()].fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn set_state(&mut self, new_state: vm_page_state_t) {
-        let old_state = self.state_.swap(new_state, Ordering::Relaxed);
+        let old_state = vm_page_state_t::from_raw(
+            self.state_.swap(new_state as u8, Ordering::Relaxed));
+
+        debug_assert!(vm_page_state::is_valid_transition(old_state,
+                                                         new_state),
+                      "page {:x}: illegal state transition {} -> {}",
+                      self.paddr_, old_state.name(), new_state.name());
+
+        self.payload_ = vm_page_payload { raw: [0; 2] };
+        Self::account_state_change(old_state, new_state);
+    }
+
+    /* Only for the free path of the PmmNode, which is the one way
+     * for a page to get back to FREE, from whatever state it is in. */
+    pub fn set_state_free(&mut self) {
+        let old_state = vm_page_state_t::from_raw(
+            self.state_.swap(vm_page_state::FREE as u8, Ordering::Relaxed));
+
+        debug_assert!(old_state != vm_page_state::FREE,
+                      "page {:x}: double free", self.paddr_);
+        debug_assert!(old_state != vm_page_state::MMU ||
+                      self.mmu().num_mappings == 0,
+                      "page {:x}: freeing page table still in use",
+                      self.paddr_);
 
-        /*
-            auto& p = percpu::GetCurrent();
-            p.vm_page_counts.by_state[VmPageStateIndex(old_state)] -= 1;
-            p.vm_page_counts.by_state[VmPageStateIndex(new_state)] += 1;
-        */
+        self.payload_ = vm_page_payload { raw: [0; 2] };
+        Self::account_state_change(old_state, vm_page_state::FREE);
     }
 
     pub fn paddr(&self) -> paddr_t {
@@ -79,17 +175,76 @@ impl vm_page {
     }
 
     pub fn state(&self) -> vm_page_state_t {
-        self.state_.load(Ordering::Relaxed)
+        vm_page_state_t::from_raw(self.state_.load(Ordering::Relaxed))
     }
 
     pub fn add_to_initial_count(state: vm_page_state_t, n: usize) {
-        /*
-        percpu::WithCurrentPreemptDisable(
-            [&state, &n](percpu* p) {
-                p->vm_page_counts.by_state[VmPageStateIndex(state)] +=
-                    n;
-        });
-        */
+        VM_PAGE_COUNTS[state.index()].fetch_add(n, Ordering::Relaxed);
+    }
+
+    /* Number of pages currently in |state|, all arenas together. */
+    pub fn count_by_state(state: vm_page_state_t) -> usize {
+        VM_PAGE_COUNTS[state.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn dump_state_counts() {
+        for i in 0..vm_page_state::COUNT_ {
+            let state = vm_page_state_t::from_raw(i as u8);
+            dprint!(CRITICAL, "  {:>8}: {} pages\n",
+                    state.name(), Self::count_by_state(state));
+        }
+    }
+
+    /* per-state payload accessors */
+
+    pub fn free(&self) -> &vm_page_free {
+        debug_assert!(self.state() == vm_page_state::FREE);
+        unsafe { &self.payload_.free }
+    }
+
+    pub fn free_mut(&mut self) -> &mut vm_page_free {
+        debug_assert!(self.state() == vm_page_state::FREE);
+        unsafe { &mut self.payload_.free }
+    }
+
+    pub fn object(&self) -> &vm_page_object {
+        debug_assert!(self.state() == vm_page_state::OBJECT);
+        unsafe { &self.payload_.object }
+    }
+
+    pub fn object_mut(&mut self) -> &mut vm_page_object {
+        debug_assert!(self.state() == vm_page_state::OBJECT);
+        unsafe { &mut self.payload_.object }
+    }
+
+    pub fn mmu(&self) -> &vm_page_mmu {
+        debug_assert!(self.state() == vm_page_state::MMU);
+        unsafe { &self.payload_.mmu }
+    }
+
+    pub fn mmu_mut(&mut self) -> &mut vm_page_mmu {
+        debug_assert!(self.state() == vm_page_state::MMU);
+        unsafe { &mut self.payload_.mmu }
+    }
+
+    pub fn heap(&self) -> &vm_page_heap {
+        debug_assert!(self.state() == vm_page_state::HEAP);
+        unsafe { &self.payload_.heap }
+    }
+
+    pub fn heap_mut(&mut self) -> &mut vm_page_heap {
+        debug_assert!(self.state() == vm_page_state::HEAP);
+        unsafe { &mut self.payload_.heap }
+    }
+
+    pub fn slab(&self) -> &vm_page_slab {
+        debug_assert!(self.state() == vm_page_state::SLAB);
+        unsafe { &self.payload_.slab }
+    }
+
+    pub fn slab_mut(&mut self) -> &mut vm_page_slab {
+        debug_assert!(self.state() == vm_page_state::SLAB);
+        unsafe { &mut self.payload_.slab }
     }
 
     /* helper routines */
@@ -115,6 +270,34 @@ impl vm_page {
         (self.loaned_state_.load(Ordering::Relaxed) & IsLoaned)
             == IsLoaned
     }
+
+    /* If true, the original contiguous VMO wants the page back.
+     * Such pages won't be re-used until the page is no longer loaned,
+     * either via commit of the page back into the contiguous VMO
+     * that loaned the page, or via deletion of the contiguous VMO
+     * that loaned the page. */
+    pub fn is_loan_cancelled(&self) -> bool {
+        (self.loaned_state_.load(Ordering::Relaxed) & IsLoanCancelled)
+            == IsLoanCancelled
+    }
+
+    /* Manipulation of 'loaned' is only allowed while
+     * the PmmNode lock is held. */
+    pub fn set_is_loaned(&self) {
+        self.loaned_state_.fetch_or(IsLoaned, Ordering::Relaxed);
+    }
+
+    pub fn clear_is_loaned(&self) {
+        self.loaned_state_.fetch_and(!IsLoaned, Ordering::Relaxed);
+    }
+
+    pub fn set_is_loan_cancelled(&self) {
+        self.loaned_state_.fetch_or(IsLoanCancelled, Ordering::Relaxed);
+    }
+
+    pub fn clear_is_loan_cancelled(&self) {
+        self.loaned_state_.fetch_and(!IsLoanCancelled, Ordering::Relaxed);
+    }
 }
 
 pub type vm_page_t = vm_page;
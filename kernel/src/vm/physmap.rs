@@ -12,13 +12,31 @@
  * the kernel
  */
 
-use crate::{KERNEL_ASPACE_BASE, ARCH_PHYSMAP_SIZE};
+use crate::KERNEL_BASE;
+use crate::arch::mmu::kernel_aspace_base;
+
+/* The physmap starts at the base of the kernel aspace,
+ * which is only known once the paging mode has been probed. */
+fn physmap_base() -> usize {
+    kernel_aspace_base()
+}
+
+/* How far the physmap may be grown past arch_physmap_size()
+ * for memory added at runtime. It keeps to the lower half of
+ * the space below the kernel, the peripherals sit right under
+ * the kernel image. */
+pub fn physmap_max_size() -> usize {
+    (KERNEL_BASE - physmap_base()) / 2
+}
 
-const PHYSMAP_BASE: usize = KERNEL_ASPACE_BASE;
-const _PHYSMAP_SIZE: usize = ARCH_PHYSMAP_SIZE;
 const PHYSMAP_BASE_PHYS: usize = 0;
 
 /* physical to virtual in the big kernel map */
 pub fn paddr_to_physmap(pa: usize) -> usize {
-    pa - PHYSMAP_BASE_PHYS + PHYSMAP_BASE
+    pa - PHYSMAP_BASE_PHYS + physmap_base()
+}
+
+/* virtual in the big kernel map to physical */
+pub fn physmap_to_paddr(va: usize) -> usize {
+    va - physmap_base() + PHYSMAP_BASE_PHYS
 }
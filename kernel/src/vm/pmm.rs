@@ -5,21 +5,86 @@
  */
 
 use crate::{
-    dprint, INFO, PmmNode, ErrNO, BootReserveRange, paddr_t,
+    dprint, ALWAYS, INFO, WARN, PmmNode, ErrNO, BootReserveRange, paddr_t,
+    vaddr_t, PAGE_SIZE, PAGE_SHIFT, ROUNDUP, ROUNDDOWN, IS_ALIGNED,
+    IS_PAGE_ALIGNED,
 };
 use alloc::vec::Vec;
 use alloc::string::String;
+use core::cmp::{max, min};
+use core::ptr::NonNull;
 use crate::lib::list::List;
 use crate::vm::page::vm_page_t;
+use crate::vm::vm_page_state;
+use crate::vm::pmm_arena::PmmArena;
+use crate::vm::physmap::physmap_to_paddr;
+use crate::arch::mmu::{
+    arch_physmap_map, arch_physmap_unmap, arch_physmap_size,
+};
+use crate::kernel::spinlock::SpinLock;
+use crate::vm::pmm_node::MemAvailStateUpdatedCallback;
+use crate::kernel::event::EventCallback;
+use crate::vm::pmm_checker::{PmmChecker, PmmCheckerAction};
+use crate::lib::cmdline::{cmdline_get, cmdline_get_bool};
+use crate::config_generated::*;
 
 /* all of the configured memory arenas */
-pub const MAX_ARENAS: usize = 16;
+pub const MAX_ARENAS: usize = _CONFIG_MAX_ARENAS;
+
+/* numa nodes are numbered from 0 to MAX_NUMA_NODES-1 */
+pub const MAX_NUMA_NODES: usize = 8;
+
+/* The distances of numa nodes that the platform doesn't give,
+ * as in the device tree "distance-map" */
+pub const NUMA_LOCAL_DISTANCE:  u8 = 10;
+pub const NUMA_REMOTE_DISTANCE: u8 = 20;
+
+/* Allocation flags */
+pub const PMM_ALLOC_FLAG_ANY: u32 = 0;
+/* The caller is able to deal with a loaned page; without this flag
+ * loaned pages are never handed out. */
+pub const PMM_ALLOC_FLAG_CAN_BORROW: u32 = 1 << 2;
+/* Only a loaned page will do. Implies PMM_ALLOC_FLAG_CAN_BORROW. */
+pub const PMM_ALLOC_FLAG_MUST_BORROW: u32 = 1 << 3;
+/* The caller can wait for free pages instead of eating into
+ * the last reserves; gets ErrNO::ShouldWait in the OOM state. */
+pub const PMM_ALLOC_FLAG_CAN_WAIT: u32 = 1 << 4;
+/* The pages come zeroed; they're taken from the zeroed pool
+ * when there are any left there, and zeroed on the spot if not. */
+pub const PMM_ALLOC_FLAG_ZERO: u32 = 1 << 5;
+
+/* The free page checker: whether it runs, how many bytes of each
+ * page it fills and checks, and "oops" or "panic" on corruption */
+const PMM_CHECKER_ENABLE_OPTION: &str = "kernel.pmm-checker.enable";
+const PMM_CHECKER_FILL_SIZE_OPTION: &str = "kernel.pmm-checker.fill-size";
+const PMM_CHECKER_ACTION_OPTION: &str = "kernel.pmm-checker.action";
+
+/* Free pages zeroed per hold of the PMM lock
+ * by pmm_zero_free_pages() */
+const PMM_ZERO_BATCH: usize = 16;
+
+/* memory availability states of the default watermarks */
+pub const MEM_AVAIL_STATE_OOM:      u8 = 0;
+pub const MEM_AVAIL_STATE_CRITICAL: u8 = 1;
+pub const MEM_AVAIL_STATE_WARNING:  u8 = 2;
+pub const MEM_AVAIL_STATE_NORMAL:   u8 = 3;
+
+/* A range of an arena without memory behind it */
+#[derive(Clone, Copy, Debug)]
+pub struct ArenaHole {
+    pub base: usize,
+    pub size: usize,
+}
 
 pub struct ArenaInfo {
     pub name: String,
     pub flags: u32,
     pub base: usize,
     pub size: usize,
+    /* the numa node this memory belongs to */
+    pub numa_id: u32,
+    /* parts of [base, base + size) that aren't memory */
+    pub holes: Vec<ArenaHole>,
 }
 
 impl ArenaInfo {
@@ -28,24 +93,655 @@ impl ArenaInfo {
 
         ArenaInfo {
             name: String::from(name),
-            flags, base, size
+            flags, base, size,
+            numa_id: 0,
+            holes: Vec::new(),
         }
     }
+
+    /* Holes cover whole pages */
+    pub fn add_hole(&mut self, base: usize, size: usize) {
+        let end = ROUNDUP!(base + size, PAGE_SIZE);
+        let base = ROUNDDOWN!(base, PAGE_SIZE);
+        self.holes.push(ArenaHole { base, size: end - base });
+    }
+}
+
+/* One pmm node per numa node, in ascending numa id.
+ * The heap grows from here, so nothing may allocate while
+ * holding this lock: the vector keeps room for MAX_NUMA_NODES. */
+static PMM_NODES: SpinLock<Vec<PmmNode>> = SpinLock::new(Vec::new());
+
+/* Serializes hot-adding arenas; never taken under the PMM lock. */
+static PMM_HOTPLUG_LOCK: SpinLock<()> = SpinLock::new(());
+
+/* Returns the node for |numa_id|, creating it on first use;
+ * |pmm_nodes| is kept in ascending order of numa id.
+ * Boot only, creating a node allocates. */
+fn pmm_node_get_or_create(numa_id: u32, pmm_nodes: &mut Vec<PmmNode>)
+    -> &mut PmmNode {
+
+    let pos = match pmm_node_position(numa_id, pmm_nodes) {
+        Ok(pos) => pos,
+        Err(pos) => {
+            dprint!(INFO, "PMM: adding node for numa id {}\n", numa_id);
+            if pmm_nodes.capacity() == 0 {
+                pmm_nodes.reserve_exact(MAX_NUMA_NODES);
+            }
+            pmm_nodes.insert(pos, PmmNode::new(numa_id));
+            pos
+        }
+    };
+
+    &mut pmm_nodes[pos]
+}
+
+fn pmm_node_position(numa_id: u32, pmm_nodes: &[PmmNode])
+    -> Result<usize, usize> {
+    pmm_nodes.binary_search_by_key(&numa_id, |n| n.numa_id())
 }
 
-pub fn pmm_add_arena(info: ArenaInfo, pmm_node: &mut PmmNode,
-                     reserve_ranges: &Vec<BootReserveRange>)
+fn pmm_check_numa_id(numa_id: u32) -> u32 {
+    if numa_id as usize >= MAX_NUMA_NODES {
+        dprint!(WARN, "PMM: bad numa id {}, using node 0\n", numa_id);
+        return 0;
+    }
+    numa_id
+}
+
+/* The node holding the physical address |pa|, if any. */
+fn pmm_node_for_paddr(pa: paddr_t, pmm_nodes: &mut [PmmNode])
+    -> Option<&mut PmmNode> {
+
+    pmm_nodes.iter_mut().find(|n| n.address_in_node(pa))
+}
+
+/* The distances between numa nodes, by numa id;
+ * 0 where the platform didn't give one */
+static NUMA_DISTANCE: SpinLock<[[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES]> =
+    SpinLock::new([[0; MAX_NUMA_NODES]; MAX_NUMA_NODES]);
+
+/* Record the distance between two numa nodes, both ways,
+ * as the platform describes it. */
+pub fn pmm_set_numa_distance(from: u32, to: u32, distance: u32)
     -> Result<(), ErrNO> {
 
-    dprint!(INFO, "Arena.{}: flags[{:x}] {:x} {:x}\n",
-            info.name, info.flags, info.base, info.size);
-    pmm_node.add_arena(info, reserve_ranges)
+    let (from, to) = (from as usize, to as usize);
+    if from >= MAX_NUMA_NODES || to >= MAX_NUMA_NODES ||
+       distance == 0 || distance > u8::MAX as u32 ||
+       (from == to && distance != NUMA_LOCAL_DISTANCE as u32) {
+        return Err(ErrNO::InvalidArgs);
+    }
+    let mut table = NUMA_DISTANCE.lock();
+    table[from][to] = distance as u8;
+    table[to][from] = distance as u8;
+    Ok(())
+}
+
+/* Indices of |pmm_nodes| in allocation order for |numa_id|:
+ * the preferred node first, then the others by increasing
+ * numa distance. Returns the indices and their count.
+ * Must not allocate, the heap grows through here. */
+fn pmm_node_fallback_order(numa_id: u32, pmm_nodes: &Vec<PmmNode>)
+    -> ([usize; MAX_NUMA_NODES], usize) {
+
+    let mut order = [0usize; MAX_NUMA_NODES];
+    let count = pmm_nodes.len();
+    for (i, o) in order.iter_mut().enumerate().take(count) {
+        *o = i;
+    }
+
+    let distances = NUMA_DISTANCE.lock()[numa_id as usize];
+    order[..count].sort_unstable_by_key(|&i| {
+        let id = pmm_nodes[i].numa_id();
+        let distance = match distances[id as usize] {
+            0 if id == numa_id => NUMA_LOCAL_DISTANCE,
+            0 => NUMA_REMOTE_DISTANCE,
+            d => d,
+        };
+        (id != numa_id, distance, id)
+    });
+    (order, count)
+}
+
+/* During early boot, before the heap is up: the allocations
+ * made here are served by the boot allocator. */
+pub fn pmm_add_arena(info: ArenaInfo,
+                     reserve_ranges: &[BootReserveRange])
+    -> Result<(), ErrNO> {
+
+    let mut pmm_nodes = PMM_NODES.lock();
+    dprint!(INFO, "Arena.{}: flags[{:x}] {:x} {:x} numa {}\n",
+            info.name, info.flags, info.base, info.size, info.numa_id);
+
+    let numa_id = pmm_check_numa_id(info.numa_id);
+    pmm_node_get_or_create(numa_id, &mut pmm_nodes)
+        .add_arena(info, reserve_ranges)
+}
+
+/* Adds the memory [info.base, info.base + info.size) once the
+ * system is up. Memory past the boot physmap gets mapped first.
+ * The page array is allocated from the free memory the PMM already
+ * has, or carved out of the top of the new arena when that fails. */
+pub fn pmm_hot_add_arena(info: ArenaInfo) -> Result<(), ErrNO> {
+    let _hotplug = PMM_HOTPLUG_LOCK.lock();
+
+    dprint!(INFO, "PMM: hot-add arena '{}' flags[{:x}] {:x} {:x} \
+            numa {}\n",
+            info.name, info.flags, info.base, info.size, info.numa_id);
+
+    if !IS_PAGE_ALIGNED!(info.base) ||
+       !IS_PAGE_ALIGNED!(info.size) ||
+       (info.size == 0) {
+        return Err(ErrNO::BadAlign);
+    }
+    let end = info.base.checked_add(info.size)
+        .ok_or_else(|| ErrNO::OutOfRange)?;
+
+    let numa_id = pmm_check_numa_id(info.numa_id);
+
+    /* Look at what is there under the lock; as arenas are only
+     * added under the hotplug lock this stays true until the end. */
+    let (has_node, has_room, arena_count) = {
+        let pmm_nodes = PMM_NODES.lock();
+        if pmm_nodes.iter().any(|n| n.overlaps(info.base, info.size)) {
+            dprint!(WARN, "PMM: [{:x}, {:x}) overlaps an arena\n",
+                    info.base, end);
+            return Err(ErrNO::BadRange);
+        }
+        match pmm_node_position(numa_id, &pmm_nodes) {
+            Ok(pos) => (true, pmm_nodes[pos].has_arena_room(),
+                        pmm_nodes[pos].arena_count()),
+            Err(_) => (false, true, 0),
+        }
+    };
+
+    let mut arena = PmmArena::new(info);
+    let array_size = arena.page_array_size();
+    if array_size >= arena.present_size() {
+        return Err(ErrNO::LackBuf);
+    }
+
+    /* the pages are reached through the physmap,
+     * which only covers arch_physmap_size() from boot */
+    let mut ret = Ok(());
+    let mut mapped_end = 0;
+    arena.for_each_present_run(|start, end| {
+        let start = max(start, arch_physmap_size());
+        if ret.is_ok() && start < end {
+            ret = arch_physmap_map(start, end - start);
+            if ret.is_ok() {
+                mapped_end = end;
+            }
+        }
+    });
+    if let Err(e) = ret {
+        hot_add_unmap(&arena, mapped_end);
+        return Err(e);
+    }
+
+    let mut array_pages = List::<vm_page_t>::new();
+    let array_pa = match pmm_alloc_contiguous(array_size / PAGE_SIZE,
+                                              PMM_ALLOC_FLAG_ANY,
+                                              PAGE_SHIFT as u8, numa_id,
+                                              &mut array_pages) {
+        Ok(pa) => {
+            for mut page in array_pages.iter() {
+                unsafe { page.as_mut().set_state(vm_page_state::WIRED); }
+            }
+            pa
+        },
+        Err(_) => {
+            match arena.find_page_array_spot(array_size, &[]) {
+                Ok(pa) => pa,
+                Err(e) => {
+                    hot_add_unmap(&arena, mapped_end);
+                    return Err(e);
+                },
+            }
+        },
+    };
+
+    let mut free_pages = List::<vm_page_t>::new();
+    if let Err(e) = arena.init_hot(array_pa, &mut free_pages) {
+        pmm_free(&mut array_pages);
+        hot_add_unmap(&arena, mapped_end);
+        return Err(e);
+    }
+
+    /* everything that allocates happens before taking the lock */
+    let new_node = if has_node { None } else { Some(PmmNode::new(numa_id)) };
+    let new_storage = if has_room {
+        None
+    } else {
+        Some(Vec::<PmmArena>::with_capacity(arena_count * 2))
+    };
+
+    let name = String::from(arena.name());
+    let size = arena.size();
+    let free_count = free_pages.len();
+    let old_storage = {
+        let mut pmm_nodes = PMM_NODES.lock();
+        /* nodes and arenas are only added under the hotplug lock,
+         * so what was looked at above still holds */
+        let pos = match (pmm_node_position(numa_id, &pmm_nodes), new_node) {
+            (Ok(pos), None) => pos,
+            (Err(pos), Some(node)) => {
+                debug_assert!(pmm_nodes.len() < pmm_nodes.capacity());
+                pmm_nodes.insert(pos, node);
+                pos
+            },
+            _ => unreachable!("PMM: node {} changed while hot-adding",
+                              numa_id),
+        };
+        let node = &mut pmm_nodes[pos];
+        let old_storage =
+            new_storage.map(|arenas| node.swap_arena_storage(arenas));
+        node.add_hot_arena(arena, &mut free_pages);
+        old_storage
+    };
+    drop(old_storage);
+
+    dprint!(INFO, "PMM: added arena '{}' [{:x}, {:x}) to node {}, \
+            {} free pages, page array at {:x}\n",
+            name, end - size, end, numa_id, free_count, array_pa);
+    Ok(())
+}
+
+/* Take the runs of |arena| below |mapped_end| back out of the
+ * physmap, when hot-adding it fails after they were mapped. */
+fn hot_add_unmap(arena: &PmmArena, mapped_end: paddr_t) {
+    arena.for_each_present_run(|start, end| {
+        let start = max(start, arch_physmap_size());
+        let end = min(end, mapped_end);
+        if start < end {
+            if let Err(e) = arch_physmap_unmap(start, end - start) {
+                dprint!(WARN, "PMM: [{:x}, {:x}) stays in the physmap: \
+                        {:?}\n", start, end, e);
+            }
+        }
+    });
+}
+
+pub fn pmm_paddr_to_page(pa: paddr_t) -> Option<NonNull<vm_page_t>> {
+    let pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter().find_map(|n| n.paddr_to_page(pa))
+}
+
+/* The page behind the physmap address |va| */
+pub fn pmm_physmap_to_page(va: vaddr_t) -> Option<NonNull<vm_page_t>> {
+    let va = ROUNDDOWN!(va, PAGE_SIZE);
+    pmm_paddr_to_page(physmap_to_paddr(va))
+}
+
+/* Allocate a page, trying the node |numa_id| first and
+ * then falling back to the others. */
+pub fn pmm_alloc_page(alloc_flags: u32, numa_id: u32)
+    -> Result<NonNull<vm_page_t>, ErrNO> {
+
+    let mut pmm_nodes = PMM_NODES.lock();
+    let mut ret = Err(ErrNO::NoMem);
+    let (order, count) = pmm_node_fallback_order(numa_id, &pmm_nodes);
+    for &i in &order[..count] {
+        ret = pmm_nodes[i].alloc_page(alloc_flags);
+        if ret.is_ok() {
+            break;
+        }
+    }
+    ret
+}
+
+/* All |count| pages come from a single node,
+ * in the same order as pmm_alloc_page(). */
+pub fn pmm_alloc_pages(count: usize, alloc_flags: u32, numa_id: u32,
+                       list: &mut List<vm_page_t>)
+    -> Result<(), ErrNO> {
+
+    let mut pmm_nodes = PMM_NODES.lock();
+    let mut ret = Err(ErrNO::NoMem);
+    let (order, node_count) = pmm_node_fallback_order(numa_id, &pmm_nodes);
+    for &i in &order[..node_count] {
+        ret = pmm_nodes[i].alloc_pages(count, alloc_flags, list);
+        if ret.is_ok() {
+            break;
+        }
+    }
+    ret
+}
+
+/* Allocate |count| physically contiguous pages, aligned to
+ * 1 << |alignment_log2| bytes (at least a page), in the same
+ * node order as pmm_alloc_page(). Returns the base address. */
+pub fn pmm_alloc_contiguous(count: usize, alloc_flags: u32,
+                            alignment_log2: u8, numa_id: u32,
+                            list: &mut List<vm_page_t>)
+    -> Result<paddr_t, ErrNO> {
+
+    let mut pmm_nodes = PMM_NODES.lock();
+    let mut ret = Err(ErrNO::NotFound);
+    let (order, node_count) = pmm_node_fallback_order(numa_id, &pmm_nodes);
+    for &i in &order[..node_count] {
+        ret = pmm_nodes[i].alloc_contiguous(count, alloc_flags,
+                                            alignment_log2, list);
+        if ret.is_ok() {
+            break;
+        }
+    }
+    ret
 }
 
 pub fn pmm_alloc_range(paddr: paddr_t, count: usize,
-                       pmm_node: &mut PmmNode,
                        list: &mut List<vm_page_t>)
     -> Result<(), ErrNO> {
 
-    pmm_node.alloc_range(paddr, count, list)
+    let mut pmm_nodes = PMM_NODES.lock();
+    pmm_node_for_paddr(paddr, &mut pmm_nodes)
+        .ok_or_else(|| ErrNO::NotFound)?
+        .alloc_range(paddr, count, list)
+}
+
+pub fn pmm_free_page(page: NonNull<vm_page_t>) {
+    let mut pmm_nodes = PMM_NODES.lock();
+    let pa = unsafe { page.as_ref().paddr() };
+    if let Some(node) = pmm_node_for_paddr(pa, &mut pmm_nodes) {
+        node.free_page(page);
+    }
+}
+
+/* Take the pages owned by |node| out of |list|. */
+fn pmm_take_node_pages(list: &mut List<vm_page_t>, node: &PmmNode)
+    -> List<vm_page_t> {
+
+    let mut node_list = List::<vm_page_t>::new();
+    for page in list.iter() {
+        let pa = unsafe { page.as_ref().paddr() };
+        if node.address_in_node(pa) {
+            list.delete(page);
+            node_list.add_tail(page);
+        }
+    }
+    node_list
+}
+
+/* Pages that no node owns are left on |list|; they shouldn't
+ * have come from the PMM. */
+fn pmm_warn_foreign_pages(list: &List<vm_page_t>, what: &str) {
+    if let Some(page) = list.iter().next() {
+        dprint!(WARN, "PMM: {}: {} pages of no node, first at {:x}\n",
+                what, list.len(), unsafe { page.as_ref().paddr() });
+    }
+}
+
+/* Each page goes back to the node that owns it. */
+pub fn pmm_free(list: &mut List<vm_page_t>) {
+    let mut pmm_nodes = PMM_NODES.lock();
+    for node in pmm_nodes.iter_mut() {
+        let mut node_list = pmm_take_node_pages(list, node);
+        node.free_list(&mut node_list);
+    }
+    pmm_warn_foreign_pages(list, "free");
+}
+
+/* Pages of a contiguous VMO that is decommitted are loaned to
+ * the rest of the system; the owner gets them back with
+ * pmm_cancel_loan() followed by pmm_end_loan(). */
+pub fn pmm_begin_loan(list: &mut List<vm_page_t>) {
+    let mut pmm_nodes = PMM_NODES.lock();
+    for node in pmm_nodes.iter_mut() {
+        let mut node_list = pmm_take_node_pages(list, node);
+        node.begin_loan(&mut node_list);
+    }
+    pmm_warn_foreign_pages(list, "begin_loan");
+}
+
+pub fn pmm_cancel_loan(paddr: paddr_t, count: usize) {
+    let mut pmm_nodes = PMM_NODES.lock();
+    if let Some(node) = pmm_node_for_paddr(paddr, &mut pmm_nodes) {
+        node.cancel_loan(paddr, count)
+    }
+}
+
+pub fn pmm_end_loan(paddr: paddr_t, count: usize,
+                    list: &mut List<vm_page_t>) -> Result<(), ErrNO> {
+    let mut pmm_nodes = PMM_NODES.lock();
+    match pmm_node_for_paddr(paddr, &mut pmm_nodes) {
+        Some(node) => node.end_loan(paddr, count, list),
+        None => Err(ErrNO::NotFound),
+    }
+}
+
+/* The contiguous VMO owning these pages is gone,
+ * so they stop being loaned. */
+pub fn pmm_delete_lender(paddr: paddr_t, count: usize) {
+    let mut pmm_nodes = PMM_NODES.lock();
+    if let Some(node) = pmm_node_for_paddr(paddr, &mut pmm_nodes) {
+        node.delete_lender(paddr, count)
+    }
+}
+
+pub fn pmm_count_free_pages() -> u64 {
+    let pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter().map(|n| n.count_free_pages()).sum()
+}
+
+pub fn pmm_count_loaned_free_pages() -> u64 {
+    let pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter().map(|n| n.count_loaned_free_pages()).sum()
+}
+
+/* Loaned pages, free or not, all nodes together */
+pub fn pmm_count_loaned_pages() -> u64 {
+    let pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter().map(|n| n.count_loaned_pages()).sum()
+}
+
+/* Loaned pages whose lender wants them back */
+pub fn pmm_count_loan_cancelled_pages() -> u64 {
+    let pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter().map(|n| n.count_loan_cancelled_pages()).sum()
+}
+
+/* Free pages in the zeroed pool, all nodes together */
+pub fn pmm_count_zeroed_free_pages() -> u64 {
+    let pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter().map(|n| n.count_zeroed_free_pages()).sum()
+}
+
+/* Free pages of unknown content, all nodes together */
+pub fn pmm_count_dirty_free_pages() -> u64 {
+    let pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter().map(|n| n.count_dirty_free_pages()).sum()
+}
+
+/* For the idle loop: zeroes up to |max_pages| free pages, topping
+ * up the zeroed pool of each node to CONFIG_PMM_ZERO_POOL_SIZE.
+ * The PMM lock is dropped every PMM_ZERO_BATCH pages.
+ * Returns the number of pages zeroed. */
+pub fn pmm_zero_free_pages(max_pages: usize) -> usize {
+    let target = (_CONFIG_PMM_ZERO_POOL_SIZE / PAGE_SIZE) as u64;
+    let node_count = PMM_NODES.lock().len();
+
+    let mut zeroed = 0;
+    for i in 0..node_count {
+        loop {
+            let batch = min(PMM_ZERO_BATCH, max_pages - zeroed);
+            if batch == 0 {
+                return zeroed;
+            }
+
+            let count = PMM_NODES.lock().get_mut(i)
+                .map_or(0, |n| n.zero_free_pages(batch, target));
+            zeroed += count;
+            if count < batch {
+                break;
+            }
+        }
+    }
+    zeroed
+}
+
+pub fn pmm_count_total_bytes() -> u64 {
+    let pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter().map(|n| n.count_total_bytes()).sum()
+}
+
+/* Print the per-node page counts */
+pub fn pmm_dump_node_stats() {
+    let pmm_nodes = PMM_NODES.lock();
+    for node in pmm_nodes.iter() {
+        node.dump_stats();
+    }
+}
+
+/* Print every arena with a map of its pages by state */
+pub fn pmm_dump_map() {
+    let pmm_nodes = PMM_NODES.lock();
+    for node in pmm_nodes.iter() {
+        node.dump_map();
+    }
+}
+
+fn pmm_usage(name: &str) {
+    dprint!(ALWAYS, "usage:\n");
+    dprint!(ALWAYS, "{} arenas : dump the arenas and their page map\n",
+            name);
+    dprint!(ALWAYS, "{} stats  : dump the per node counts\n", name);
+    dprint!(ALWAYS, "{} free   : free pages, zeroed and loaned\n", name);
+    dprint!(ALWAYS, "{} zero N : zero up to N free pages\n", name);
+}
+
+/* The "pmm" debug console command */
+pub fn cmd_pmm(argv: &[&str]) -> Result<(), ErrNO> {
+    if argv.len() < 2 {
+        pmm_usage(argv[0]);
+        return Err(ErrNO::InvalidArgs);
+    }
+
+    match argv[1] {
+        "arenas" => pmm_dump_map(),
+        "stats" => pmm_dump_node_stats(),
+        "free" => {
+            dprint!(ALWAYS, "free pages: {} ({} zeroed, {} dirty), \
+                    loaned {} free of {} ({} cancelled)\n",
+                    pmm_count_free_pages(), pmm_count_zeroed_free_pages(),
+                    pmm_count_dirty_free_pages(),
+                    pmm_count_loaned_free_pages(), pmm_count_loaned_pages(),
+                    pmm_count_loan_cancelled_pages());
+        },
+        "zero" => {
+            let count = argv.get(2).and_then(|s| s.parse::<usize>().ok())
+                .ok_or_else(|| ErrNO::InvalidArgs)?;
+            dprint!(ALWAYS, "zeroed {} pages\n", pmm_zero_free_pages(count));
+        },
+        _ => {
+            dprint!(ALWAYS, "unknown command\n");
+            pmm_usage(argv[0]);
+            return Err(ErrNO::InvalidArgs);
+        },
+    }
+    Ok(())
+}
+
+/* Poison free pages and check them on allocation, by default in
+ * debug builds only. Must be called once the boot reserved ranges
+ * are wired. */
+pub fn pmm_checker_init() {
+    if !cmdline_get_bool(PMM_CHECKER_ENABLE_OPTION,
+                         cfg!(debug_assertions)) {
+        return;
+    }
+
+    let fill_size = match cmdline_get(PMM_CHECKER_FILL_SIZE_OPTION) {
+        None => PAGE_SIZE,
+        Some(s) => match s.parse::<usize>() {
+            Ok(size) if PmmChecker::is_valid_fill_size(size) => size,
+            _ => {
+                dprint!(WARN, "PMM: bad checker fill size '{}'\n", s);
+                PAGE_SIZE
+            }
+        },
+    };
+    let action = match cmdline_get(PMM_CHECKER_ACTION_OPTION) {
+        None | Some("oops") => PmmCheckerAction::Oops,
+        Some("panic") => PmmCheckerAction::Panic,
+        Some(s) => {
+            dprint!(WARN, "PMM: bad checker action '{}'\n", s);
+            PmmCheckerAction::Oops
+        }
+    };
+
+    let mut pmm_nodes = PMM_NODES.lock();
+    for node in pmm_nodes.iter_mut() {
+        node.fill_free_pages_and_arm(fill_size, action);
+    }
+}
+
+pub fn pmm_checker_is_armed() -> bool {
+    let pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter().any(|n| n.checker_is_armed())
+}
+
+/* Sets up the memory availability watermarks (in bytes, ascending)
+ * of the node |numa_id|.
+ * |callback| runs each time the free memory crosses one of them. */
+pub fn pmm_init_reclamation(watermarks: &[u64], debounce: u64,
+                            callback: MemAvailStateUpdatedCallback,
+                            numa_id: u32)
+    -> Result<(), ErrNO> {
+
+    let mut pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter_mut().find(|n| n.numa_id() == numa_id)
+        .ok_or_else(|| ErrNO::NotFound)?
+        .init_reclamation(watermarks, debounce, callback)
+}
+
+fn mem_avail_state_name(state: u8) -> &'static str {
+    match state {
+        MEM_AVAIL_STATE_OOM => "oom",
+        MEM_AVAIL_STATE_CRITICAL => "critical",
+        MEM_AVAIL_STATE_WARNING => "warning",
+        MEM_AVAIL_STATE_NORMAL => "normal",
+        _ => "unknown",
+    }
+}
+
+fn default_mem_avail_state_updated(state: u8) {
+    dprint!(INFO, "PMM: free memory is now at level '{}'\n",
+            mem_avail_state_name(state));
+}
+
+/* Install the OOM/critical/warning watermarks from config.ini
+ * on every node. */
+pub fn pmm_init_default_reclamation() -> Result<(), ErrNO> {
+    let mut pmm_nodes = PMM_NODES.lock();
+    let watermarks = [
+        _CONFIG_PMM_OOM_WATERMARK as u64,
+        _CONFIG_PMM_CRITICAL_WATERMARK as u64,
+        _CONFIG_PMM_WARNING_WATERMARK as u64,
+    ];
+
+    for node in pmm_nodes.iter_mut() {
+        node.init_reclamation(&watermarks,
+                              _CONFIG_PMM_WATERMARK_DEBOUNCE as u64,
+                              default_mem_avail_state_updated)?;
+    }
+    Ok(())
+}
+
+/* The lowest memory availability state among all nodes. */
+pub fn pmm_get_mem_avail_state() -> u8 {
+    let pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter().map(|n| n.mem_avail_state()).min()
+        .unwrap_or(MEM_AVAIL_STATE_NORMAL)
+}
+
+/* Run |waiter| once free memory of the node |numa_id|
+ * is above the OOM watermark. It runs with the PMM lock held,
+ * so it must neither allocate nor call into the PMM. */
+pub fn pmm_wait_for_free_pages(waiter: EventCallback, numa_id: u32)
+    -> Result<(), ErrNO> {
+    let mut pmm_nodes = PMM_NODES.lock();
+    pmm_nodes.iter_mut().find(|n| n.numa_id() == numa_id)
+        .ok_or_else(|| ErrNO::NotFound)?
+        .wait_for_free_pages(waiter)
 }
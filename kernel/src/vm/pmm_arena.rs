@@ -5,23 +5,44 @@
  */
 
 use core::mem;
-use alloc::vec::Vec;
+use core::cmp::{max, min};
 use crate::{
-    ArenaInfo, PmmNode, ErrNO, dprint, CRITICAL, INFO,
-    PAGE_SIZE, ROUNDUP_PAGE_SIZE, ROUNDUP, PAGE_ALIGN, ALIGN,
-    BootReserveRange, paddr_t,
+    ArenaInfo, PmmNode, ErrNO, dprint, ALWAYS, CRITICAL, INFO,
+    PAGE_SIZE, PAGE_SHIFT, ROUNDUP_PAGE_SIZE, ROUNDUP, PAGE_ALIGN, ALIGN,
+    BootReserveRange, paddr_t, vaddr_t,
 };
+use crate::vm::pmm::ArenaHole;
 use crate::vm::page::{vm_page_t, vm_page};
 use crate::vm::vm_page_state;
 use crate::vm::vm_page_state::vm_page_state_t;
-use crate::vm::physmap::paddr_to_physmap;
-use crate::vm::bootreserve::boot_reserve_range_search;
+use crate::vm::physmap::{paddr_to_physmap, physmap_to_paddr};
+use crate::vm::bootreserve::{
+    boot_reserve_range_search, BootAllocPolicy,
+};
 use crate::lib::list::List;
 use core::ptr::NonNull;
 
+/* The page array is made of sections of 1 << SECTION_SHIFT bytes
+ * of the arena; only the sections with memory behind them have
+ * page structs, so holes in the arena cost next to nothing. */
+const SECTION_SHIFT: usize = 27;
+const SECTION_PAGES: usize = 1 << (SECTION_SHIFT - PAGE_SHIFT);
+
+/* How far memory ranges may be spread over a single arena:
+ * the span whose section table fits in a page. */
+pub const MAX_SPARSE_ARENA_SIZE: usize =
+    (PAGE_SIZE / mem::size_of::<vaddr_t>()) << SECTION_SHIFT;
+
+/*
+ * Layout of the page array:
+ * | section table | page structs of the present sections |
+ * The table holds the address of the page structs of each
+ * section, or 0 for a section that is all hole.
+ */
 struct PageArray {
-    start:      paddr_t,
+    start:      vaddr_t,
     len:        usize,
+    section_count: usize,
     obj_size:   usize,
 }
 
@@ -30,17 +51,44 @@ impl PageArray {
         Self {
             start:  0,
             len:    0,
+            section_count: 0,
             obj_size: mem::size_of::<vm_page_t>(),
         }
     }
 
-    fn init(&mut self, start: paddr_t, len: usize) {
+    fn table_size(section_count: usize) -> usize {
+        ROUNDUP!(section_count * mem::size_of::<vaddr_t>(),
+                 mem::align_of::<vm_page_t>())
+    }
+
+    fn init(&mut self, start: vaddr_t, len: usize, section_count: usize) {
         self.start = start;
         self.len = len;
+        self.section_count = section_count;
+    }
+
+    fn section(&self, section: usize) -> vaddr_t {
+        let table = self.start as *const vaddr_t;
+        unsafe { *table.add(section) }
+    }
+
+    fn set_section(&mut self, section: usize, va: vaddr_t) {
+        let table = self.start as *mut vaddr_t;
+        unsafe { *table.add(section) = va; }
     }
 
     fn get_page(&self, index: usize) -> Option<NonNull<vm_page_t>> {
-        let ptr = index * self.obj_size + self.start;
+        let section = index / SECTION_PAGES;
+        if section >= self.section_count {
+            return None;
+        }
+
+        let base = self.section(section);
+        if base == 0 {
+            return None;
+        }
+
+        let ptr = base + (index % SECTION_PAGES) * self.obj_size;
         if ptr >= (self.start + self.len) {
             return None;
         }
@@ -48,26 +96,18 @@ impl PageArray {
         NonNull::<vm_page_t>::new(ptr as *mut vm_page_t)
     }
 
-    fn init_page(&self, index: usize, paddr: paddr_t)
+    fn init_page(&self, index: usize, paddr: paddr_t,
+                 state: vm_page_state_t)
         -> Result<(), ErrNO> {
 
         let mut page = self.get_page(index)
             .ok_or_else(|| ErrNO::NoMem)?;
 
         unsafe {
-            page.as_mut().init(paddr);
+            page.as_mut().init(paddr, state);
         }
         Ok(())
     }
-
-    fn set_page_state(&self, index: usize, state: vm_page_state_t)
-        -> Result<(), ErrNO> {
-        let mut page = self.get_page(index)
-            .ok_or_else(|| ErrNO::NoMem)?;
-
-        unsafe { page.as_mut().set_state(state); }
-        Ok(())
-    }
 }
 
 pub struct PmmArena {
@@ -76,7 +116,13 @@ pub struct PmmArena {
 }
 
 impl PmmArena {
-    pub fn new(info: ArenaInfo) -> PmmArena {
+    pub fn new(mut info: ArenaInfo) -> PmmArena {
+        /* keep the holes sorted and inside of the arena */
+        let end = info.base + info.size;
+        info.holes.retain(|h| h.size != 0 &&
+                          h.base < end && info.base < h.base + h.size);
+        info.holes.sort_unstable_by_key(|h| h.base);
+
         PmmArena {
             info,
             page_array_: PageArray::new(),
@@ -104,17 +150,111 @@ impl PmmArena {
         self.info.flags
     }
 
+    /* Calls |f| on each run [start, end) of the arena
+     * that isn't a hole, in ascending order. */
+    pub fn for_each_present_run<F>(&self, mut f: F)
+    where F: FnMut(paddr_t, paddr_t)
+    {
+        let end = self.base() + self.size();
+        let mut start = self.base();
+        for h in &self.info.holes {
+            if h.base > start {
+                f(start, h.base);
+            }
+            start = max(start, h.base + h.size);
+        }
+        if start < end {
+            f(start, end);
+        }
+    }
+
+    /* The bytes of memory behind the arena, holes excluded. */
+    pub fn present_size(&self) -> usize {
+        let mut size = 0;
+        self.for_each_present_run(|start, end| size += end - start);
+        size
+    }
+
+    fn is_hole(&self, pa: paddr_t) -> bool {
+        self.first_hole_in(pa, 1).is_some()
+    }
+
+    /* The lowest hole sharing a byte with [pa, pa + len) */
+    fn first_hole_in(&self, pa: paddr_t, len: usize) -> Option<&ArenaHole> {
+        self.info.holes.iter()
+            .find(|h| pa < h.base + h.size && h.base < pa + len)
+    }
+
+    fn section_count(&self) -> usize {
+        ROUNDUP!(self.size(), 1 << SECTION_SHIFT) >> SECTION_SHIFT
+    }
+
+    /* The number of pages of the arena that |section| spans */
+    fn section_page_count(&self, section: usize) -> usize {
+        let page_count = self.size() / PAGE_SIZE;
+        min(SECTION_PAGES, page_count - section * SECTION_PAGES)
+    }
+
+    fn section_present(&self, section: usize) -> bool {
+        let start = self.base() + (section << SECTION_SHIFT);
+        let len = self.section_page_count(section) * PAGE_SIZE;
+
+        let mut present = false;
+        self.for_each_present_run(|s, e| {
+            present |= s < start + len && start < e;
+        });
+        present
+    }
+
+    /* The bytes of page array needed to back this arena. */
+    pub fn page_array_size(&self) -> usize {
+        let section_count = self.section_count();
+        let mut size = PageArray::table_size(section_count);
+        for section in 0..section_count {
+            if self.section_present(section) {
+                size += self.section_page_count(section) *
+                    mem::size_of::<vm_page_t>();
+            }
+        }
+        ROUNDUP_PAGE_SIZE!(size)
+    }
+
+    /* Top down search for |len| bytes of the arena to hold its page
+     * array, staying clear of |reserve_ranges| and of the holes. */
+    pub fn find_page_array_spot(&self, len: usize,
+                                reserve_ranges: &[BootReserveRange])
+        -> Result<paddr_t, ErrNO> {
+
+        let mut top = self.base() + self.size();
+        loop {
+            let mut range = BootReserveRange::default();
+            boot_reserve_range_search(self.base(), top - self.base(),
+                                      len, PAGE_SIZE,
+                                      BootAllocPolicy::TopDown,
+                                      reserve_ranges,
+                                      &mut range)?;
+
+            if range.pa < self.base() || range.len > len {
+                return Err(ErrNO::OutOfRange);
+            }
+
+            /* anything higher is taken, go below the hole */
+            match self.first_hole_in(range.pa, range.len) {
+                Some(hole) => top = hole.base,
+                None => return Ok(range.pa),
+            }
+        }
+    }
+
     pub fn init(&mut self, pmm_node: &mut PmmNode,
-                reserve_ranges: &Vec<BootReserveRange>)
+                reserve_ranges: &[BootReserveRange])
         -> Result<(), ErrNO> {
 
         /* allocate an array of pages to back this one */
-        let page_count = self.info.size / PAGE_SIZE;
-        let vm_page_sz = mem::size_of::<vm_page_t>();
-        let page_array_size = ROUNDUP_PAGE_SIZE!(page_count*vm_page_sz);
+        let page_array_size = self.page_array_size();
 
         /* if the arena is too small to be useful, bail */
-        if page_array_size >= self.info.size {
+        if page_array_size >= self.present_size() {
             dprint!(CRITICAL,
                     "PMM: arena too small to hold page array ({:x})\n",
                     self.info.size);
@@ -123,79 +263,216 @@ impl PmmArena {
 
         /* allocate a chunk to back the page array out of
          * the arena itself, near the top of memory */
-        let mut range = BootReserveRange::default();
-        boot_reserve_range_search(self.info.base, self.info.size,
-                                  page_array_size,
-                                  reserve_ranges,
-                                  &mut range)?;
+        let array_pa = self.find_page_array_spot(page_array_size,
+                                                 reserve_ranges)?;
+
+        dprint!(INFO, "arena for base {:x} size {:x}\n",
+                array_pa, page_array_size);
 
-        if range.pa < self.info.base || range.len > page_array_size {
-            return Err(ErrNO::OutOfRange);
+        let mut list = List::new();
+        self.init_page_array(array_pa, page_array_size, &mut list)?;
+
+        pmm_node.add_free_pages(&mut list);
+        dprint!(INFO, "init page_array ok!\n");
+        Ok(())
+    }
+
+    /* For an arena added at runtime: the page array is at |array_pa|,
+     * either inside the arena or in pages the caller has wired.
+     * The free pages are returned in |list| rather than handed
+     * to the node, which the caller does under the PMM lock. */
+    pub fn init_hot(&mut self, array_pa: paddr_t,
+                    list: &mut List<vm_page_t>)
+        -> Result<(), ErrNO> {
+
+        let page_array_size = self.page_array_size();
+        if page_array_size >= self.present_size() {
+            return Err(ErrNO::LackBuf);
         }
 
-        dprint!(INFO, "arena for base {:x} size {:x}\n",
-                range.pa, range.len);
+        self.init_page_array(array_pa, page_array_size, list)
+    }
 
-        let page_array_va = paddr_to_physmap(range.pa);
-        self.page_array_.init(page_array_va, page_array_size);
+    fn init_page_array(&mut self, array_pa: paddr_t,
+                       page_array_size: usize,
+                       list: &mut List<vm_page_t>)
+        -> Result<(), ErrNO> {
 
-        /* |page_count| pages in the state FREE */
-        vm_page::add_to_initial_count(vm_page_state::FREE, page_count);
+        let page_count = self.info.size / PAGE_SIZE;
 
-        /* compute the range of the array that backs the array itself */
-        let array_start_index =
-            (PAGE_ALIGN!(range.pa) - self.info.base) / PAGE_SIZE;
-        let array_end_index =
-            array_start_index + page_array_size / PAGE_SIZE;
+        /* compute the range of the array that backs the array itself,
+         * empty when the array lives outside of the arena */
+        let (array_start_index, array_end_index) =
+            if self.address_in_arena(array_pa) {
+                let start = (PAGE_ALIGN!(array_pa) - self.info.base) /
+                    PAGE_SIZE;
+                (start, start + page_array_size / PAGE_SIZE)
+            } else {
+                (0, 0)
+            };
 
         dprint!(INFO, "array_start_index {}, array_end_index {}\n",
                 array_start_index, array_end_index);
 
-        if array_start_index >= page_count ||
-           array_end_index > page_count {
+        if array_end_index > page_count {
             return Err(ErrNO::BadRange);
         }
 
+        /* lay out the section table and the present sections */
+        let section_count = self.section_count();
+        let array_va = paddr_to_physmap(array_pa);
+        self.page_array_.init(array_va, page_array_size, section_count);
+
+        let mut offset = PageArray::table_size(section_count);
+        for section in 0..section_count {
+            if self.section_present(section) {
+                self.page_array_.set_section(section, array_va + offset);
+                offset += self.section_page_count(section) *
+                    mem::size_of::<vm_page_t>();
+            } else {
+                self.page_array_.set_section(section, 0);
+            }
+        }
+
+        /* the pages backing the array are WIRED, the rest FREE */
+        let array_page_count = array_end_index - array_start_index;
+        let present_count = self.present_size() / PAGE_SIZE;
+        vm_page::add_to_initial_count(vm_page_state::FREE,
+                                      present_count - array_page_count);
+        vm_page::add_to_initial_count(vm_page_state::WIRED,
+                                      array_page_count);
+
         dprint!(INFO, "init page_array ...\n");
 
         /* add all pages that aren't part of the page array
-         * to the free list pages */
-        let mut list = List::new();
+         * to the free list pages; the holes get no page at all */
+        let mut ret = Ok(());
+        let base = self.info.base;
+        let page_array = &self.page_array_;
+        self.for_each_present_run(|start, end| {
+            let mut i = (start - base) / PAGE_SIZE;
+            while ret.is_ok() && i < (end - base) / PAGE_SIZE {
+                let paddr = base + i * PAGE_SIZE;
+
+                ret = if i >= array_start_index && i < array_end_index {
+                    page_array.init_page(i, paddr, vm_page_state::WIRED)
+                } else {
+                    page_array.init_page(i, paddr, vm_page_state::FREE)
+                        .and_then(|_| page_array.get_page(i)
+                                  .ok_or_else(|| ErrNO::NoMem))
+                        .map(|page| list.add_tail(page))
+                };
+                i += 1;
+            }
+        });
 
-        let mut i = 0;
-        while i < page_count {
-            let paddr = self.info.base + i * PAGE_SIZE;
-            self.page_array_.init_page(i, paddr)?;
+        ret
+    }
 
-            if i >= array_start_index && i < array_end_index {
-                self.page_array_
-                    .set_page_state(i, vm_page_state::WIRED)?;
-            } else {
-                let page = self.page_array_.get_page(i)
-                    .ok_or_else(|| ErrNO::NoMem)?;
+    pub fn page_array_pa(&self) -> paddr_t {
+        physmap_to_paddr(self.page_array_.start)
+    }
 
-                list.add_tail(page);
+    /* Print the arena and a map of its pages: each run of pages
+     * in the same state on a line, with the holes in between. */
+    pub fn dump_map(&self) {
+        dprint!(ALWAYS, "arena '{}': [{:x}, {:x}) {} present bytes, \
+                flags {:x}, page array [{:x}, {:x})\n",
+                self.name(), self.base(), self.base() + self.size(),
+                self.present_size(), self.info.flags,
+                self.page_array_pa(),
+                self.page_array_pa() + self.page_array_.len);
+
+        let mut prev_end = self.base();
+        self.for_each_present_run(|start, end| {
+            if start > prev_end {
+                dprint!(ALWAYS, "  [{:x}, {:x}) {:>8}\n",
+                        prev_end, start, "hole");
+            }
+            prev_end = end;
+
+            /* the first page of the current run and its state */
+            let mut run_start = start;
+            let mut run_state = None;
+            let mut pa = start;
+            while pa <= end {
+                let state = if pa < end {
+                    self.find_specific(pa)
+                        .map(|p| unsafe { p.as_ref().state() })
+                } else {
+                    None
+                };
+
+                if pa == end || state != run_state {
+                    if let Some(s) = run_state {
+                        dprint!(ALWAYS, "  [{:x}, {:x}) {:>8} {} pages\n",
+                                run_start, pa, s.name(),
+                                (pa - run_start) / PAGE_SIZE);
+                    }
+                    run_start = pa;
+                    run_state = state;
+                }
+                pa += PAGE_SIZE;
             }
-            i += 1;
+        });
+
+        let end = self.base() + self.size();
+        if end > prev_end {
+            dprint!(ALWAYS, "  [{:x}, {:x}) {:>8}\n", prev_end, end, "hole");
         }
+    }
 
-        pmm_node.add_free_pages(&mut list);
-        dprint!(INFO, "init page_array ok!\n");
-        Ok(())
+    /* Whether [base, base + size) shares any page with the arena */
+    pub fn overlaps(&self, base: paddr_t, size: usize) -> bool {
+        base < self.base() + self.size() && self.base() < base + size
     }
 
     pub fn address_in_arena(&self, pa: paddr_t) -> bool {
         pa >= self.base() && pa <= self.base() + self.size() - 1
     }
 
+    /* The page at |pa|, None if it is outside of the arena
+     * or in one of its holes. */
     pub fn find_specific(&self, pa: paddr_t)
         -> Option<NonNull<vm_page_t>> {
 
-        if !self.address_in_arena(pa) {
+        if !self.address_in_arena(pa) || self.is_hole(pa) {
             return None;
         }
 
         let index = (pa - self.base()) / PAGE_SIZE;
         self.page_array_.get_page(index)
     }
+
+    /* First fit search for |count| free, non loaned pages starting
+     * on a 1 << |alignment_log2| boundary. Returns the base address. */
+    pub fn find_free_contiguous(&self, count: usize, alignment_log2: u8)
+        -> Option<paddr_t> {
+
+        let alignment_log2 = max(alignment_log2 as usize, PAGE_SHIFT);
+        let align = 1usize << alignment_log2;
+        let end = self.base() + self.size();
+
+        let mut start = ALIGN!(self.base(), align);
+        'search: while start >= self.base() && start < end &&
+                       count <= (end - start) / PAGE_SIZE {
+            /* skip whole holes rather than page by page */
+            if let Some(hole) = self.first_hole_in(start, count * PAGE_SIZE) {
+                start = ALIGN!(hole.base + hole.size, align);
+                continue 'search;
+            }
+
+            for i in 0..count {
+                let page = self.find_specific(start + i * PAGE_SIZE)?;
+                let page = unsafe { page.as_ref() };
+                if !page.is_free() || page.is_loaned() {
+                    /* restart past the page in the way */
+                    start = ALIGN!(start + (i + 1) * PAGE_SIZE, align);
+                    continue 'search;
+                }
+            }
+            return Some(start);
+        }
+        None
+    }
 }
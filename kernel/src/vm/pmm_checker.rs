@@ -0,0 +1,106 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * PmmChecker is used to detect memory corruption of free pages.
+ * When armed, every page freed to the PmmNode is filled with
+ * a pattern, which is verified when the page is allocated again.
+ * A mismatch means somebody wrote to a page it no longer owns.
+ */
+
+use core::ptr;
+use crate::{PAGE_SIZE, IS_ALIGNED};
+use crate::vm::page::vm_page_t;
+use crate::vm::physmap::paddr_to_physmap;
+
+const PATTERN: u64 = 0x4242_4242_4242_4242;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PmmCheckerAction {
+    /* Report the corruption and keep going. */
+    Oops,
+    /* Report the corruption and stop the kernel. */
+    Panic,
+}
+
+pub struct PmmChecker {
+    armed: bool,
+    fill_size: usize,
+    action: PmmCheckerAction,
+}
+
+impl PmmChecker {
+    pub fn new() -> Self {
+        Self {
+            armed: false,
+            fill_size: PAGE_SIZE,
+            action: PmmCheckerAction::Oops,
+        }
+    }
+
+    /* Only the first |fill_size| bytes of each page are filled
+     * and checked, to limit the cost. */
+    pub fn is_valid_fill_size(fill_size: usize) -> bool {
+        (8..=PAGE_SIZE).contains(&fill_size) && IS_ALIGNED!(fill_size, 8)
+    }
+
+    /* May only be called while disarmed. */
+    pub fn set_fill_size(&mut self, fill_size: usize) {
+        debug_assert!(!self.armed);
+        debug_assert!(Self::is_valid_fill_size(fill_size));
+        self.fill_size = fill_size;
+    }
+
+    pub fn set_action(&mut self, action: PmmCheckerAction) {
+        self.action = action;
+    }
+
+    pub fn action(&self) -> PmmCheckerAction {
+        self.action
+    }
+
+    pub fn fill_size(&self) -> usize {
+        self.fill_size
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    /* Every free page must already be filled when arming. */
+    pub fn arm(&mut self) {
+        self.armed = true;
+    }
+
+    pub fn disarm(&mut self) {
+        self.armed = false;
+    }
+
+    fn page_words(&self, page: &vm_page_t) -> *mut u64 {
+        paddr_to_physmap(page.paddr()) as *mut u64
+    }
+
+    pub fn fill_pattern(&self, page: &vm_page_t) {
+        debug_assert!(page.is_free());
+
+        let base = self.page_words(page);
+        for i in 0..(self.fill_size / 8) {
+            unsafe { ptr::write_volatile(base.add(i), PATTERN); }
+        }
+    }
+
+    /* Returns the byte offset of the first word that doesn't match
+     * the pattern, or None if the page is intact. */
+    pub fn validate_pattern(&self, page: &vm_page_t) -> Option<usize> {
+        let base = self.page_words(page);
+        for i in 0..(self.fill_size / 8) {
+            if unsafe { ptr::read_volatile(base.add(i)) } != PATTERN {
+                return Some(i * 8);
+            }
+        }
+        None
+    }
+}
@@ -9,38 +9,621 @@ use core::sync::atomic::{AtomicU64, Ordering};
 use super::pmm_arena::PmmArena;
 use crate::MAX_ARENAS;
 use crate::{
-    ArenaInfo, dprint, INFO, CRITICAL, BootReserveRange, paddr_t,
-    PAGE_SIZE, IS_ALIGNED, IS_PAGE_ALIGNED, ErrNO, ROUNDDOWN,
+    ArenaInfo, dprint, ALWAYS, INFO, WARN, CRITICAL, BootReserveRange, paddr_t,
+    PAGE_SIZE, PAGE_SHIFT, IS_ALIGNED, IS_PAGE_ALIGNED, ErrNO,
+    ROUNDDOWN, ROUNDUP,
 };
-use crate::lib::list::{List, Linked};
+use crate::lib::list::List;
 use crate::vm::page::vm_page_t;
+use crate::vm::vm_page_state;
+use crate::vm::pmm::{
+    PMM_ALLOC_FLAG_CAN_BORROW, PMM_ALLOC_FLAG_MUST_BORROW,
+    PMM_ALLOC_FLAG_CAN_WAIT, PMM_ALLOC_FLAG_ZERO,
+};
+use crate::vm::pmm_checker::{PmmChecker, PmmCheckerAction};
+use core::ptr::{NonNull, write_bytes};
+use crate::kernel::event::{Event, EventCallback};
+use crate::vm::physmap::paddr_to_physmap;
+
+/* The max number of memory availability watermarks */
+pub const MAX_WATERMARK_COUNT: usize = 8;
+
+/* Called with the new memory availability state index
+ * every time the free memory crosses a watermark. */
+pub type MemAvailStateUpdatedCallback = fn(u8);
 
 /* per numa node collection of pmm arenas and worker threads */
 pub struct PmmNode {
+    numa_id: u32,
+
     arenas: Vec<PmmArena>,
 
     arena_cumulative_size: usize,
 
-    /* Free pages where !loaned. */
+    /* Free pages where !loaned, split into those of unknown
+     * content and the zeroed pool; |free_count| counts both. */
     free_count  : AtomicU64,
     free_list   : List<vm_page_t>,
+    free_zeroed_count   : AtomicU64,
+    free_zeroed_list    : List<vm_page_t>,
+
+    /* Free pages where loaned && !loan_cancelled. */
+    free_loaned_count   : AtomicU64,
+    free_loaned_list    : List<vm_page_t>,
+
+    /* Pages that are loaned, and the subset whose loan is cancelled.
+     * Cancelled pages which get freed are kept off both free lists
+     * until the loan ends. */
+    loaned_count        : u64,
+    loan_cancelled_count: u64,
+
+    /* Watermarks are in pages, ascending; state index |i| means
+     * free memory is below |mem_avail_state_watermarks[i]|. */
+    mem_avail_state_watermarks: [u64; MAX_WATERMARK_COUNT],
+    mem_avail_state_watermark_count: u8,
+    mem_avail_state_cur_index: u8,
+    mem_avail_state_debounce: u64,
+    mem_avail_state_upper_bound: u64,
+    mem_avail_state_lower_bound: u64,
+    mem_avail_state_callback: Option<MemAvailStateUpdatedCallback>,
+
+    /* Signaled while free memory is above the lowest watermark. */
+    free_pages_evt: Event,
+
+    /* Poisons free pages to catch use-after-free. */
+    checker: PmmChecker,
 }
 
+/* The pages on the lists are only touched with the PMM lock held. */
+unsafe impl Send for PmmNode {}
+
 impl PmmNode {
-    pub fn new() -> PmmNode {
-        PmmNode {
+    pub fn new(numa_id: u32) -> PmmNode {
+        let mut node = PmmNode {
+            numa_id,
+
             arenas: Vec::<PmmArena>::with_capacity(MAX_ARENAS),
 
             arena_cumulative_size: 0,
 
             free_count  : AtomicU64::new(0),
             free_list   : List::new(),
+            free_zeroed_count   : AtomicU64::new(0),
+            free_zeroed_list    : List::new(),
+
+            free_loaned_count   : AtomicU64::new(0),
+            free_loaned_list    : List::new(),
+
+            loaned_count        : 0,
+            loan_cancelled_count: 0,
+
+            mem_avail_state_watermarks: [0; MAX_WATERMARK_COUNT],
+            mem_avail_state_watermark_count: 0,
+            mem_avail_state_cur_index: 0,
+            mem_avail_state_debounce: 0,
+            mem_avail_state_upper_bound: u64::MAX,
+            mem_avail_state_lower_bound: 0,
+            mem_avail_state_callback: None,
+
+            free_pages_evt: Event::new(),
+
+            checker: PmmChecker::new(),
+        };
+
+        /* Without any watermarks there is a single state,
+         * in which allocations are never held off. */
+        node.set_mem_avail_state_locked(0);
+        node
+    }
+
+    pub fn numa_id(&self) -> u32 {
+        self.numa_id
+    }
+
+    pub fn address_in_node(&self, pa: paddr_t) -> bool {
+        self.arenas.iter().any(|a| a.address_in_arena(pa))
+    }
+
+    pub fn count_total_bytes(&self) -> u64 {
+        self.arena_cumulative_size as u64
+    }
+
+    pub fn dump_stats(&self) {
+        dprint!(INFO, "node {}: {} arenas, {} bytes total, \
+                {} free pages ({} zeroed), {} free loaned pages, \
+                {} loaned pages ({} cancelled), memory state {}\n",
+                self.numa_id, self.arenas.len(),
+                self.count_total_bytes(),
+                self.count_free_pages(), self.count_zeroed_free_pages(),
+                self.count_loaned_free_pages(),
+                self.count_loaned_pages(),
+                self.count_loan_cancelled_pages(),
+                self.mem_avail_state_cur_index);
+    }
+
+    /* Print the map of each arena */
+    pub fn dump_map(&self) {
+        dprint!(ALWAYS, "node {}: {} arenas\n",
+                self.numa_id, self.arenas.len());
+        for a in &self.arenas {
+            a.dump_map();
+        }
+    }
+
+    /* Sets up the memory availability states.
+     * |watermarks| are in bytes and must be strictly ascending;
+     * |debounce| (in bytes) is how far free memory must move past
+     * a watermark before the state changes again.
+     * |callback| runs on every state transition. */
+    pub fn init_reclamation(&mut self, watermarks: &[u64],
+                            debounce: u64,
+                            callback: MemAvailStateUpdatedCallback)
+        -> Result<(), ErrNO> {
+
+        if watermarks.len() > MAX_WATERMARK_COUNT {
+            return Err(ErrNO::InvalidArgs);
+        }
+
+        let page_size = PAGE_SIZE as u64;
+        let debounce = ROUNDUP!(debounce, page_size) / page_size;
+
+        let mut tmp = [0u64; MAX_WATERMARK_COUNT];
+        for (i, w) in watermarks.iter().enumerate() {
+            tmp[i] = w / page_size;
+            if i > 0 {
+                if tmp[i] <= tmp[i - 1] {
+                    return Err(ErrNO::InvalidArgs);
+                }
+            } else if tmp[i] < debounce {
+                return Err(ErrNO::InvalidArgs);
+            }
+        }
+
+        self.mem_avail_state_watermarks = tmp;
+        self.mem_avail_state_watermark_count = watermarks.len() as u8;
+        self.mem_avail_state_debounce = debounce;
+        self.mem_avail_state_callback = Some(callback);
+
+        self.update_mem_avail_state_locked();
+        Ok(())
+    }
+
+    pub fn mem_avail_state(&self) -> u8 {
+        self.mem_avail_state_cur_index
+    }
+
+    /* Registers a one-shot |waiter| which runs once free memory
+     * is above the lowest watermark. It runs with the PMM lock
+     * held, so it must neither allocate nor call into the PMM. */
+    pub fn wait_for_free_pages(&mut self, waiter: EventCallback)
+        -> Result<(), ErrNO> {
+        self.free_pages_evt.wait(waiter)
+    }
+
+    fn update_mem_avail_state_locked(&mut self) {
+        /* Find the smallest watermark which is greater than
+         * the number of free pages. */
+        let free_count = self.free_count.load(Ordering::Relaxed);
+        let count = self.mem_avail_state_watermark_count;
+        let mut target = count;
+        for i in 0..count {
+            if self.mem_avail_state_watermarks[i as usize] > free_count {
+                target = i;
+                break;
+            }
+        }
+        self.set_mem_avail_state_locked(target);
+    }
+
+    fn set_mem_avail_state_locked(&mut self, mem_avail_state: u8) {
+        let prev_index = self.mem_avail_state_cur_index;
+        self.mem_avail_state_cur_index = mem_avail_state;
+
+        let index = mem_avail_state as usize;
+        if index == 0 {
+            self.mem_avail_state_lower_bound = 0;
+        } else {
+            self.mem_avail_state_lower_bound =
+                self.mem_avail_state_watermarks[index - 1]
+                    .saturating_sub(self.mem_avail_state_debounce);
+        }
+
+        if mem_avail_state == self.mem_avail_state_watermark_count {
+            self.mem_avail_state_upper_bound = u64::MAX;
+        } else {
+            self.mem_avail_state_upper_bound =
+                self.mem_avail_state_watermarks[index] +
+                self.mem_avail_state_debounce;
+        }
+
+        /* Allocations should be held off only in the lowest state. */
+        if mem_avail_state == 0 && self.mem_avail_state_watermark_count > 0 {
+            self.free_pages_evt.unsignal();
+        } else {
+            self.free_pages_evt.signal();
+        }
+
+        if prev_index != mem_avail_state {
+            if let Some(callback) = self.mem_avail_state_callback {
+                callback(mem_avail_state);
+            }
+        }
+    }
+
+    fn increment_free_count_locked(&mut self, amount: u64) {
+        let free_count =
+            self.free_count.fetch_add(amount, Ordering::Relaxed) + amount;
+
+        if self.mem_avail_state_upper_bound < free_count {
+            self.update_mem_avail_state_locked();
+        }
+    }
+
+    fn decrement_free_count_locked(&mut self, amount: u64) {
+        let free_count =
+            self.free_count.fetch_sub(amount, Ordering::Relaxed) - amount;
+
+        if self.mem_avail_state_lower_bound > free_count {
+            self.update_mem_avail_state_locked();
+        }
+    }
+
+    fn increment_free_loaned_count_locked(&mut self, amount: u64) {
+        self.free_loaned_count.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    fn decrement_free_loaned_count_locked(&mut self, amount: u64) {
+        self.free_loaned_count.fetch_sub(amount, Ordering::Relaxed);
+    }
+
+    /* Allocations that can wait are held off in the lowest state,
+     * leaving the last reserves to those that cannot. */
+    fn should_delay_allocation_locked(&self) -> bool {
+        self.mem_avail_state_watermark_count > 0 &&
+            self.mem_avail_state_cur_index == 0
+    }
+
+    pub fn count_free_pages(&self) -> u64 {
+        self.free_count.load(Ordering::Relaxed)
+    }
+
+    pub fn count_loaned_free_pages(&self) -> u64 {
+        self.free_loaned_count.load(Ordering::Relaxed)
+    }
+
+    pub fn count_loaned_pages(&self) -> u64 {
+        self.loaned_count
+    }
+
+    pub fn count_loan_cancelled_pages(&self) -> u64 {
+        self.loan_cancelled_count
+    }
+
+    /* The free, non loaned pages known to be zeroed */
+    pub fn count_zeroed_free_pages(&self) -> u64 {
+        self.free_zeroed_count.load(Ordering::Relaxed)
+    }
+
+    /* The free, non loaned pages of unknown content */
+    pub fn count_dirty_free_pages(&self) -> u64 {
+        self.count_free_pages() - self.count_zeroed_free_pages()
+    }
+
+    pub fn paddr_to_page(&self, pa: paddr_t)
+        -> Option<NonNull<vm_page_t>> {
+
+        for a in &(self.arenas) {
+            if a.address_in_arena(pa) {
+                return a.find_specific(pa);
+            }
+        }
+        None
+    }
+
+    fn arena_for_paddr(&self, pa: paddr_t) -> Option<&PmmArena> {
+        self.arenas.iter().find(|a| a.address_in_arena(pa))
+    }
+
+    fn alloc_page_helper_locked(&self, page: &mut vm_page_t,
+                                alloc_flags: u32) {
+        debug_assert!(page.is_free());
+
+        let is_zeroed = page.free().is_zeroed;
+        if self.checker.is_armed() {
+            self.check_free_page(page);
+        }
+
+        page.set_state(vm_page_state::ALLOC);
+
+        if (alloc_flags & PMM_ALLOC_FLAG_ZERO) != 0 && !is_zeroed {
+            Self::zero_page(page);
+        }
+    }
+
+    fn zero_page(page: &vm_page_t) {
+        let va = paddr_to_physmap(page.paddr());
+        unsafe { write_bytes(va as *mut u8, 0, PAGE_SIZE); }
+    }
+
+    /* The head of the non loaned free pages, out of the zeroed pool
+     * first if |zeroed| and out of it last otherwise, so that those
+     * who don't need zeroed pages leave the pool alone. */
+    fn remove_free_head_locked(&mut self, zeroed: bool)
+        -> Option<NonNull<vm_page_t>> {
+
+        let page = if zeroed {
+            self.free_zeroed_list.remove_head()
+                .or_else(|| self.free_list.remove_head())
+        } else {
+            self.free_list.remove_head()
+                .or_else(|| self.free_zeroed_list.remove_head())
+        }?;
+
+        if unsafe { page.as_ref().free().is_zeroed } {
+            self.free_zeroed_count.fetch_sub(1, Ordering::Relaxed);
+        }
+        Some(page)
+    }
+
+    /* Takes the non loaned free |page| off whichever list it is on. */
+    fn remove_free_page_locked(&mut self, page: NonNull<vm_page_t>) {
+        if unsafe { page.as_ref().free().is_zeroed } {
+            self.free_zeroed_list.delete(page);
+            self.free_zeroed_count.fetch_sub(1, Ordering::Relaxed);
+        } else {
+            self.free_list.delete(page);
+        }
+    }
+
+    /* Zeroes up to |max| free pages of unknown content through the
+     * physmap and moves them to the zeroed pool, until the pool holds
+     * |target| pages. Returns the number of pages zeroed.
+     * Never while the checker is armed, as free pages carry its
+     * pattern then. */
+    pub fn zero_free_pages(&mut self, max: usize, target: u64) -> usize {
+        if self.checker.is_armed() {
+            return 0;
+        }
+
+        let mut zeroed = 0;
+        while zeroed < max && self.count_zeroed_free_pages() < target {
+            /* leave the recently freed pages at the head,
+             * they're the ones likely to be in the cache */
+            let mut page = match self.free_list.remove_tail() {
+                Some(page) => page,
+                None => break,
+            };
+
+            unsafe {
+                Self::zero_page(page.as_ref());
+                page.as_mut().free_mut().is_zeroed = true;
+            }
+            self.free_zeroed_list.add_tail(page);
+            self.free_zeroed_count.fetch_add(1, Ordering::Relaxed);
+            zeroed += 1;
+        }
+        zeroed
+    }
+
+    fn free_page_helper_locked(&self, page: &mut vm_page_t) {
+        page.set_state_free();
+
+        if self.checker.is_armed() {
+            self.checker.fill_pattern(page);
+        }
+    }
+
+    fn check_free_page(&self, page: &vm_page_t) {
+        let offset = match self.checker.validate_pattern(page) {
+            Some(offset) => offset,
+            None => return,
+        };
+
+        let arena = match self.arena_for_paddr(page.paddr()) {
+            Some(a) => a.name(),
+            None => "unknown",
+        };
+
+        dprint!(CRITICAL, "PMM: free page {:x} of arena '{}' \
+                was modified at offset {:x} while free\n",
+                page.paddr(), arena, offset);
+
+        if self.checker.action() == PmmCheckerAction::Panic {
+            panic!("PMM: corrupted free page {:x}", page.paddr());
+        }
+    }
+
+    /* Fill every free page with the pattern, then start checking
+     * the first |fill_size| bytes of each page with |action|.
+     * That includes the free pages whose loan is cancelled, which
+     * are on no list but checked once the loan ends. */
+    pub fn fill_free_pages_and_arm(&mut self, fill_size: usize,
+                                   action: PmmCheckerAction) {
+        self.checker.set_fill_size(fill_size);
+        self.checker.set_action(action);
+
+        /* the zeroed pool gets the pattern as well */
+        for mut page in self.free_zeroed_list.iter() {
+            unsafe { page.as_mut().free_mut().is_zeroed = false; }
         }
+        self.free_list.append(&mut self.free_zeroed_list);
+        self.free_zeroed_count.store(0, Ordering::Relaxed);
+
+        for a in &self.arenas {
+            a.for_each_present_run(|start, end| {
+                for pa in (start..end).step_by(PAGE_SIZE) {
+                    let page = match a.find_specific(pa) {
+                        Some(page) => unsafe { page.as_ref() },
+                        None => continue,
+                    };
+                    if page.is_free() {
+                        self.checker.fill_pattern(page);
+                    }
+                }
+            });
+        }
+
+        self.checker.arm();
+        dprint!(INFO, "PMM: node {} checker armed, fill size {}\n",
+                self.numa_id, self.checker.fill_size());
+    }
+
+    pub fn disarm_checker(&mut self) {
+        self.checker.disarm();
+    }
+
+    pub fn checker_is_armed(&self) -> bool {
+        self.checker.is_armed()
+    }
+
+    pub fn alloc_page(&mut self, alloc_flags: u32)
+        -> Result<NonNull<vm_page_t>, ErrNO> {
+
+        let must_borrow = (alloc_flags & PMM_ALLOC_FLAG_MUST_BORROW) != 0;
+        let can_borrow =
+            must_borrow || (alloc_flags & PMM_ALLOC_FLAG_CAN_BORROW) != 0;
+        let use_loaned_list = can_borrow &&
+            (!self.free_loaned_list.is_empty() || must_borrow);
+
+        /* Even when borrowing, we still want to preserve
+         * the loaned pages in the OOM state. */
+        if (alloc_flags & PMM_ALLOC_FLAG_CAN_WAIT) != 0 &&
+            self.should_delay_allocation_locked() {
+            return Err(ErrNO::ShouldWait);
+        }
+
+        let zeroed = (alloc_flags & PMM_ALLOC_FLAG_ZERO) != 0;
+        let mut page = if use_loaned_list {
+            self.free_loaned_list.remove_head()
+        } else {
+            self.remove_free_head_locked(zeroed)
+        }.ok_or_else(|| ErrNO::NoMem)?;
+
+        unsafe {
+            debug_assert!(page.as_ref().is_loaned() == use_loaned_list);
+            self.alloc_page_helper_locked(page.as_mut(), alloc_flags);
+        }
+
+        if use_loaned_list {
+            self.decrement_free_loaned_count_locked(1);
+        } else {
+            self.decrement_free_count_locked(1);
+        }
+
+        Ok(page)
+    }
+
+    pub fn alloc_pages(&mut self, count: usize, alloc_flags: u32,
+                       list: &mut List<vm_page_t>)
+        -> Result<(), ErrNO> {
+
+        if count == 0 {
+            return Ok(());
+        }
+
+        let must_borrow = (alloc_flags & PMM_ALLOC_FLAG_MUST_BORROW) != 0;
+        let can_borrow =
+            must_borrow || (alloc_flags & PMM_ALLOC_FLAG_CAN_BORROW) != 0;
+
+        let free_count = self.count_free_pages() as usize;
+        let free_loaned_count = if can_borrow {
+            self.count_loaned_free_pages() as usize
+        } else {
+            0
+        };
+        let available = if must_borrow {
+            free_loaned_count
+        } else {
+            free_count + free_loaned_count
+        };
+        if count > available {
+            return Err(ErrNO::NoMem);
+        }
+
+        if (alloc_flags & PMM_ALLOC_FLAG_CAN_WAIT) != 0 &&
+            self.should_delay_allocation_locked() {
+            return Err(ErrNO::ShouldWait);
+        }
+
+        /* Prefer the loaned pages, when allowed to use them,
+         * to keep the non-loaned ones for everybody else. */
+        let zeroed = (alloc_flags & PMM_ALLOC_FLAG_ZERO) != 0;
+        let mut allocated: u64 = 0;
+        let mut allocated_loaned: u64 = 0;
+        while (allocated as usize) < count {
+            let loaned = can_borrow && !self.free_loaned_list.is_empty();
+            let mut page = if loaned {
+                self.free_loaned_list.remove_head()
+            } else {
+                self.remove_free_head_locked(zeroed)
+            }.ok_or_else(|| ErrNO::NoMem)?;
+
+            unsafe {
+                self.alloc_page_helper_locked(page.as_mut(), alloc_flags);
+            }
+            list.add_tail(page);
+
+            allocated += 1;
+            if loaned {
+                allocated_loaned += 1;
+            }
+        }
+
+        self.decrement_free_loaned_count_locked(allocated_loaned);
+        self.decrement_free_count_locked(allocated - allocated_loaned);
+        Ok(())
+    }
+
+    pub fn alloc_contiguous(&mut self, count: usize, alloc_flags: u32,
+                            alignment_log2: u8,
+                            list: &mut List<vm_page_t>)
+        -> Result<paddr_t, ErrNO> {
+
+        if count == 0 {
+            return Err(ErrNO::InvalidArgs);
+        }
+
+        /* if we're called with a single page, just fall through
+         * to the regular allocation routine */
+        if count == 1 && (alignment_log2 as usize) <= PAGE_SHIFT {
+            let page = self.alloc_page(alloc_flags)?;
+            list.add_tail(page);
+            return Ok(unsafe { page.as_ref().paddr() });
+        }
+
+        if (alloc_flags & PMM_ALLOC_FLAG_CAN_WAIT) != 0 &&
+            self.should_delay_allocation_locked() {
+            return Err(ErrNO::ShouldWait);
+        }
+
+        let pa = self.arenas.iter()
+            .find_map(|a| a.find_free_contiguous(count, alignment_log2))
+            .ok_or_else(|| ErrNO::NotFound)?;
+
+        /* remove the pages from the run out of the free list */
+        for i in 0..count {
+            let mut page = self.paddr_to_page(pa + i * PAGE_SIZE)
+                .ok_or_else(|| ErrNO::NotFound)?;
+
+            unsafe {
+                /* Loaned pages are never returned by
+                 * find_free_contiguous() above. */
+                debug_assert!(page.as_ref().is_free() &&
+                              !page.as_ref().is_loaned());
+
+                self.remove_free_page_locked(page);
+                self.alloc_page_helper_locked(page.as_mut(), alloc_flags);
+            }
+            list.add_tail(page);
+        }
+
+        self.decrement_free_count_locked(count as u64);
+        Ok(pa)
     }
 
     /* during early boot before threading exists. */
     pub fn add_arena(&mut self, info: ArenaInfo,
-                     reserve_ranges: &Vec<BootReserveRange>)
+                     reserve_ranges: &[BootReserveRange])
         -> Result<(), ErrNO> {
         dprint!(INFO, "PMM: adding arena '{}' base {:x} size {:x}\n",
                 info.name, info.base, info.size);
@@ -60,30 +643,73 @@ impl PmmNode {
 
         dprint!(INFO, "Adding arena '{}' ...\n", arena.name());
 
-        self.arena_cumulative_size += arena.size();
+        self.insert_arena(arena);
+        Ok(())
+    }
+
+    fn insert_arena(&mut self, arena: PmmArena) {
+        self.arena_cumulative_size += arena.present_size();
 
         /* insert arena in ascending order of its base address */
-        let mut pos = 0;
-        for a in &(self.arenas) {
-            if arena.base() < a.base() {
-                return Ok(self.arenas.insert(pos, arena));
+        let pos = self.arenas.iter()
+            .position(|a| arena.base() < a.base())
+            .unwrap_or(self.arenas.len());
+        self.arenas.insert(pos, arena);
+    }
+
+    pub fn overlaps(&self, base: paddr_t, size: usize) -> bool {
+        self.arenas.iter().any(|a| a.overlaps(base, size))
+    }
+
+    pub fn arena_count(&self) -> usize {
+        self.arenas.len()
+    }
+
+    /* Whether another arena fits without growing the arena vector,
+     * which must not happen with the PMM lock held. */
+    pub fn has_arena_room(&self) -> bool {
+        self.arenas.len() < self.arenas.capacity()
+    }
+
+    /* Moves the arenas over to the (empty, larger) |arenas| and
+     * returns the old vector, to be dropped after the PMM lock is. */
+    pub fn swap_arena_storage(&mut self, mut arenas: Vec<PmmArena>)
+        -> Vec<PmmArena> {
+        debug_assert!(arenas.is_empty() &&
+                      arenas.capacity() > self.arenas.len());
+        arenas.append(&mut self.arenas);
+        core::mem::replace(&mut self.arenas, arenas)
+    }
+
+    /* Adds an |arena| set up with PmmArena::init_hot() at runtime,
+     * along with its free pages in |list|. There has to be room
+     * for it, see has_arena_room(), as nothing may be allocated
+     * under the PMM lock. */
+    pub fn add_hot_arena(&mut self, arena: PmmArena,
+                         list: &mut List<vm_page_t>) {
+
+        debug_assert!(self.has_arena_room());
+
+        if self.checker.is_armed() {
+            for page in list.iter() {
+                unsafe { self.checker.fill_pattern(page.as_ref()); }
             }
-            pos += 1;
         }
 
-        Ok(self.arenas.push(arena))
+        self.insert_arena(arena);
+        self.add_free_pages(list);
     }
 
     pub fn add_free_pages(&mut self, list: &mut List<vm_page_t>) {
-        self.free_count.fetch_add(list.len() as u64, Ordering::Relaxed);
+        let count = list.len() as u64;
         self.free_list.append(list);
-        // free_pages_evt_.Signal();
+        self.increment_free_count_locked(count);
 
         dprint!(INFO, "free count now {}\n",
                 self.free_count.load(Ordering::Relaxed));
     }
 
-    pub fn alloc_range(&self, paddr: paddr_t, count: usize,
+    pub fn alloc_range(&mut self, paddr: paddr_t, count: usize,
                        list: &mut List<vm_page_t>)
         -> Result<(), ErrNO> {
         dprint!(INFO, "address {:x}, count {}\n", paddr, count);
@@ -95,49 +721,211 @@ impl PmmNode {
         let mut paddr = ROUNDDOWN!(paddr, PAGE_SIZE);
 
         let mut allocated = 0;
+        let mut range_list = List::<vm_page_t>::new();
 
         //AutoPreemptDisabler preempt_disable;
         //Guard<Mutex> guard{&lock_};
 
         /* walk through the arenas,
          * looking to see if the physical page belongs to it */
-        'next: for a in &(self.arenas) {
-            while allocated < count {
-                if !a.address_in_arena(paddr) {
-                    continue 'next;
-                }
+        for i in 0..self.arenas.len() {
+            while allocated < count &&
+                  self.arenas[i].address_in_arena(paddr) {
+                let mut page = match self.arenas[i].find_specific(paddr) {
+                    Some(page) => page,
+                    None => break,
+                };
 
-                if let Some(mut page) = a.find_specific(paddr) {
-                    /* And we never allocate loaned pages
-                     * for caller of AllocRange() */
-                    unsafe {
-                        if page.as_ref().is_free() &&
-                           !page.as_ref().is_loaned() {
-                            page.as_mut().delete_from_list();
-                            //AllocPageHelperLocked(page);
-                            list.add_tail(page);
-                            allocated += 1;
-                            //DecrementFreeCountLocked(1);
-                        }
+                /* And we never allocate loaned pages
+                 * for caller of AllocRange() */
+                unsafe {
+                    if !page.as_ref().is_free() ||
+                       page.as_ref().is_loaned() {
+                        break;
                     }
+
+                    self.remove_free_page_locked(page);
+                    self.alloc_page_helper_locked(page.as_mut(), 0);
                 }
+                range_list.add_tail(page);
 
+                allocated += 1;
                 paddr += PAGE_SIZE;
             }
 
-            if allocated >= count {
+            if allocated == count {
                 break;
             }
         }
 
+        self.decrement_free_count_locked(allocated as u64);
+
         if allocated != count {
             /* we were not able to allocate the entire run,
              * free these pages */
-            //FreeListLocked(list);
+            self.free_list_locked(&mut range_list);
             return Err(ErrNO::NotFound);
         }
 
+        list.append(&mut range_list);
+
         dprint!(INFO, "########## alloc range ok!\n");
         Ok(())
     }
+
+    pub fn free_page(&mut self, page: NonNull<vm_page_t>) {
+        let mut list = List::<vm_page_t>::new();
+        list.add_tail(page);
+        self.free_list_locked(&mut list);
+    }
+
+    pub fn free_list(&mut self, list: &mut List<vm_page_t>) {
+        self.free_list_locked(list);
+    }
+
+    fn free_list_locked(&mut self, list: &mut List<vm_page_t>) {
+        let mut free_list = List::<vm_page_t>::new();
+        let mut free_loaned_list = List::<vm_page_t>::new();
+
+        while let Some(mut page) = list.remove_head() {
+            unsafe {
+                self.free_page_helper_locked(page.as_mut());
+
+                if !page.as_ref().is_loaned() {
+                    free_list.add_tail(page);
+                } else if !page.as_ref().is_loan_cancelled() {
+                    free_loaned_list.add_tail(page);
+                }
+                /* A freed page whose loan is cancelled stays FREE but
+                 * on no list, until the loan ends. */
+            }
+        }
+
+        let count = free_list.len() as u64;
+        let loaned = free_loaned_list.len() as u64;
+
+        self.free_list.append(&mut free_list);
+        self.free_loaned_list.append(&mut free_loaned_list);
+
+        self.increment_free_count_locked(count);
+        self.increment_free_loaned_count_locked(loaned);
+    }
+
+    /* Loans the (allocated) pages of |list| to the rest of the system;
+     * they are freed to the loaned free list. */
+    pub fn begin_loan(&mut self, list: &mut List<vm_page_t>) {
+        for page in list.iter() {
+            unsafe {
+                debug_assert!(!page.as_ref().is_loaned());
+                debug_assert!(!page.as_ref().is_free());
+                debug_assert!(!page.as_ref().is_loan_cancelled());
+                page.as_ref().set_is_loaned();
+            }
+            self.loaned_count += 1;
+        }
+
+        self.free_list_locked(list);
+    }
+
+    /* The lender wants its pages back. Free ones are taken out of
+     * circulation now; borrowed ones once their borrower frees them. */
+    pub fn cancel_loan(&mut self, paddr: paddr_t, count: usize) {
+        for i in 0..count {
+            let pa = paddr + i * PAGE_SIZE;
+            let page = match self.paddr_to_page(pa) {
+                Some(page) => page,
+                None => {
+                    debug_assert!(false, "no page at {:x}", pa);
+                    continue;
+                }
+            };
+
+            unsafe {
+                debug_assert!(page.as_ref().is_loaned());
+                debug_assert!(!page.as_ref().is_loan_cancelled());
+                page.as_ref().set_is_loan_cancelled();
+                self.loan_cancelled_count += 1;
+
+                if page.as_ref().is_free() {
+                    /* Currently in free_loaned_list. */
+                    self.free_loaned_list.delete(page);
+                    self.decrement_free_loaned_count_locked(1);
+                }
+            }
+        }
+    }
+
+    /* Ends the loan of the (cancelled and free) pages, which are
+     * returned to the lender allocated, in |list|. Nothing changes
+     * unless all of them are in that state. */
+    pub fn end_loan(&mut self, paddr: paddr_t, count: usize,
+                    list: &mut List<vm_page_t>) -> Result<(), ErrNO> {
+        for i in 0..count {
+            let pa = paddr + i * PAGE_SIZE;
+            let page = self.paddr_to_page(pa).ok_or(ErrNO::NotFound)?;
+            let page = unsafe { page.as_ref() };
+            if !page.is_loaned() || !page.is_loan_cancelled() ||
+               !page.is_free() {
+                dprint!(WARN, "end_loan: page {:x} is not a free \
+                        cancelled loan\n", pa);
+                return Err(ErrNO::BadState);
+            }
+        }
+
+        for i in 0..count {
+            let pa = paddr + i * PAGE_SIZE;
+            let mut page = self.paddr_to_page(pa).ok_or(ErrNO::NotFound)?;
+            unsafe {
+                page.as_ref().clear_is_loaned();
+                page.as_ref().clear_is_loan_cancelled();
+                self.loaned_count -= 1;
+                self.loan_cancelled_count -= 1;
+
+                self.alloc_page_helper_locked(page.as_mut(), 0);
+            }
+            list.add_tail(page);
+        }
+        Ok(())
+    }
+
+    /* The lender of these pages is gone, so they are no longer
+     * loaned. Free ones move to the ordinary free list. */
+    pub fn delete_lender(&mut self, paddr: paddr_t, count: usize) {
+        let mut freed = List::<vm_page_t>::new();
+
+        for i in 0..count {
+            let pa = paddr + i * PAGE_SIZE;
+            let page = match self.paddr_to_page(pa) {
+                Some(page) => page,
+                None => {
+                    debug_assert!(false, "no page at {:x}", pa);
+                    continue;
+                }
+            };
+
+            unsafe {
+                debug_assert!(page.as_ref().is_loaned());
+
+                if page.as_ref().is_free() {
+                    if !page.as_ref().is_loan_cancelled() {
+                        self.free_loaned_list.delete(page);
+                        self.decrement_free_loaned_count_locked(1);
+                    }
+                    freed.add_tail(page);
+                }
+
+                if page.as_ref().is_loan_cancelled() {
+                    self.loan_cancelled_count -= 1;
+                }
+
+                page.as_ref().clear_is_loan_cancelled();
+                page.as_ref().clear_is_loaned();
+                self.loaned_count -= 1;
+            }
+        }
+
+        let count = freed.len() as u64;
+        self.free_list.append(&mut freed);
+        self.increment_free_count_locked(count);
+    }
 }
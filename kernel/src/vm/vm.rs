@@ -4,19 +4,381 @@
  * at https://opensource.org/licenses/MIT
  */
 
-use crate::vm::vm_aspace::kernel_aspace_init_pre_heap;
+use alloc::sync::Arc;
+use crate::{
+    ErrNO, dprint, ALWAYS, INFO, SPEW, PAGE_SIZE, BOOT_HEAP_SIZE,
+    paddr_t, vaddr_t,
+};
+use crate::lib::list::List;
+use crate::vm::page::vm_page_t;
+use crate::vm::vm_page_state;
+use crate::vm::pmm::{pmm_paddr_to_page, pmm_free, pmm_count_free_pages};
+use crate::vm::bootalloc::{boot_alloc_start_phys, boot_alloc_seal};
+use crate::vm::vm_aspace::{
+    kernel_aspace_init_pre_heap, kernel_aspace, vaddr_to_aspace,
+    vm_aspace_dump_all, VmAspace, VmAspaceType,
+};
+use crate::arch::mmu::{
+    ARCH_MMU_FLAG_PERM_READ, ARCH_MMU_FLAG_PERM_WRITE,
+    ARCH_MMU_FLAG_PERM_USER,
+};
+use crate::arch::mmu::arch_kernel_wx_check;
+use crate::vm::vm_mapping::VmMapping;
+use crate::vm::vm_object::VmObject;
+use crate::vm::vm_object_paged::{VmObjectPaged, VMO_CHILD_SNAPSHOT};
+use crate::vm::vm_address_region::{
+    VMAR_FLAG_COMPACT, VMAR_FLAG_MAP_RANGE,
+};
+
+/* Page fault flags */
+pub const VMM_PF_FLAG_WRITE:        u32 = 1 << 0;
+pub const VMM_PF_FLAG_USER:         u32 = 1 << 1;
+pub const VMM_PF_FLAG_INSTRUCTION:  u32 = 1 << 3;
+
+/* A short string for the page fault flags, e.g. "wu" */
+fn vmm_pf_flags_to_string(pf_flags: u32) -> [u8; 3] {
+    [
+        if (pf_flags & VMM_PF_FLAG_WRITE) != 0 { b'w' } else { b'r' },
+        if (pf_flags & VMM_PF_FLAG_USER) != 0 { b'u' } else { b's' },
+        if (pf_flags & VMM_PF_FLAG_INSTRUCTION) != 0 { b'i' } else { b'd' },
+    ]
+}
+
+/* Called from the arch trap handler for a page fault at |addr| */
+pub fn vmm_page_fault_handler(addr: vaddr_t, pf_flags: u32)
+    -> Result<(), ErrNO> {
+
+    let flags = vmm_pf_flags_to_string(pf_flags);
+    let flags = core::str::from_utf8(&flags).unwrap_or("");
+    dprint!(SPEW, "page fault at {:x} flags {}\n", addr, flags);
+
+    let aspace = match vaddr_to_aspace(addr) {
+        Some(aspace) => aspace,
+        None => {
+            dprint!(INFO, "PageFault: no aspace for {:x} flags {}\n",
+                    addr, flags);
+            return Err(ErrNO::NotFound);
+        }
+    };
+
+    let ret = aspace.page_fault(addr, pf_flags);
+    if let Err(e) = &ret {
+        dprint!(INFO, "PageFault: error {:?} in aspace {} at {:x} \
+                flags {}\n", e, aspace.name(), addr, flags);
+    }
+    ret
+}
+
+/* Give the WIRED pages of [pa, pa + len) back to the PMM. */
+fn free_pages_in_use_phys(pa: paddr_t, len: usize) -> Result<(), ErrNO> {
+    let mut list = List::<vm_page_t>::new();
+    let mut addr = pa;
+    while addr < pa + len {
+        let page = pmm_paddr_to_page(addr)
+            .ok_or_else(|| ErrNO::NotFound)?;
+
+        unsafe {
+            if page.as_ref().state() != vm_page_state::WIRED {
+                return Err(ErrNO::BadState);
+            }
+        }
+        list.add_tail(page);
+        addr += PAGE_SIZE;
+    }
+
+    pmm_free(&mut list);
+    Ok(())
+}
+
+pub fn vm_init_preheap() -> Result<(), ErrNO> {
+    /* the kernel image is mapped W^X, make sure it stays so */
+    arch_kernel_wx_check()?;
 
-pub fn vm_init_preheap() {
     /* allow the vmm a shot at initializing some of its data structures */
-    kernel_aspace_init_pre_heap();
+    kernel_aspace_init_pre_heap()?;
 
     // vm_init_preheap_vmars();
 
-    // // mark the physical pages used by the boot time allocator
-    // if (boot_alloc_end != boot_alloc_start) {
-    //   dprintf(INFO, "VM: marking boot alloc used range [%#" PRIxPTR ", %#" PRIxPTR ")\n",
-    //           boot_alloc_start, boot_alloc_end);
+    /* The whole boot heap was reserved, and so WIRED, at boot.
+     * From here on the boot allocator doesn't grow any more:
+     * the pages it used stay WIRED, the rest go back to the PMM. */
+    let boot_alloc_start = boot_alloc_start_phys();
+    let boot_alloc_end = boot_alloc_seal();
+    if boot_alloc_end != boot_alloc_start {
+        dprint!(INFO, "VM: marking boot alloc used range [{:x}, {:x})\n",
+                boot_alloc_start, boot_alloc_end);
+    }
+
+    let tail_len = boot_alloc_start + BOOT_HEAP_SIZE - boot_alloc_end;
+    dprint!(INFO, "VM: returning boot alloc unused range [{:x}, {:x})\n",
+            boot_alloc_end, boot_alloc_end + tail_len);
+    free_pages_in_use_phys(boot_alloc_end, tail_len)
+}
+
+/* Allocate |count| pages of demand paged kernel memory and touch
+ * each of them, so that they are faulted in one by one. */
+fn vm_demand_test(count: usize) -> Result<(), ErrNO> {
+    let aspace = kernel_aspace().ok_or(ErrNO::BadState)?;
+    let size = count * PAGE_SIZE;
+    let base = aspace.alloc("demand test", size,
+                            ARCH_MMU_FLAG_PERM_READ |
+                            ARCH_MMU_FLAG_PERM_WRITE)?;
+
+    let free_before = pmm_count_free_pages();
+    let mut ret = Ok(());
+    for i in 0..count {
+        let ptr = (base + i * PAGE_SIZE) as *mut usize;
+        unsafe {
+            if ptr.read_volatile() != 0 {
+                ret = Err(ErrNO::BadState);
+            }
+            ptr.write_volatile(i);
+        }
+    }
+    let faulted = free_before - pmm_count_free_pages();
+    dprint!(ALWAYS, "{} pages at {:x}, {} faulted in\n",
+            count, base, faulted);
+
+    aspace.free(base)?;
+    ret
+}
+
+/* Map a paged VMO of |count| pages and go through what it does:
+ * writes through the mapping and through the VMO, reads of pages
+ * never written, zero-page dedup and decommit. */
+fn vm_vmo_test(count: usize) -> Result<(), ErrNO> {
+    let aspace = kernel_aspace().ok_or(ErrNO::BadState)?;
+    let size = count * PAGE_SIZE;
+    let vmo = VmObjectPaged::create(0, size)?;
+    let mapping = VmMapping::new("vmo test", size,
+                                 ARCH_MMU_FLAG_PERM_READ |
+                                 ARCH_MMU_FLAG_PERM_WRITE,
+                                 Some(vmo.clone()), 0);
+    let base = aspace.map_object(aspace.base(), 0, VMAR_FLAG_COMPACT,
+                                 mapping)?;
+
+    let mut ret = Ok(());
+    for i in 0..count {
+        let ptr = (base + i * PAGE_SIZE) as *mut usize;
+        unsafe {
+            /* even pages are written, odd ones only read */
+            if i % 2 == 0 {
+                ptr.write_volatile(i + 1);
+            } else if ptr.read_volatile() != 0 {
+                ret = Err(ErrNO::BadState);
+            }
+        }
+        /* zeroes don't commit anything */
+        vmo.write(i * PAGE_SIZE + 8, &[0u8; 8])?;
+    }
+    let mut word = [0u8; 8];
+    vmo.read(0, &mut word)?;
+    if usize::from_ne_bytes(word) != 1 {
+        ret = Err(ErrNO::BadState);
+    }
+    dprint!(ALWAYS, "{} pages at {:x}, {} committed\n",
+            count, base, vmo.committed_pages());
+
+    for i in (0..count).step_by(4) {
+        let ptr = (base + i * PAGE_SIZE) as *mut usize;
+        unsafe { ptr.write_volatile(0); }
+    }
+    let deduped = vmo.dedup_zero_pages();
+    dprint!(ALWAYS, "{} zero pages deduped, {} committed\n",
+            deduped, vmo.committed_pages());
+
+    vmo.decommit_range(0, size)?;
+    dprint!(ALWAYS, "decommitted, {} committed\n", vmo.committed_pages());
+
+    aspace.free(base)?;
+    ret
+}
+
+/* The first word of page |i| of |vmo| */
+fn vmo_read_word(vmo: &VmObjectPaged, i: usize) -> Result<usize, ErrNO> {
+    let mut word = [0u8; 8];
+    vmo.read(i * PAGE_SIZE, &mut word)?;
+    Ok(usize::from_ne_bytes(word))
+}
+
+/* The word at |va|, as read by the kernel */
+fn peek_word(va: vaddr_t) -> usize {
+    unsafe { (va as *const usize).read_volatile() }
+}
+
+/* Map |vmo| into the kernel aspace, read-write */
+fn map_test_vmo(name: &str, vmo: &Arc<VmObjectPaged>, size: usize)
+    -> Result<vaddr_t, ErrNO> {
+    let aspace = kernel_aspace().ok_or(ErrNO::BadState)?;
+    let mapping = VmMapping::new(name, size,
+                                 ARCH_MMU_FLAG_PERM_READ |
+                                 ARCH_MMU_FLAG_PERM_WRITE,
+                                 Some(vmo.clone()), 0);
+    aspace.map_object(aspace.base(), 0, VMAR_FLAG_COMPACT, mapping)
+}
+
+/* Snapshot a VMO of |count| pages, write to both sides through a
+ * mapping and through the VMOs, and check neither sees the other's
+ * writes, nor does any mapping keep showing a page that was
+ * replaced: the parent is mapped twice and the clone once.
+ * Then let the parent go, which gives its pages back. */
+fn vm_clone_test(count: usize) -> Result<(), ErrNO> {
+    if count < 2 {
+        return Err(ErrNO::InvalidArgs);
+    }
+    let aspace = kernel_aspace().ok_or(ErrNO::BadState)?;
+    let size = count * PAGE_SIZE;
+    let parent = VmObjectPaged::create(0, size)?;
+    for i in 0..count {
+        parent.write(i * PAGE_SIZE, &(i + 1).to_ne_bytes())?;
+    }
+    let base = map_test_vmo("clone test", &parent, size)?;
+    let base2 = map_test_vmo("clone test 2", &parent, size)?;
+
+    let mut ret = Ok(());
+    if peek_word(base2) != 1 || peek_word(base2 + PAGE_SIZE) != 2 {
+        ret = Err(ErrNO::BadState);
+    }
+
+    let clone = VmObjectPaged::create_clone(&parent, VMO_CHILD_SNAPSHOT,
+                                            0, size)?;
+    let clone_base = map_test_vmo("clone test clone", &clone, size)?;
+    if peek_word(clone_base + PAGE_SIZE) != 2 {
+        ret = Err(ErrNO::BadState);
+    }
+
+    /* the parent writes page 0 through its mapping, now read-only,
+     * and page 1 through the VMO; the clone writes page 1 */
+    unsafe { (base as *mut usize).write_volatile(100); }
+    parent.write(PAGE_SIZE, &300usize.to_ne_bytes())?;
+    clone.write(PAGE_SIZE, &200usize.to_ne_bytes())?;
+    if vmo_read_word(&clone, 0)? != 1 ||
+       vmo_read_word(&parent, 0)? != 100 ||
+       vmo_read_word(&parent, 1)? != 300 ||
+       vmo_read_word(&clone, 1)? != 200 ||
+       peek_word(base2) != 100 ||
+       peek_word(base2 + PAGE_SIZE) != 300 ||
+       peek_word(clone_base) != 1 ||
+       peek_word(clone_base + PAGE_SIZE) != 200 {
+        ret = Err(ErrNO::BadState);
+    }
+    dprint!(ALWAYS, "{} pages cloned, parent has {}, clone has {}\n",
+            count, parent.committed_pages(), clone.committed_pages());
+
+    /* what the clone decommits reads as zeroes, not as the parent */
+    clone.decommit_range(PAGE_SIZE, PAGE_SIZE)?;
+    if vmo_read_word(&clone, 1)? != 0 ||
+       peek_word(clone_base + PAGE_SIZE) != 0 {
+        ret = Err(ErrNO::BadState);
+    }
+
+    aspace.free(base)?;
+    aspace.free(base2)?;
+    let free_before = pmm_count_free_pages();
+    drop(parent);
+    dprint!(ALWAYS, "parent gone, {} pages freed, clone has {}\n",
+            pmm_count_free_pages() - free_before, clone.committed_pages());
+    if vmo_read_word(&clone, 0)? != 1 ||
+       peek_word(clone_base) != 1 ||
+       vmo_read_word(&clone, 1)? != 0 {
+        ret = Err(ErrNO::BadState);
+    }
+    aspace.free(clone_base)?;
+    ret
+}
+
+/* Make a user aspace, map a VMO of |count| committed pages into it
+ * and destroy it again, which must give back the pages of the VMO
+ * and of the page tables. The heap and the slab caches may hold on
+ * to pages of their own, so only these two states are counted. */
+fn vm_aspace_test(count: usize) -> Result<(), ErrNO> {
+    let size = count * PAGE_SIZE;
+    let object_before = vm_page_t::count_by_state(vm_page_state::OBJECT);
+    let mmu_before = vm_page_t::count_by_state(vm_page_state::MMU);
+
+    let aspace = VmAspace::create(VmAspaceType::User, "test")?;
+    let vmo = VmObjectPaged::create(0, size)?;
+    vmo.commit_range(0, size)?;
+    let committed = vmo.committed_pages();
+    let mapping = VmMapping::new("aspace test", size,
+                                 ARCH_MMU_FLAG_PERM_READ |
+                                 ARCH_MMU_FLAG_PERM_WRITE |
+                                 ARCH_MMU_FLAG_PERM_USER,
+                                 Some(vmo), 0);
+    aspace.map_object(aspace.base(), 0,
+                      VMAR_FLAG_COMPACT | VMAR_FLAG_MAP_RANGE, mapping)?;
+    vm_aspace_dump_all();
+
+    let object_used =
+        vm_page_t::count_by_state(vm_page_state::OBJECT) - object_before;
+    let mmu_used = vm_page_t::count_by_state(vm_page_state::MMU) - mmu_before;
+    dprint!(ALWAYS, "aspace mapped {} VMO pages ({} committed), \
+            {} page table pages\n", object_used, committed, mmu_used);
+    if object_used != committed || mmu_used == 0 {
+        aspace.destroy()?;
+        return Err(ErrNO::BadState);
+    }
+
+    aspace.destroy()?;
+    drop(aspace);
+    let object_after = vm_page_t::count_by_state(vm_page_state::OBJECT);
+    let mmu_after = vm_page_t::count_by_state(vm_page_state::MMU);
+    dprint!(ALWAYS, "aspace destroyed, {} VMO and {} page table pages \
+            left over\n",
+            object_after.wrapping_sub(object_before),
+            mmu_after.wrapping_sub(mmu_before));
+    if object_after != object_before || mmu_after != mmu_before {
+        return Err(ErrNO::BadState);
+    }
+    Ok(())
+}
+
+fn vm_usage(name: &str) {
+    dprint!(ALWAYS, "usage:\n");
+    dprint!(ALWAYS, "{} demand N : fault in N pages of kernel memory\n",
+            name);
+    dprint!(ALWAYS, "{} vmo N    : exercise a paged VMO of N pages\n",
+            name);
+    dprint!(ALWAYS, "{} clone N  : snapshot a VMO of N pages\n", name);
+    dprint!(ALWAYS, "{} aspace N : map N pages into a user aspace\n",
+            name);
+    dprint!(ALWAYS, "{} aspaces  : dump all aspaces\n", name);
+}
+
+/* The "vm" debug console command */
+pub fn cmd_vm(argv: &[&str]) -> Result<(), ErrNO> {
+    if argv.len() < 2 {
+        vm_usage(argv[0]);
+        return Err(ErrNO::InvalidArgs);
+    }
 
-    //   MarkPagesInUsePhys(boot_alloc_start, boot_alloc_end - boot_alloc_start);
-    // }
+    match argv[1] {
+        "demand" => {
+            let count = argv.get(2).and_then(|s| s.parse::<usize>().ok())
+                .ok_or_else(|| ErrNO::InvalidArgs)?;
+            vm_demand_test(count)?;
+        },
+        "vmo" => {
+            let count = argv.get(2).and_then(|s| s.parse::<usize>().ok())
+                .ok_or_else(|| ErrNO::InvalidArgs)?;
+            vm_vmo_test(count)?;
+        },
+        "clone" => {
+            let count = argv.get(2).and_then(|s| s.parse::<usize>().ok())
+                .ok_or_else(|| ErrNO::InvalidArgs)?;
+            vm_clone_test(count)?;
+        },
+        "aspace" => {
+            let count = argv.get(2).and_then(|s| s.parse::<usize>().ok())
+                .ok_or_else(|| ErrNO::InvalidArgs)?;
+            vm_aspace_test(count)?;
+        },
+        "aspaces" => vm_aspace_dump_all(),
+        _ => {
+            dprint!(ALWAYS, "unknown command\n");
+            vm_usage(argv[0]);
+            return Err(ErrNO::InvalidArgs);
+        },
+    }
+    Ok(())
 }
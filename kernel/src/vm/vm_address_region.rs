@@ -0,0 +1,423 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * Virtual memory address regions (VMARs)
+ *
+ * A VMAR is a contiguous range of an address space, carved up into
+ * child VMARs and mappings that never overlap. Each VMAR caps what
+ * may be mapped below it with its CAN_MAP_* flags; a child can't
+ * have more than its parent. The tree belongs to its aspace and is
+ * only touched under the aspace lock.
+ */
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+use crate::{
+    ErrNO, dprint, ALWAYS, PAGE_SIZE, PAGE_SHIFT, IS_ALIGNED,
+    IS_PAGE_ALIGNED, vaddr_t,
+};
+use crate::arch::mmu::{
+    ArchMmu, ARCH_MMU_FLAG_PERM_READ, ARCH_MMU_FLAG_PERM_WRITE,
+    ARCH_MMU_FLAG_PERM_EXECUTE,
+};
+use crate::lib::cmdline::cmdline_get_bool;
+use crate::lib::slab::{SlabCache, SlabBox};
+use crate::kernel::spinlock::SpinLock;
+use crate::vm::vm_mapping::VmMapping;
+use crate::vm::vm_object::VmoMappedRange;
+
+/* Place the child at the lowest address that fits,
+ * even where randomized placement is the default */
+pub const VMAR_FLAG_COMPACT:            u32 = 1 << 0;
+/* Place the child at exactly the offset asked for */
+pub const VMAR_FLAG_SPECIFIC:           u32 = 1 << 1;
+/* Map the pages of a new mapping right away rather than
+ * on first touch */
+pub const VMAR_FLAG_MAP_RANGE:          u32 = 1 << 2;
+/* Children may be placed at specific offsets */
+pub const VMAR_FLAG_CAN_MAP_SPECIFIC:   u32 = 1 << 3;
+/* What mappings below the VMAR may allow */
+pub const VMAR_FLAG_CAN_MAP_READ:       u32 = 1 << 4;
+pub const VMAR_FLAG_CAN_MAP_WRITE:      u32 = 1 << 5;
+pub const VMAR_FLAG_CAN_MAP_EXECUTE:    u32 = 1 << 6;
+
+pub const VMAR_CAN_RWX_FLAGS: u32 =
+    VMAR_FLAG_CAN_MAP_READ | VMAR_FLAG_CAN_MAP_WRITE |
+    VMAR_FLAG_CAN_MAP_EXECUTE;
+
+const VMAR_FLAGS_MASK: u32 =
+    VMAR_FLAG_COMPACT | VMAR_FLAG_SPECIFIC |
+    VMAR_FLAG_CAN_MAP_SPECIFIC | VMAR_CAN_RWX_FLAGS;
+
+/* The flags a VMAR keeps; the others only say how to place it */
+const VMAR_FLAGS_KEPT: u32 = VMAR_FLAG_CAN_MAP_SPECIFIC | VMAR_CAN_RWX_FLAGS;
+
+/* Randomized placement can be turned off on the command line */
+const ASLR_DISABLE_OPTION: &str = "kernel.aslr.disable";
+
+/* The nodes of every VMAR tree come from these */
+static VMAR_CACHE: SpinLock<SlabCache<VmAddressRegion>> =
+    SpinLock::new(SlabCache::new("vmar"));
+static VM_MAPPING_CACHE: SpinLock<SlabCache<VmMapping>> =
+    SpinLock::new(SlabCache::new("vm_mapping"));
+
+pub enum VmAddressRegionOrMapping {
+    Region(SlabBox<VmAddressRegion>),
+    Mapping(SlabBox<VmMapping>),
+}
+
+impl VmAddressRegionOrMapping {
+    fn base(&self) -> vaddr_t {
+        match self {
+            Self::Region(r) => r.base,
+            Self::Mapping(m) => m.base(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Self::Region(r) => r.size,
+            Self::Mapping(m) => m.size(),
+        }
+    }
+
+    fn destroy(&mut self, arch_aspace: &mut ArchMmu) -> Result<(), ErrNO> {
+        match self {
+            Self::Region(r) => r.destroy(arch_aspace),
+            Self::Mapping(m) => m.destroy(arch_aspace),
+        }
+    }
+}
+
+/* A representation of a contiguous range of virtual address space */
+pub struct VmAddressRegion {
+    name: String,
+    base: vaddr_t,
+    size: usize,
+    flags: u32,
+    /* pick random spots for children that aren't COMPACT */
+    aslr: bool,
+    /* the children, by base address */
+    children: BTreeMap<vaddr_t, VmAddressRegionOrMapping>,
+}
+
+/* A small xorshift generator for placement, seeded from the timer.
+ * Good enough to make layouts hard to guess, not for secrets. */
+static ASLR_STATE: AtomicU64 = AtomicU64::new(0);
+
+fn aslr_random(bound: usize) -> usize {
+    let mut x = ASLR_STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        let time: u64;
+        unsafe { asm!("rdtime {0}", out(reg) time); }
+        x = time | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    ASLR_STATE.store(x, Ordering::Relaxed);
+    (x % bound as u64) as usize
+}
+
+impl VmAddressRegion {
+    /* The root VMAR of an aspace, which may map anything */
+    pub fn new_root(base: vaddr_t, size: usize, aslr: bool) -> Self {
+        Self {
+            name: String::from("root"),
+            base,
+            size,
+            flags: VMAR_FLAG_CAN_MAP_SPECIFIC | VMAR_CAN_RWX_FLAGS,
+            aslr: aslr && !cmdline_get_bool(ASLR_DISABLE_OPTION, false),
+            children: BTreeMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn base(&self) -> vaddr_t {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /* Whether [base, base + size) is within the region
+     * and clear of its children */
+    fn is_range_free(&self, base: vaddr_t, size: usize) -> bool {
+        if size == 0 || base < self.base || size > self.size ||
+           base - self.base > self.size - size {
+            return false;
+        }
+        let last = base + (size - 1);
+        if let Some((_, c)) = self.children.range(..=last).next_back() {
+            if c.base() + (c.size() - 1) >= base {
+                return false;
+            }
+        }
+        true
+    }
+
+    /* Calls |f| with each gap between the children as its first and
+     * last address, the last being inclusive so that a region at the
+     * top of the address space doesn't overflow. Stops once |f|
+     * returns false. */
+    fn for_each_gap<F>(&self, mut f: F)
+    where F: FnMut(vaddr_t, vaddr_t) -> bool
+    {
+        let mut start = Some(self.base);
+        for c in self.children.values() {
+            if let Some(s) = start {
+                if c.base() > s && !f(s, c.base() - 1) {
+                    return;
+                }
+            }
+            start = c.base().checked_add(c.size());
+        }
+        let last = self.base + (self.size - 1);
+        if let Some(s) = start {
+            if s <= last {
+                f(s, last);
+            }
+        }
+    }
+
+    /* The first |align|-aligned spot for |size| bytes in the gap
+     * [start, last], with the number of such spots */
+    fn spots_in_gap(start: vaddr_t, last: vaddr_t,
+                    size: usize, align: usize) -> (vaddr_t, usize) {
+        let first = match start.checked_add(align - 1) {
+            Some(s) => s & !(align - 1),
+            None => return (0, 0),
+        };
+        if first > last || last - first < size - 1 {
+            return (0, 0);
+        }
+        (first, (last - first - (size - 1)) / align + 1)
+    }
+
+    /* Pick a base for a child of |size| bytes, the lowest one
+     * or, with ASLR, one at random among all that fit */
+    fn alloc_spot(&self, size: usize, align: usize, compact: bool)
+        -> Result<vaddr_t, ErrNO> {
+
+        let mut total = 0;
+        let mut lowest = None;
+        self.for_each_gap(|start, last| {
+            let (first, n) = Self::spots_in_gap(start, last, size, align);
+            if n != 0 && lowest.is_none() {
+                lowest = Some(first);
+            }
+            total += n;
+            true
+        });
+
+        let lowest = lowest.ok_or(ErrNO::NoMem)?;
+        if compact || !self.aslr {
+            return Ok(lowest);
+        }
+
+        let mut pick = aslr_random(total);
+        let mut spot = lowest;
+        self.for_each_gap(|start, last| {
+            let (first, n) = Self::spots_in_gap(start, last, size, align);
+            if pick < n {
+                spot = first + pick * align;
+                return false;
+            }
+            pick -= n;
+            true
+        });
+        Ok(spot)
+    }
+
+    /* Where a new child goes: at |offset| for SPECIFIC,
+     * otherwise anywhere it fits */
+    fn place_child(&self, offset: usize, size: usize, align_pow2: u8,
+                   vmar_flags: u32) -> Result<vaddr_t, ErrNO> {
+
+        if size == 0 || !IS_PAGE_ALIGNED!(size) ||
+           (vmar_flags & !VMAR_FLAGS_MASK) != 0 {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let align_pow2 = core::cmp::max(align_pow2 as usize, PAGE_SHIFT);
+        if align_pow2 >= usize::BITS as usize {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let align = 1 << align_pow2;
+
+        if (vmar_flags & VMAR_FLAG_SPECIFIC) == 0 {
+            if offset != 0 {
+                return Err(ErrNO::InvalidArgs);
+            }
+            return self.alloc_spot(size, align,
+                                   (vmar_flags & VMAR_FLAG_COMPACT) != 0);
+        }
+
+        if (self.flags & VMAR_FLAG_CAN_MAP_SPECIFIC) == 0 {
+            return Err(ErrNO::AccessDenied);
+        }
+        if !IS_ALIGNED!(offset, align) || offset >= self.size {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let base = self.base + offset;
+        if !self.is_range_free(base, size) {
+            return Err(ErrNO::NoMem);
+        }
+        Ok(base)
+    }
+
+    /* Whether mappings of |arch_mmu_flags| are within
+     * what the VMAR allows */
+    fn is_mmu_flags_allowed(&self, arch_mmu_flags: u32) -> bool {
+        let checks = [
+            (ARCH_MMU_FLAG_PERM_READ, VMAR_FLAG_CAN_MAP_READ),
+            (ARCH_MMU_FLAG_PERM_WRITE, VMAR_FLAG_CAN_MAP_WRITE),
+            (ARCH_MMU_FLAG_PERM_EXECUTE, VMAR_FLAG_CAN_MAP_EXECUTE),
+        ];
+        checks.iter().all(|&(perm, can)| {
+            (arch_mmu_flags & perm) == 0 || (self.flags & can) != 0
+        })
+    }
+
+    /* Make a child VMAR of |size| bytes, at |offset| for SPECIFIC.
+     * Its CAN_MAP_* flags have to be a subset of ours. */
+    pub fn create_sub_vmar(&mut self, offset: usize, size: usize,
+                           align_pow2: u8, vmar_flags: u32, name: &str)
+        -> Result<&mut VmAddressRegion, ErrNO> {
+
+        if (vmar_flags & VMAR_CAN_RWX_FLAGS & !self.flags) != 0 ||
+           ((vmar_flags & VMAR_FLAG_CAN_MAP_SPECIFIC) != 0 &&
+            (self.flags & VMAR_FLAG_CAN_MAP_SPECIFIC) == 0) {
+            return Err(ErrNO::AccessDenied);
+        }
+
+        let base = self.place_child(offset, size, align_pow2, vmar_flags)?;
+        let vmar = SlabBox::new(&VMAR_CACHE, VmAddressRegion {
+            name: String::from(name),
+            base,
+            size,
+            flags: vmar_flags & VMAR_FLAGS_KEPT,
+            aslr: self.aslr,
+            children: BTreeMap::new(),
+        })?;
+        self.children.insert(base, VmAddressRegionOrMapping::Region(vmar));
+
+        match self.children.get_mut(&base) {
+            Some(VmAddressRegionOrMapping::Region(r)) => Ok(&mut **r),
+            _ => Err(ErrNO::BadState),
+        }
+    }
+
+    /* Place |mapping| at |offset| for SPECIFIC, or anywhere it fits.
+     * Its permissions have to be allowed by the VMAR. */
+    pub fn create_vm_mapping(&mut self, offset: usize, align_pow2: u8,
+                             vmar_flags: u32, mut mapping: VmMapping)
+        -> Result<&mut VmMapping, ErrNO> {
+
+        if (vmar_flags & (VMAR_FLAG_CAN_MAP_SPECIFIC |
+                          VMAR_CAN_RWX_FLAGS)) != 0 {
+            return Err(ErrNO::InvalidArgs);
+        }
+        if !self.is_mmu_flags_allowed(mapping.arch_mmu_flags()) {
+            return Err(ErrNO::AccessDenied);
+        }
+        if !IS_PAGE_ALIGNED!(mapping.object_offset()) ||
+           mapping.object_offset().checked_add(mapping.size()).is_none() {
+            return Err(ErrNO::InvalidArgs);
+        }
+
+        let base = self.place_child(offset, mapping.size(), align_pow2,
+                                    vmar_flags)?;
+        mapping.set_base(base);
+        let mapping = SlabBox::new(&VM_MAPPING_CACHE, mapping)?;
+        self.children.insert(base, VmAddressRegionOrMapping::Mapping(mapping));
+
+        match self.children.get_mut(&base) {
+            Some(VmAddressRegionOrMapping::Mapping(m)) => Ok(&mut **m),
+            _ => Err(ErrNO::BadState),
+        }
+    }
+
+    /* The child covering |vaddr|, if any */
+    fn find_child(&mut self, vaddr: vaddr_t)
+        -> Option<&mut VmAddressRegionOrMapping> {
+        self.children.range_mut(..=vaddr).next_back()
+            .map(|(_, c)| c)
+            .filter(|c| vaddr - c.base() < c.size())
+    }
+
+    /* The VMAR starting at |base|, this one or one below it */
+    pub fn find_region(&mut self, base: vaddr_t)
+        -> Option<&mut VmAddressRegion> {
+        if base == self.base {
+            return Some(self);
+        }
+        match self.find_child(base) {
+            Some(VmAddressRegionOrMapping::Region(r)) => r.find_region(base),
+            _ => None,
+        }
+    }
+
+    /* Destroy the child that starts at |base|, with all below it */
+    pub fn destroy_child(&mut self, base: vaddr_t,
+                         arch_aspace: &mut ArchMmu)
+        -> Result<(), ErrNO> {
+        match self.children.remove(&base) {
+            Some(mut child) => child.destroy(arch_aspace),
+            None => Err(ErrNO::NotFound),
+        }
+    }
+
+    /* Tear down every child, unmapping and freeing what they hold.
+     * The VMAR itself stays, empty. */
+    pub fn destroy(&mut self, arch_aspace: &mut ArchMmu)
+        -> Result<(), ErrNO> {
+        let mut ret = Ok(());
+        let children = core::mem::take(&mut self.children);
+        for (_, mut child) in children {
+            if let Err(e) = child.destroy(arch_aspace) {
+                ret = Err(e);
+            }
+        }
+        ret
+    }
+
+    pub fn page_fault(&mut self, vaddr: vaddr_t, pf_flags: u32,
+                      arch_aspace: &mut ArchMmu,
+                      stale: &mut Vec<VmoMappedRange>)
+        -> Result<(), ErrNO> {
+        match self.find_child(vaddr) {
+            Some(VmAddressRegionOrMapping::Region(r)) =>
+                r.page_fault(vaddr, pf_flags, arch_aspace, stale),
+            Some(VmAddressRegionOrMapping::Mapping(m)) =>
+                m.page_fault(vaddr, pf_flags, arch_aspace, stale),
+            None => Err(ErrNO::NotFound),
+        }
+    }
+
+    pub fn dump(&self, depth: usize) {
+        dprint!(ALWAYS, "{:indent$}vmar [{:x}, {:x}) flags {:#x} '{}'\n",
+                "", self.base, self.base + (self.size - 1), self.flags,
+                self.name, indent = depth * 2);
+        for child in self.children.values() {
+            match child {
+                VmAddressRegionOrMapping::Region(r) => r.dump(depth + 1),
+                VmAddressRegionOrMapping::Mapping(m) => m.dump(depth + 1),
+            }
+        }
+    }
+}
@@ -6,22 +6,28 @@
 
 use core::ptr::NonNull;
 use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 use crate::{
-    vaddr_t, KERNEL_ASPACE_BASE, KERNEL_ASPACE_SIZE,
-    dprint, INFO,
+    vaddr_t, dprint, INFO, ALWAYS, ErrNO, PAGE_SIZE, IS_ALIGNED,
+    IS_PAGE_ALIGNED, ROUNDUP,
+    ROUNDUP_PAGE_SIZE, KERNEL_BASE, BOOT_HEAP_SIZE, kernel_size,
 };
-use crate::lib::list::{ListNode, Linked};
-
-/* A representation of a contiguous range of virtual address space */
-/* VmAddressRegionOrMapping */
-struct VmAddressRegion {
-}
-
-impl VmAddressRegion {
-    pub fn new() -> Self {
-        Self {}
-    }
-}
+use crate::arch::mmu::{
+    kernel_aspace_base, kernel_aspace_size, user_aspace_size, ArchMmu,
+    ARCH_ASPACE_FLAG_KERNEL, USER_ASPACE_BASE,
+};
+use crate::vm::physmap::physmap_max_size;
+use crate::vm::vm_mapping::VmMapping;
+use crate::vm::vm_object::VmoMappedRange;
+use crate::vm::vm_object_paged::VmObjectPaged;
+use crate::vm::vm_address_region::{
+    VmAddressRegion, VMAR_FLAG_SPECIFIC, VMAR_FLAG_COMPACT,
+    VMAR_FLAG_MAP_RANGE,
+};
+use crate::kernel::spinlock::SpinLock;
+use crate::kernel::once::Once;
+use crate::lib::list::{List, ListNode, Linked};
 
 pub enum VmAspaceType {
     User,
@@ -34,15 +40,34 @@ pub enum VmAspaceType {
     GuestPhysical,
 }
 
+/* The parts of an aspace that change, under its lock */
+struct VmAspaceInner {
+    root_vmar: Option<VmAddressRegion>,
+    arch_aspace: ArchMmu,
+    /* on ASPACES, from create() until it is destroyed */
+    registered: bool,
+}
+
+unsafe impl Send for VmAspaceInner {}
+
+/* queue_node comes first, from_node() relies on it */
+#[repr(C)]
 pub struct VmAspace {
     queue_node: ListNode,
     name: String,
     base: vaddr_t,
     size: usize,
     as_type: VmAspaceType,
-    root_vmar: Option<VmAddressRegion>,
+    /* the Arc the aspace lives in, for its mappings to refer to */
+    self_ref: Weak<VmAspace>,
+    inner: SpinLock<VmAspaceInner>,
 }
 
+/* queue_node is only touched under the ASPACES lock,
+ * everything else that changes is under inner */
+unsafe impl Send for VmAspace {}
+unsafe impl Sync for VmAspace {}
+
 impl Linked for VmAspace {
     fn from_node(ptr: NonNull<ListNode>) -> Option<NonNull<Self>> {
         NonNull::<Self>::new(ptr.as_ptr() as *mut Self)
@@ -51,37 +76,312 @@ impl Linked for VmAspace {
     fn into_node(&mut self) -> &mut ListNode {
         &mut (self.queue_node)
     }
+}
 
-    fn delete_from_list(&mut self) {
-        self.into_node().delete_from_list();
-    }
+/* Every aspace that has been created and not destroyed yet */
+struct AspaceList {
+    list: List<VmAspace>,
 }
 
+unsafe impl Send for AspaceList {}
+
+static ASPACES: SpinLock<AspaceList> =
+    SpinLock::new(AspaceList { list: List::new() });
+
 impl VmAspace {
-    pub fn new(name: &str,
-               base: vaddr_t,
-               size: usize,
-               as_type: VmAspaceType) -> Self {
+    fn new(name: &str,
+           base: vaddr_t,
+           size: usize,
+           as_type: VmAspaceType,
+           self_ref: Weak<VmAspace>) -> Self {
         VmAspace {
             queue_node: ListNode::new(),
             name: String::from(name),
             base,
             size,
             as_type,
-            root_vmar: None,
+            self_ref,
+            inner: SpinLock::new(VmAspaceInner {
+                root_vmar: None,
+                arch_aspace: ArchMmu::new(),
+                registered: false,
+            }),
+        }
+    }
+
+    /* An aspace of |as_type| with its page tables and root VMAR,
+     * listed with the others until it is destroyed */
+    pub fn create(as_type: VmAspaceType, name: &str)
+        -> Result<Arc<VmAspace>, ErrNO> {
+
+        let (base, size) = match as_type {
+            VmAspaceType::Kernel =>
+                (kernel_aspace_base(), kernel_aspace_size()),
+            VmAspaceType::User => (USER_ASPACE_BASE, user_aspace_size()),
+            VmAspaceType::LowKernel | VmAspaceType::GuestPhysical =>
+                (0, USER_ASPACE_BASE + user_aspace_size()),
+        };
+
+        /* on the heap rather than a slab cache, see lib/slab.rs */
+        let aspace = Arc::new_cyclic(|self_ref| {
+            VmAspace::new(name, base, size, as_type, self_ref.clone())
+        });
+        aspace.init()?;
+
+        let mut aspaces = ASPACES.lock();
+        aspaces.list.add_tail(NonNull::from(&*aspace));
+        aspace.inner.lock().registered = true;
+        Ok(aspace)
+    }
+
+    /* Set up the page tables and the root VMAR */
+    fn init(&self) -> Result<(), ErrNO> {
+        let arch_flags = match self.as_type {
+            VmAspaceType::Kernel => ARCH_ASPACE_FLAG_KERNEL,
+            _ => 0,
+        };
+
+        /* only user aspaces get randomized layouts */
+        let aslr = matches!(self.as_type, VmAspaceType::User);
+
+        let mut inner = self.inner.lock();
+        inner.arch_aspace.init(self.base, self.size, arch_flags)?;
+        inner.root_vmar =
+            Some(VmAddressRegion::new_root(self.base, self.size, aslr));
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn base(&self) -> vaddr_t {
+        self.base
+    }
+
+    /* A reference that doesn't keep the aspace around */
+    pub fn downgrade(&self) -> Weak<VmAspace> {
+        self.self_ref.clone()
+    }
+
+    /* Whether destroy() was called, after which nothing
+     * is mapped anymore */
+    pub fn is_destroyed(&self) -> bool {
+        self.inner.lock().root_vmar.is_none()
+    }
+
+    pub fn contains(&self, vaddr: vaddr_t) -> bool {
+        vaddr >= self.base && vaddr - self.base < self.size
+    }
+
+    /* Set aside [base, base + size) for something mapped
+     * outside of the VMM, so that nothing else lands there */
+    pub fn reserve_space(&self, name: &str, base: vaddr_t, size: usize)
+        -> Result<(), ErrNO> {
+
+        let mut inner = self.inner.lock();
+        let root_vmar = inner.root_vmar.as_mut().ok_or(ErrNO::BadState)?;
+        let offset = base.wrapping_sub(root_vmar.base());
+        root_vmar.create_vm_mapping(offset, 0, VMAR_FLAG_SPECIFIC,
+                                    VmMapping::new(name, size, 0, None, 0))?;
+        Ok(())
+    }
+
+    /* Place |mapping| in the VMAR starting at |parent| and return
+     * where it starts. With VMAR_FLAG_MAP_RANGE the pages the VMO
+     * has are mapped right away, the others are faulted in. */
+    pub fn map_object(&self, parent: vaddr_t, offset: usize,
+                      vmar_flags: u32, mapping: VmMapping)
+        -> Result<vaddr_t, ErrNO> {
+
+        let mut inner = self.inner.lock();
+        let VmAspaceInner { root_vmar, arch_aspace, .. } = &mut *inner;
+        let root_vmar = root_vmar.as_mut().ok_or(ErrNO::BadState)?;
+        let parent = root_vmar.find_region(parent).ok_or(ErrNO::NotFound)?;
+        let mapping = parent.create_vm_mapping(offset, 0,
+                                               vmar_flags &
+                                               !VMAR_FLAG_MAP_RANGE,
+                                               mapping)?;
+        mapping.activate(self);
+
+        let base = mapping.base();
+        if (vmar_flags & VMAR_FLAG_MAP_RANGE) != 0 {
+            let size = mapping.size();
+            if let Err(e) = mapping.map_range(0, size, arch_aspace) {
+                parent.destroy_child(base, arch_aspace)?;
+                return Err(e);
+            }
         }
+        Ok(base)
+    }
+
+    /* Find room for |size| bytes of zero-filled memory that is paged
+     * in on demand, and return where it starts. */
+    pub fn alloc(&self, name: &str, size: usize, arch_mmu_flags: u32)
+        -> Result<vaddr_t, ErrNO> {
+
+        if size == 0 || !IS_PAGE_ALIGNED!(size) {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let vmo = VmObjectPaged::create(0, size)?;
+        let mapping = VmMapping::new(name, size, arch_mmu_flags,
+                                     Some(vmo), 0);
+        self.map_object(self.base, 0, VMAR_FLAG_COMPACT, mapping)
+    }
+
+    /* Unmap |count| pages at |vaddr|, for a VMO that takes its pages
+     * back; they are faulted in again if they are still needed. */
+    pub fn unmap_range(&self, vaddr: vaddr_t, count: usize)
+        -> Result<usize, ErrNO> {
+        let mut inner = self.inner.lock();
+        if inner.root_vmar.is_none() {
+            return Err(ErrNO::BadState);
+        }
+        inner.arch_aspace.unmap(vaddr, count)
+    }
+
+    /* Change the permissions of what is mapped of |count| pages
+     * at |vaddr|, for a VMO that starts sharing its pages. */
+    pub fn protect_range(&self, vaddr: vaddr_t, count: usize,
+                         arch_mmu_flags: u32) -> Result<(), ErrNO> {
+        let mut inner = self.inner.lock();
+        if inner.root_vmar.is_none() {
+            return Err(ErrNO::BadState);
+        }
+        inner.arch_aspace.protect(vaddr, count, arch_mmu_flags)
+    }
+
+    /* Undo the alloc that returned |vaddr| */
+    pub fn free(&self, vaddr: vaddr_t) -> Result<(), ErrNO> {
+        let mut inner = self.inner.lock();
+        let VmAspaceInner { root_vmar, arch_aspace, .. } = &mut *inner;
+        let root_vmar = root_vmar.as_mut().ok_or(ErrNO::BadState)?;
+        root_vmar.destroy_child(vaddr, arch_aspace)
+    }
+
+    /* Make a VMAR of |size| bytes under the one starting at
+     * |parent|, and return where it starts. */
+    pub fn create_sub_vmar(&self, parent: vaddr_t, offset: usize,
+                           size: usize, align_pow2: u8, vmar_flags: u32,
+                           name: &str) -> Result<vaddr_t, ErrNO> {
+
+        let mut inner = self.inner.lock();
+        let root_vmar = inner.root_vmar.as_mut().ok_or(ErrNO::BadState)?;
+        let parent = root_vmar.find_region(parent).ok_or(ErrNO::NotFound)?;
+        let vmar = parent.create_sub_vmar(offset, size, align_pow2,
+                                          vmar_flags, name)?;
+        Ok(vmar.base())
+    }
+
+    /* Destroy the VMAR starting at |base| and everything in it */
+    pub fn destroy_vmar(&self, parent: vaddr_t, base: vaddr_t)
+        -> Result<(), ErrNO> {
+
+        let mut inner = self.inner.lock();
+        let VmAspaceInner { root_vmar, arch_aspace, .. } = &mut *inner;
+        let root_vmar = root_vmar.as_mut().ok_or(ErrNO::BadState)?;
+        let parent = root_vmar.find_region(parent).ok_or(ErrNO::NotFound)?;
+        parent.destroy_child(base, arch_aspace)
+    }
+
+    /* Tear down every mapping and the page tables. What is left
+     * of the aspace can't map anything anymore. */
+    pub fn destroy(&self) -> Result<(), ErrNO> {
+        if matches!(self.as_type, VmAspaceType::Kernel) {
+            return Err(ErrNO::BadState);
+        }
+
+        let mut inner = self.inner.lock();
+        let VmAspaceInner { root_vmar, arch_aspace, .. } = &mut *inner;
+        let mut vmar = root_vmar.take().ok_or(ErrNO::BadState)?;
+        let ret = vmar.destroy(arch_aspace);
+        arch_aspace.destroy()?;
+        drop(inner);
+
+        self.unregister();
+        ret
+    }
+
+    /* Take the aspace off ASPACES, once */
+    fn unregister(&self) {
+        let mut aspaces = ASPACES.lock();
+        let mut inner = self.inner.lock();
+        if inner.registered {
+            inner.registered = false;
+            aspaces.list.delete(NonNull::from(self));
+        }
+    }
+
+    pub fn dump(&self) {
+        dprint!(ALWAYS, "aspace '{}' [{:x}, {:x})\n",
+                self.name, self.base, self.base + (self.size - 1));
+        if let Some(vmar) = self.inner.lock().root_vmar.as_ref() {
+            vmar.dump(1);
+        }
+    }
+
+    /* Resolve a fault at |vaddr| against the mapping covering it */
+    pub fn page_fault(&self, vaddr: vaddr_t, pf_flags: u32)
+        -> Result<(), ErrNO> {
+
+        let mut stale = Vec::new();
+        let ret = {
+            let mut inner = self.inner.lock();
+            let VmAspaceInner { root_vmar, arch_aspace, .. } = &mut *inner;
+            match root_vmar {
+                Some(vmar) => vmar.page_fault(vaddr, pf_flags, arch_aspace,
+                                              &mut stale),
+                None => Err(ErrNO::BadState),
+            }
+        };
+
+        /* the other mappings of the page that was replaced */
+        VmoMappedRange::unmap(&stale)?;
+        ret
+    }
+}
+
+impl Drop for VmAspace {
+    fn drop(&mut self) {
+        if self.inner.lock().root_vmar.is_some() {
+            let _ = self.destroy();
+        }
+        self.unregister();
+    }
+}
+
+/* Dump every aspace, for the debug console */
+pub fn vm_aspace_dump_all() {
+    let aspaces = ASPACES.lock();
+    for aspace in aspaces.list.iter() {
+        unsafe { aspace.as_ref().dump(); }
     }
 }
 
-pub fn kernel_aspace_init_pre_heap() {
-    let mut kernel_aspace =
-        VmAspace::new("kernel", KERNEL_ASPACE_BASE, KERNEL_ASPACE_SIZE,
-                      VmAspaceType::Kernel);
+static KERNEL_ASPACE: Once<Option<Arc<VmAspace>>> = Once::new(None);
 
-    let root_vmar = Some(VmAddressRegion::new());
-    kernel_aspace.root_vmar = root_vmar;
-    //kernel_aspace.init()?;
+pub fn kernel_aspace() -> Option<&'static VmAspace> {
+    KERNEL_ASPACE.get().and_then(|aspace| aspace.as_deref())
+}
 
-    //aspaces.push_front(kernel_aspace);
+/* The aspace |vaddr| belongs to; only the kernel's for now,
+ * as nothing runs in a user aspace yet */
+pub fn vaddr_to_aspace(vaddr: vaddr_t) -> Option<&'static VmAspace> {
+    kernel_aspace().filter(|aspace| aspace.contains(vaddr))
+}
+
+pub fn kernel_aspace_init_pre_heap() -> Result<(), ErrNO> {
+    let kernel_aspace = VmAspace::create(VmAspaceType::Kernel, "kernel")?;
+
+    /* what was mapped at boot */
+    kernel_aspace.reserve_space("physmap", kernel_aspace_base(),
+                                physmap_max_size())?;
+    let image_size = ROUNDUP_PAGE_SIZE!(kernel_size() + BOOT_HEAP_SIZE);
+    kernel_aspace.reserve_space("kernel image", KERNEL_BASE, image_size)?;
+
+    if !KERNEL_ASPACE.init(|aspace| *aspace = Some(kernel_aspace)) {
+        return Err(ErrNO::BadState);
+    }
     dprint!(INFO, "kernel_aspace_init_pre_heap ok!\n");
-}
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,218 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * A range of an address space and the VMO that backs it.
+ * Pages are mapped as they are faulted in, or all at once
+ * with map_range(). A mapping without a VMO only reserves
+ * its range for something the kernel maps by itself.
+ */
+
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use crate::{ErrNO, dprint, ALWAYS, PAGE_SIZE, ROUNDDOWN, vaddr_t};
+use crate::vm::vm::{
+    VMM_PF_FLAG_WRITE, VMM_PF_FLAG_USER, VMM_PF_FLAG_INSTRUCTION,
+};
+use crate::vm::vm_aspace::VmAspace;
+use crate::vm::vm_object::{VmObject, VmoMappingRef, VmoMappedRange};
+use crate::arch::mmu::{
+    ArchMmu, ARCH_MMU_FLAG_PERM_READ, ARCH_MMU_FLAG_PERM_WRITE,
+    ARCH_MMU_FLAG_PERM_EXECUTE, ARCH_MMU_FLAG_PERM_USER,
+    ARCH_MMU_FLAG_CACHE_MASK,
+};
+
+pub struct VmMapping {
+    name: String,
+    base: vaddr_t,
+    size: usize,
+    arch_mmu_flags: u32,
+    /* None for a reserved range, which never faults pages in */
+    object: Option<Arc<dyn VmObject>>,
+    object_offset: usize,
+    /* set once the mapping is placed and registered with the VMO */
+    aspace: Weak<VmAspace>,
+}
+
+impl VmMapping {
+    /* A mapping of |size| bytes of |object| from |object_offset|,
+     * yet to be placed in a VMAR. The cache policy is the VMO's. */
+    pub fn new(name: &str, size: usize, arch_mmu_flags: u32,
+               object: Option<Arc<dyn VmObject>>, object_offset: usize)
+        -> Self {
+
+        let arch_mmu_flags = match &object {
+            Some(vmo) => (arch_mmu_flags & !ARCH_MMU_FLAG_CACHE_MASK) |
+                vmo.cache_policy(),
+            None => arch_mmu_flags,
+        };
+        Self {
+            name: String::from(name),
+            base: 0,
+            size,
+            arch_mmu_flags,
+            object,
+            object_offset,
+            aspace: Weak::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn base(&self) -> vaddr_t {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn arch_mmu_flags(&self) -> u32 {
+        self.arch_mmu_flags
+    }
+
+    pub fn object_offset(&self) -> usize {
+        self.object_offset
+    }
+
+    pub fn object(&self) -> Option<&Arc<dyn VmObject>> {
+        self.object.as_ref()
+    }
+
+    pub fn contains(&self, vaddr: vaddr_t) -> bool {
+        vaddr >= self.base && vaddr - self.base < self.size
+    }
+
+    /* Placed at |base| by the VMAR */
+    pub(super) fn set_base(&mut self, base: vaddr_t) {
+        self.base = base;
+    }
+
+    /* Let the VMO know where it is mapped, so that it can unmap
+     * pages it takes away */
+    pub fn activate(&mut self, aspace: &VmAspace) {
+        self.aspace = aspace.downgrade();
+        if let Some(vmo) = &self.object {
+            vmo.mapping_list().add(VmoMappingRef {
+                aspace: self.aspace.clone(),
+                base: self.base,
+                size: self.size,
+                object_offset: self.object_offset,
+                arch_mmu_flags: self.arch_mmu_flags,
+            });
+        }
+    }
+
+    /* Whether an access of |pf_flags| is allowed by the mapping */
+    fn access_allowed(&self, pf_flags: u32) -> bool {
+        let needed = if (pf_flags & VMM_PF_FLAG_INSTRUCTION) != 0 {
+            ARCH_MMU_FLAG_PERM_EXECUTE
+        } else if (pf_flags & VMM_PF_FLAG_WRITE) != 0 {
+            ARCH_MMU_FLAG_PERM_WRITE
+        } else {
+            ARCH_MMU_FLAG_PERM_READ
+        };
+        if (self.arch_mmu_flags & needed) == 0 {
+            return false;
+        }
+
+        let user = (pf_flags & VMM_PF_FLAG_USER) != 0;
+        user == ((self.arch_mmu_flags & ARCH_MMU_FLAG_PERM_USER) != 0)
+    }
+
+    /* Resolve a fault at |vaddr| by mapping the page of the VMO.
+     * What else maps the page the VMO showed there before goes to
+     * |stale|, see VmObject::get_page(). */
+    pub fn page_fault(&mut self, vaddr: vaddr_t, pf_flags: u32,
+                      arch_aspace: &mut ArchMmu,
+                      stale: &mut Vec<VmoMappedRange>)
+        -> Result<(), ErrNO> {
+
+        let vmo = match &self.object {
+            Some(vmo) if self.access_allowed(pf_flags) => vmo,
+            _ => return Err(ErrNO::AccessDenied),
+        };
+
+        let va = ROUNDDOWN!(vaddr, PAGE_SIZE);
+        let write = (pf_flags & VMM_PF_FLAG_WRITE) != 0;
+        if let Ok((_, flags)) = arch_aspace.query(va) {
+            /* already there, e.g. a stale TLB entry of another hart */
+            if !write || (flags & ARCH_MMU_FLAG_PERM_WRITE) != 0 {
+                return Ok(());
+            }
+            /* a write to a shared page, e.g. the zero page or one of
+             * the parent of a clone, which gets a copy of its own */
+            arch_aspace.unmap(va, 1)?;
+        }
+
+        let offset = va - self.base + self.object_offset;
+        let (pa, writable) = vmo.get_page(offset, pf_flags, stale)?;
+        /* this one is mapped again right here */
+        stale.retain(|r| !r.is_page(&self.aspace, va));
+        let mut mmu_flags = self.arch_mmu_flags;
+        if !writable {
+            mmu_flags &= !ARCH_MMU_FLAG_PERM_WRITE;
+        }
+        arch_aspace.map(va, &[pa], mmu_flags)?;
+        Ok(())
+    }
+
+    /* Map the pages the VMO already has in [offset, offset + len)
+     * of the mapping. Nothing is committed, that would have to
+     * unmap what the aspace lock held here protects. */
+    pub fn map_range(&mut self, offset: usize, len: usize,
+                     arch_aspace: &mut ArchMmu)
+        -> Result<(), ErrNO> {
+
+        let vmo = self.object.as_ref().ok_or(ErrNO::BadState)?;
+        let end = offset.checked_add(len).ok_or(ErrNO::OutOfRange)?;
+        if end > self.size {
+            return Err(ErrNO::OutOfRange);
+        }
+
+        for off in (ROUNDDOWN!(offset, PAGE_SIZE)..end).step_by(PAGE_SIZE) {
+            let va = self.base + off;
+            if arch_aspace.query(va).is_ok() {
+                continue;
+            }
+            let offset = self.object_offset + off;
+            if let Some((pa, writable)) = vmo.lookup_page(offset) {
+                let mut mmu_flags = self.arch_mmu_flags;
+                if !writable {
+                    mmu_flags &= !ARCH_MMU_FLAG_PERM_WRITE;
+                }
+                arch_aspace.map(va, &[pa], mmu_flags)?;
+            }
+        }
+        Ok(())
+    }
+
+    /* Unmap all of the range and let go of the VMO */
+    pub fn destroy(&mut self, arch_aspace: &mut ArchMmu)
+        -> Result<(), ErrNO> {
+
+        let vmo = match self.object.take() {
+            Some(vmo) => vmo,
+            None => return Ok(()),
+        };
+        vmo.mapping_list().remove(&self.aspace, self.base);
+        arch_aspace.unmap(self.base, self.size / PAGE_SIZE)?;
+        Ok(())
+    }
+
+    pub fn dump(&self, depth: usize) {
+        dprint!(ALWAYS, "{:indent$}map [{:x}, {:x}) mmu {:#x} \
+                offset {:#x} '{}'\n", "", self.base,
+                self.base + (self.size - 1), self.arch_mmu_flags,
+                self.object_offset, self.name, indent = depth * 2);
+        if let Some(vmo) = &self.object {
+            vmo.dump(depth + 1);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * VM objects (VMOs)
+ *
+ * A VMO is a range of memory that can be mapped into aspaces,
+ * at any number of places at once. What backs it depends on the
+ * kind: pages from the PMM for a paged VMO, a fixed physical
+ * range for a physical one.
+ *
+ * Mappings fault their pages in through the VMO. Whenever a VMO
+ * takes pages away, it unmaps them from each of its mappings first,
+ * so nothing keeps reaching memory that went back to the PMM.
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use alloc::sync::Weak;
+use crate::{ErrNO, dprint, WARN, paddr_t, vaddr_t, PAGE_SIZE};
+use crate::arch::mmu::{ARCH_MMU_FLAG_CACHED, ARCH_MMU_FLAG_PERM_WRITE};
+use crate::kernel::spinlock::SpinLock;
+use crate::vm::vm_page_state;
+use crate::vm::pmm::{pmm_alloc_page, pmm_free_page, PMM_ALLOC_FLAG_ZERO};
+use crate::vm::vm_aspace::VmAspace;
+
+pub trait VmObject: Send + Sync {
+    fn size(&self) -> usize;
+
+    fn is_paged(&self) -> bool;
+
+    /* The ARCH_MMU_FLAG_* cache policy mappings of the VMO use */
+    fn cache_policy(&self) -> u32 {
+        ARCH_MMU_FLAG_CACHED
+    }
+
+    /* The page at |offset| for an access of |pf_flags|, committed
+     * as needed, and whether it may be mapped writable. Where the
+     * VMO was mapped showing another page there is added to
+     * |stale|, for the caller to unmap once it let go of the
+     * aspace lock. */
+    fn get_page(&self, offset: usize, pf_flags: u32,
+                stale: &mut Vec<VmoMappedRange>)
+        -> Result<(paddr_t, bool), ErrNO>;
+
+    /* The page at |offset| if there is one, without committing it,
+     * and whether it may be mapped writable */
+    fn lookup_page(&self, offset: usize) -> Option<(paddr_t, bool)>;
+
+    fn commit_range(&self, offset: usize, len: usize) -> Result<(), ErrNO>;
+
+    fn decommit_range(&self, offset: usize, len: usize)
+        -> Result<(), ErrNO>;
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), ErrNO>;
+
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<(), ErrNO>;
+
+    fn resize(&self, size: usize) -> Result<(), ErrNO>;
+
+    fn committed_pages(&self) -> usize;
+
+    fn mapping_list(&self) -> &VmoMappingList;
+
+    fn dump(&self, depth: usize);
+}
+
+/* Where a VMO is mapped: [base, base + size) of |aspace|
+ * shows the VMO from |object_offset| on. */
+#[derive(Clone)]
+pub struct VmoMappingRef {
+    /* the mappings unregister themselves when they are destroyed,
+     * the aspace may be on its way out before that */
+    pub aspace: Weak<VmAspace>,
+    pub base: vaddr_t,
+    pub size: usize,
+    pub object_offset: usize,
+    pub arch_mmu_flags: u32,
+}
+
+/* Pages of a VMO mapped at [vaddr, vaddr + count pages) */
+pub struct VmoMappedRange {
+    aspace: Weak<VmAspace>,
+    vaddr: vaddr_t,
+    count: usize,
+    arch_mmu_flags: u32,
+}
+
+/*
+ * The ranges are collected under the VMO locks and then unmapped
+ * or protected once those are dropped: faults lock the aspace
+ * before the VMO, so the aspaces can't be locked the other way.
+ */
+impl VmoMappedRange {
+    /* Unmap all of |ranges|, carrying on past a failure;
+     * the first error is returned */
+    pub fn unmap(ranges: &[VmoMappedRange]) -> Result<(), ErrNO> {
+        let mut ret = Ok(());
+        for r in ranges {
+            /* an aspace that is gone has nothing mapped */
+            let aspace = match r.aspace.upgrade() {
+                Some(aspace) => aspace,
+                None => continue,
+            };
+            match aspace.unmap_range(r.vaddr, r.count) {
+                Ok(_) => (),
+                Err(ErrNO::BadState) if aspace.is_destroyed() => (),
+                Err(e) => {
+                    dprint!(WARN, "vmo: unmapping {} pages at {:x} \
+                            failed: {:?}\n", r.count, r.vaddr, e);
+                    ret = ret.and(Err(e));
+                },
+            }
+        }
+        ret
+    }
+
+    /* Take write access away, the next write faults. A range that
+     * can't be protected is unmapped instead; the first error of
+     * what could be neither is returned. */
+    pub fn protect_read_only(ranges: &[VmoMappedRange])
+        -> Result<(), ErrNO> {
+        let mut ret = Ok(());
+        for r in ranges {
+            let aspace = match r.aspace.upgrade() {
+                Some(aspace) => aspace,
+                None => continue,
+            };
+            let flags = r.arch_mmu_flags & !ARCH_MMU_FLAG_PERM_WRITE;
+            if aspace.protect_range(r.vaddr, r.count, flags).is_ok() {
+                continue;
+            }
+            match aspace.unmap_range(r.vaddr, r.count) {
+                Ok(_) => (),
+                Err(ErrNO::BadState) if aspace.is_destroyed() => (),
+                Err(e) => {
+                    dprint!(WARN, "vmo: {} pages at {:x} still writable: \
+                            {:?}\n", r.count, r.vaddr, e);
+                    ret = ret.and(Err(e));
+                },
+            }
+        }
+        ret
+    }
+
+    /* Whether this is the single page at |vaddr| of |aspace| */
+    pub fn is_page(&self, aspace: &Weak<VmAspace>, vaddr: vaddr_t)
+        -> bool {
+        self.aspace.ptr_eq(aspace) && self.vaddr == vaddr && self.count == 1
+    }
+}
+
+pub struct VmoMappingList {
+    list: SpinLock<Vec<VmoMappingRef>>,
+}
+
+impl VmoMappingList {
+    pub const fn new() -> Self {
+        Self {
+            list: SpinLock::new(Vec::new()),
+        }
+    }
+
+    pub fn add(&self, mapping: VmoMappingRef) {
+        self.list.lock().push(mapping);
+    }
+
+    pub fn remove(&self, aspace: &Weak<VmAspace>, base: vaddr_t) {
+        self.list.lock()
+            .retain(|m| !(m.aspace.ptr_eq(aspace) && m.base == base));
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.lock().len()
+    }
+
+    /* Add where [offset, offset + len) of the VMO is mapped
+     * to |ranges| */
+    pub fn collect_ranges(&self, offset: usize, len: usize,
+                          ranges: &mut Vec<VmoMappedRange>) {
+        for m in self.list.lock().iter() {
+            let start = core::cmp::max(offset, m.object_offset);
+            let end = core::cmp::min(offset + len, m.object_offset + m.size);
+            if start >= end {
+                continue;
+            }
+            ranges.push(VmoMappedRange {
+                aspace: m.aspace.clone(),
+                vaddr: m.base + (start - m.object_offset),
+                count: (end - start) / PAGE_SIZE,
+                arch_mmu_flags: m.arch_mmu_flags,
+            });
+        }
+    }
+
+    /* Unmap [offset, offset + len) of the VMO wherever it is mapped */
+    pub fn unmap_range(&self, offset: usize, len: usize)
+        -> Result<(), ErrNO> {
+        let mut ranges = Vec::new();
+        self.collect_ranges(offset, len, &mut ranges);
+        VmoMappedRange::unmap(&ranges)
+    }
+}
+
+/* A page of zeroes, mapped read-only for the pages of paged VMOs
+ * that have been read but never written */
+static ZERO_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn vm_get_zero_page_paddr() -> Result<paddr_t, ErrNO> {
+    let pa = ZERO_PAGE.load(Ordering::Acquire);
+    if pa != 0 {
+        return Ok(pa);
+    }
+
+    let mut page = pmm_alloc_page(PMM_ALLOC_FLAG_ZERO, 0)?;
+    let pa = unsafe {
+        page.as_mut().set_state(vm_page_state::WIRED);
+        page.as_ref().paddr()
+    };
+    match ZERO_PAGE.compare_exchange(0, pa, Ordering::AcqRel,
+                                     Ordering::Acquire) {
+        Ok(_) => Ok(pa),
+        Err(other) => {
+            /* another cpu got there first */
+            pmm_free_page(page);
+            Ok(other)
+        }
+    }
+}
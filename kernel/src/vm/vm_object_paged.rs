@@ -0,0 +1,789 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * Paged VMOs
+ *
+ * The pages come from the PMM when they are first needed and are
+ * kept in the OBJECT state, pointing back at the VMO. Until a page
+ * is written it doesn't exist: reads see the shared zero page, and
+ * writes of zeroes leave it alone. dedup_zero_pages() turns pages
+ * that were written back to zero into holes again.
+ *
+ * Clones are copy-on-write. A clone sees the pages of its parent
+ * until it writes them, at which point it gets a copy of its own;
+ * shared pages are only ever mapped read-only. For a snapshot,
+ * neither side may see what the other writes later, so the pages
+ * of the VMO move to a new hidden VMO that becomes the parent of
+ * both. When one of the two goes away, the hidden VMO is merged
+ * into the other one, and the pages it can't see are freed.
+ * A clone that decommits a page it would otherwise see from its
+ * parent keeps a marker there instead, which reads as zeroes.
+ */
+
+use core::ptr::NonNull;
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::sync::{Arc, Weak};
+use alloc::collections::{BTreeMap, BTreeSet};
+use crate::{
+    ErrNO, dprint, ALWAYS, WARN, paddr_t, PAGE_SIZE, IS_ALIGNED,
+    IS_PAGE_ALIGNED, ROUNDUP, ROUNDUP_PAGE_SIZE, ROUNDDOWN,
+};
+use crate::kernel::spinlock::SpinLock;
+use crate::lib::list::List;
+use crate::vm::page::vm_page_t;
+use crate::vm::vm_page_state;
+use crate::vm::physmap::paddr_to_physmap;
+use crate::vm::pmm::{
+    pmm_alloc_page, pmm_free, PMM_ALLOC_FLAG_ANY, PMM_ALLOC_FLAG_ZERO,
+};
+use crate::vm::vm::VMM_PF_FLAG_WRITE;
+use crate::vm::vm_object::{
+    VmObject, VmoMappingList, VmoMappedRange, vm_get_zero_page_paddr,
+};
+
+/* The VMO may change size after it is created */
+pub const VMO_OPTION_RESIZABLE: u32 = 1 << 0;
+
+const VMO_OPTIONS_MASK: u32 = VMO_OPTION_RESIZABLE;
+
+/* A clone that doesn't see what the parent writes later */
+pub const VMO_CHILD_SNAPSHOT:                   u32 = 1 << 0;
+/* A clone that may see what the parent writes later,
+ * to pages it hasn't written itself */
+pub const VMO_CHILD_SNAPSHOT_AT_LEAST_ON_WRITE: u32 = 1 << 4;
+/* The clone may change size */
+pub const VMO_CHILD_RESIZABLE:                  u32 = 1 << 2;
+
+const VMO_CHILD_OPTIONS_MASK: u32 =
+    VMO_CHILD_SNAPSHOT | VMO_CHILD_SNAPSHOT_AT_LEAST_ON_WRITE |
+    VMO_CHILD_RESIZABLE;
+
+struct VmObjectPagedInner {
+    size: usize,
+    /* the committed pages, by offset */
+    pages: BTreeMap<usize, NonNull<vm_page_t>>,
+    /* decommitted offsets below parent_limit, which show zeroes
+     * rather than the parent; never where there is a page */
+    markers: BTreeSet<usize>,
+    /* the pages each dedup scan under way found to be zero and
+     * that weren't touched since, by scan id; see
+     * dedup_zero_pages() */
+    zero_scans: BTreeMap<u64, BTreeSet<usize>>,
+    next_scan_id: u64,
+    /* the VMO this one is a clone of, or the hidden VMO
+     * that holds the pages it shares with its sibling */
+    parent: Option<Arc<VmObjectPaged>>,
+    /* where this VMO starts in the parent */
+    parent_offset: usize,
+    /* only [0, parent_limit) of this VMO shows the parent */
+    parent_limit: usize,
+    children: Vec<Weak<VmObjectPaged>>,
+}
+
+unsafe impl Send for VmObjectPagedInner {}
+
+impl VmObjectPagedInner {
+    /* The page at |offset| was touched, no dedup scan may take it */
+    fn forget_zero(&mut self, offset: usize) {
+        for scan in self.zero_scans.values_mut() {
+            scan.remove(&offset);
+        }
+    }
+}
+
+pub struct VmObjectPaged {
+    options: u32,
+    /* holds the pages a snapshot left shared, never handed out */
+    hidden: bool,
+    /* one lock for all the VMOs of a clone tree, taken before
+     * the lock of any of them */
+    hierarchy: Arc<SpinLock<()>>,
+    inner: SpinLock<VmObjectPagedInner>,
+    mappings: VmoMappingList,
+}
+
+/* Whether the page at |pa| holds nothing but zeroes */
+fn page_is_zero(pa: paddr_t) -> bool {
+    let words = unsafe {
+        core::slice::from_raw_parts(paddr_to_physmap(pa) as *const u64,
+                                    PAGE_SIZE / 8)
+    };
+    words.iter().all(|&w| w == 0)
+}
+
+/* Hand |page| to the VMO |owner| at |offset| */
+fn set_page_owner(mut page: NonNull<vm_page_t>, owner: *const VmObjectPaged,
+                  offset: usize) {
+    unsafe {
+        let p = page.as_mut();
+        p.object_mut().object = owner as *mut u8;
+        p.object_mut().page_offset = offset as u64;
+    }
+}
+
+fn page_paddr(page: NonNull<vm_page_t>) -> paddr_t {
+    unsafe { page.as_ref().paddr() }
+}
+
+impl VmObjectPaged {
+    fn new_node(options: u32, hidden: bool,
+                hierarchy: Arc<SpinLock<()>>, size: usize) -> Self {
+        VmObjectPaged {
+            options,
+            hidden,
+            hierarchy,
+            inner: SpinLock::new(VmObjectPagedInner {
+                size,
+                pages: BTreeMap::new(),
+                markers: BTreeSet::new(),
+                zero_scans: BTreeMap::new(),
+                next_scan_id: 0,
+                parent: None,
+                parent_offset: 0,
+                parent_limit: 0,
+                children: Vec::new(),
+            }),
+            mappings: VmoMappingList::new(),
+        }
+    }
+
+    fn round_size(size: usize) -> Result<usize, ErrNO> {
+        size.checked_add(PAGE_SIZE - 1)
+            .map(|s| ROUNDDOWN!(s, PAGE_SIZE))
+            .ok_or(ErrNO::OutOfRange)
+    }
+
+    pub fn create(options: u32, size: usize)
+        -> Result<Arc<VmObjectPaged>, ErrNO> {
+
+        if (options & !VMO_OPTIONS_MASK) != 0 {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let size = Self::round_size(size)?;
+        let hierarchy = Arc::new(SpinLock::new(()));
+        Ok(Arc::new(Self::new_node(options, false, hierarchy, size)))
+    }
+
+    /*
+     * Make a copy-on-write clone of [offset, offset + size) of
+     * |parent|. |options| has one of VMO_CHILD_SNAPSHOT and
+     * VMO_CHILD_SNAPSHOT_AT_LEAST_ON_WRITE. The part of the clone
+     * past the end of the parent reads as zeroes.
+     */
+    pub fn create_clone(parent: &Arc<VmObjectPaged>, options: u32,
+                        offset: usize, size: usize)
+        -> Result<Arc<VmObjectPaged>, ErrNO> {
+
+        if (options & !VMO_CHILD_OPTIONS_MASK) != 0 ||
+           !IS_PAGE_ALIGNED!(offset) || parent.hidden {
+            return Err(ErrNO::InvalidArgs);
+        }
+        let kind = options & !VMO_CHILD_RESIZABLE;
+        let snapshot = match kind {
+            VMO_CHILD_SNAPSHOT => true,
+            VMO_CHILD_SNAPSHOT_AT_LEAST_ON_WRITE => false,
+            _ => return Err(ErrNO::InvalidArgs),
+        };
+        let size = Self::round_size(size)?;
+        let child_options = if (options & VMO_CHILD_RESIZABLE) != 0 {
+            VMO_OPTION_RESIZABLE
+        } else {
+            0
+        };
+
+        let child = Arc::new(Self::new_node(child_options, false,
+                                            parent.hierarchy.clone(), size));
+        let mut shared = Vec::new();
+        {
+            let _hierarchy = parent.hierarchy.lock();
+            let mut inner = parent.inner.lock();
+            let mut child_inner = child.inner.lock();
+
+            if snapshot {
+                let hidden = Arc::new(Self::new_node(0, true,
+                                                     parent.hierarchy.clone(),
+                                                     inner.size));
+                Self::insert_hidden_locked(parent, &mut inner, &hidden,
+                                           &child);
+                child_inner.parent = Some(hidden);
+                /* what the parent had mapped writable is shared now */
+                parent.mappings.collect_ranges(0, inner.size, &mut shared);
+            } else {
+                inner.children.push(Arc::downgrade(&child));
+                child_inner.parent = Some(parent.clone());
+            }
+            child_inner.parent_offset = offset;
+            child_inner.parent_limit =
+                core::cmp::min(size, inner.size.saturating_sub(offset));
+        }
+
+        /* on failure the child goes, and the parent gets its
+         * pages back */
+        VmoMappedRange::protect_read_only(&shared)?;
+        Ok(child)
+    }
+
+    /* Move the pages and the place in the tree of |vmo| to |hidden|,
+     * which becomes the parent of |vmo| and |child| */
+    fn insert_hidden_locked(vmo: &Arc<VmObjectPaged>,
+                            inner: &mut VmObjectPagedInner,
+                            hidden: &Arc<VmObjectPaged>,
+                            child: &Arc<VmObjectPaged>) {
+
+        let mut h = hidden.inner.lock();
+        h.pages = core::mem::take(&mut inner.pages);
+        h.markers = core::mem::take(&mut inner.markers);
+        for (&offset, &page) in h.pages.iter() {
+            set_page_owner(page, Arc::as_ptr(hidden), offset);
+        }
+        for scan in inner.zero_scans.values_mut() {
+            scan.clear();
+        }
+
+        h.parent = inner.parent.take();
+        h.parent_offset = inner.parent_offset;
+        h.parent_limit = inner.parent_limit;
+        h.children = vec![Arc::downgrade(vmo), Arc::downgrade(child)];
+        if let Some(grandparent) = &h.parent {
+            Self::replace_child_locked(grandparent, Arc::as_ptr(vmo),
+                                       Arc::downgrade(hidden));
+        }
+
+        inner.parent = Some(hidden.clone());
+        inner.parent_offset = 0;
+        inner.parent_limit = inner.size;
+    }
+
+    fn replace_child_locked(vmo: &VmObjectPaged, old: *const VmObjectPaged,
+                            new: Weak<VmObjectPaged>) {
+        let mut inner = vmo.inner.lock();
+        if let Some(w) = inner.children.iter_mut()
+            .find(|w| w.as_ptr() == old) {
+            *w = new;
+        }
+    }
+
+    /* The page |offset| shows: the VMO's own, or the one of the
+     * closest ancestor that has one, with whether it is its own */
+    fn lookup_locked(inner: &mut VmObjectPagedInner, offset: usize)
+        -> Option<(NonNull<vm_page_t>, bool)> {
+
+        if let Some(&page) = inner.pages.get(&offset) {
+            inner.forget_zero(offset);
+            return Some((page, true));
+        }
+        if offset >= inner.parent_limit || inner.markers.contains(&offset) {
+            return None;
+        }
+
+        let mut offset = offset + inner.parent_offset;
+        let mut node = inner.parent.clone()?;
+        loop {
+            let next = {
+                let mut n = node.inner.lock();
+                if let Some(&page) = n.pages.get(&offset) {
+                    /* it may get mapped, so it's no longer known
+                     * to be zero */
+                    n.forget_zero(offset);
+                    return Some((page, false));
+                }
+                if offset >= n.parent_limit || n.markers.contains(&offset) {
+                    return None;
+                }
+                offset += n.parent_offset;
+                n.parent.clone()?
+            };
+            node = next;
+        }
+    }
+
+    /* Give the VMO a page of its own at |offset|, a copy of the
+     * one it shows or a zeroed one. The mappings that showed the
+     * shared page or the zero page before, of the VMO and of its
+     * clones, go to |stale|; the clones are kept in |alive|. */
+    fn commit_page_locked(&self, inner: &mut VmObjectPagedInner,
+                          offset: usize, stale: &mut Vec<VmoMappedRange>,
+                          alive: &mut Vec<Arc<VmObjectPaged>>)
+        -> Result<paddr_t, ErrNO> {
+
+        let src = match Self::lookup_locked(inner, offset) {
+            Some((page, true)) => return Ok(page_paddr(page)),
+            Some((page, false)) => Some(page_paddr(page)),
+            None => None,
+        };
+
+        let alloc_flags = if src.is_some() {
+            PMM_ALLOC_FLAG_ANY
+        } else {
+            PMM_ALLOC_FLAG_ZERO
+        };
+        let mut page = pmm_alloc_page(alloc_flags, 0)?;
+        unsafe {
+            page.as_mut().set_state(vm_page_state::OBJECT);
+            page.as_mut().object_mut().pin_count = 0;
+        }
+        set_page_owner(page, self, offset);
+
+        let pa = page_paddr(page);
+        if let Some(src) = src {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    paddr_to_physmap(src) as *const u8,
+                    paddr_to_physmap(pa) as *mut u8, PAGE_SIZE);
+            }
+        }
+        inner.pages.insert(offset, page);
+        inner.markers.remove(&offset);
+
+        self.mappings.collect_ranges(offset, PAGE_SIZE, stale);
+        Self::collect_child_ranges_locked(inner, offset, offset + PAGE_SIZE,
+                                          stale, alive);
+        Ok(pa)
+    }
+
+    /* write() with the locks held; see commit_page_locked() */
+    fn write_locked(&self, inner: &mut VmObjectPagedInner, offset: usize,
+                    buf: &[u8], stale: &mut Vec<VmoMappedRange>,
+                    alive: &mut Vec<Arc<VmObjectPaged>>)
+        -> Result<(), ErrNO> {
+
+        Self::page_range(offset, buf.len(), inner.size)?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let page_offset = pos % PAGE_SIZE;
+            let len = core::cmp::min(PAGE_SIZE - page_offset,
+                                     buf.len() - done);
+            let src = &buf[done..done + len];
+            let base = pos - page_offset;
+
+            /* zeroes over a page that shows nothing change nothing */
+            if Self::lookup_locked(inner, base).is_some() ||
+               src.iter().any(|&b| b != 0) {
+                let pa = self.commit_page_locked(inner, base, stale,
+                                                 alive)?;
+                unsafe {
+                    let dst = paddr_to_physmap(pa) + page_offset;
+                    core::ptr::copy_nonoverlapping(src.as_ptr(),
+                                                   dst as *mut u8, len);
+                }
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /* Check that [offset, offset + len) is within |size| and return
+     * the range rounded out to whole pages */
+    fn page_range(offset: usize, len: usize, size: usize)
+        -> Result<(usize, usize), ErrNO> {
+        let end = offset.checked_add(len).ok_or(ErrNO::OutOfRange)?;
+        if end > size {
+            return Err(ErrNO::OutOfRange);
+        }
+        Ok((ROUNDDOWN!(offset, PAGE_SIZE), ROUNDUP_PAGE_SIZE!(end)))
+    }
+
+    /* Take the pages of [start, end) out of the VMO */
+    fn take_pages_locked(inner: &mut VmObjectPagedInner,
+                         start: usize, end: usize)
+        -> Result<List<vm_page_t>, ErrNO> {
+
+        let pinned = inner.pages.range(start..end).any(|(_, p)| unsafe {
+            p.as_ref().object().pin_count != 0
+        });
+        if pinned {
+            return Err(ErrNO::BadState);
+        }
+
+        let mut list = List::<vm_page_t>::new();
+        let tail = inner.pages.split_off(&start);
+        for (offset, page) in tail {
+            if offset < end {
+                list.add_tail(page);
+                inner.forget_zero(offset);
+            } else {
+                inner.pages.insert(offset, page);
+            }
+        }
+        Ok(list)
+    }
+
+    /* Add where the clones below the VMO map what they show
+     * of [start, end) of it to |ranges|. The clones are kept
+     * in |alive| for the caller to let go of once it dropped
+     * the hierarchy lock. */
+    fn collect_child_ranges_locked(inner: &VmObjectPagedInner,
+                                   start: usize, end: usize,
+                                   ranges: &mut Vec<VmoMappedRange>,
+                                   alive: &mut Vec<Arc<VmObjectPaged>>) {
+        for child in inner.children.iter().filter_map(|w| w.upgrade()) {
+            {
+                let c = child.inner.lock();
+                let cstart = start.saturating_sub(c.parent_offset);
+                let cend = core::cmp::min(
+                    end.saturating_sub(c.parent_offset), c.parent_limit);
+                if cstart < cend {
+                    child.mappings.collect_ranges(cstart, cend - cstart,
+                                                  ranges);
+                    Self::collect_child_ranges_locked(&c, cstart, cend,
+                                                      ranges, alive);
+                }
+            }
+            alive.push(child);
+        }
+    }
+
+    /* Unmap the pages taken out of the VMO and free them. Those
+     * that may still be mapped somewhere are leaked instead. */
+    fn release_pages(ranges: &[VmoMappedRange], list: &mut List<vm_page_t>)
+        -> Result<(), ErrNO> {
+        if let Err(e) = VmoMappedRange::unmap(ranges) {
+            dprint!(WARN, "vmo: leaking {} pages that may be mapped\n",
+                    list.len());
+            return Err(e);
+        }
+        pmm_free(list);
+        Ok(())
+    }
+
+    /*
+     * Give back the pages that hold only zeroes and don't hide
+     * anything of an ancestor. A page is unmapped before it is
+     * freed, and it may be written until then, so this goes in two
+     * passes: the first one unmaps what looks like zero, the second
+     * one frees those of them that weren't touched in between and
+     * are still zero. Returns the number of pages freed.
+     */
+    pub fn dedup_zero_pages(&self) -> usize {
+        let mut alive = Vec::new();
+        let mut ranges = Vec::new();
+        let scan_id = {
+            let _hierarchy = self.hierarchy.lock();
+            let mut inner = self.inner.lock();
+            let zero: BTreeSet<usize> = inner.pages.iter()
+                .filter(|&(&offset, p)| unsafe {
+                    p.as_ref().object().pin_count == 0 &&
+                    offset >= inner.parent_limit &&
+                    page_is_zero(p.as_ref().paddr())
+                })
+                .map(|(&offset, _)| offset)
+                .collect();
+            for &offset in &zero {
+                self.mappings.collect_ranges(offset, PAGE_SIZE, &mut ranges);
+                Self::collect_child_ranges_locked(&inner, offset,
+                                                  offset + PAGE_SIZE,
+                                                  &mut ranges, &mut alive);
+            }
+            let scan_id = inner.next_scan_id;
+            inner.next_scan_id += 1;
+            inner.zero_scans.insert(scan_id, zero);
+            scan_id
+        };
+        let unmapped = VmoMappedRange::unmap(&ranges);
+
+        let mut list = List::<vm_page_t>::new();
+        {
+            let _hierarchy = self.hierarchy.lock();
+            let mut inner = self.inner.lock();
+            let zero_scan = inner.zero_scans.remove(&scan_id)
+                .unwrap_or_default();
+            /* what may still be mapped has to stay */
+            if unmapped.is_err() {
+                return 0;
+            }
+            for offset in zero_scan {
+                if let Some(&page) = inner.pages.get(&offset) {
+                    if page_is_zero(page_paddr(page)) {
+                        inner.pages.remove(&offset);
+                        list.add_tail(page);
+                    }
+                }
+            }
+        }
+
+        let count = list.len();
+        pmm_free(&mut list);
+        count
+    }
+
+    /* Fold the hidden VMO |h| into |survivor|, its last child:
+     * the pages the survivor shows and doesn't have move to it,
+     * the others go to |free|. The reference the survivor had on
+     * the hidden VMO goes to |release|. */
+    fn merge_into_child_locked(hidden: *const VmObjectPaged,
+                               h: &mut VmObjectPagedInner,
+                               survivor: &Arc<VmObjectPaged>,
+                               free: &mut List<vm_page_t>,
+                               release: &mut Vec<Arc<VmObjectPaged>>) {
+
+        let mut s = survivor.inner.lock();
+        let start = s.parent_offset;
+        let end = start + s.parent_limit;
+        for (offset, page) in core::mem::take(&mut h.pages) {
+            if offset >= start && offset < end &&
+               !s.pages.contains_key(&(offset - start)) &&
+               !s.markers.contains(&(offset - start)) {
+                set_page_owner(page, Arc::as_ptr(survivor), offset - start);
+                s.pages.insert(offset - start, page);
+            } else {
+                free.add_tail(page);
+            }
+        }
+        for offset in core::mem::take(&mut h.markers) {
+            if offset >= start && offset < end &&
+               !s.pages.contains_key(&(offset - start)) {
+                s.markers.insert(offset - start);
+            }
+        }
+
+        s.parent_limit = core::cmp::min(s.parent_limit,
+                                        h.parent_limit.saturating_sub(start));
+        s.parent_offset = start + h.parent_offset;
+        let grandparent = h.parent.take();
+        if let Some(grandparent) = &grandparent {
+            Self::replace_child_locked(grandparent, hidden,
+                                       Arc::downgrade(survivor));
+        }
+        if let Some(old) = core::mem::replace(&mut s.parent, grandparent) {
+            release.push(old);
+        }
+        h.children.clear();
+    }
+}
+
+impl VmObject for VmObjectPaged {
+    fn size(&self) -> usize {
+        self.inner.lock().size
+    }
+
+    fn is_paged(&self) -> bool {
+        true
+    }
+
+    fn get_page(&self, offset: usize, pf_flags: u32,
+                stale: &mut Vec<VmoMappedRange>)
+        -> Result<(paddr_t, bool), ErrNO> {
+
+        let offset = ROUNDDOWN!(offset, PAGE_SIZE);
+        let mut alive = Vec::new();
+        let _hierarchy = self.hierarchy.lock();
+        let mut inner = self.inner.lock();
+        if offset >= inner.size {
+            return Err(ErrNO::OutOfRange);
+        }
+
+        let write = (pf_flags & VMM_PF_FLAG_WRITE) != 0;
+        match Self::lookup_locked(&mut inner, offset) {
+            /* a page of an ancestor is shared, read-only */
+            Some((page, own)) if own || !write => {
+                Ok((page_paddr(page), own))
+            },
+            None if !write => Ok((vm_get_zero_page_paddr()?, false)),
+            _ => {
+                let pa = self.commit_page_locked(&mut inner, offset, stale,
+                                                 &mut alive)?;
+                Ok((pa, true))
+            },
+        }
+    }
+
+    fn lookup_page(&self, offset: usize) -> Option<(paddr_t, bool)> {
+        let offset = ROUNDDOWN!(offset, PAGE_SIZE);
+        let _hierarchy = self.hierarchy.lock();
+        let mut inner = self.inner.lock();
+        Self::lookup_locked(&mut inner, offset)
+            .map(|(page, own)| (page_paddr(page), own))
+    }
+
+    /* Not to be called with an aspace lock held, like write() */
+    fn commit_range(&self, offset: usize, len: usize) -> Result<(), ErrNO> {
+        let mut alive = Vec::new();
+        let mut stale = Vec::new();
+        let ret = {
+            let _hierarchy = self.hierarchy.lock();
+            let mut inner = self.inner.lock();
+            let (start, end) = Self::page_range(offset, len, inner.size)?;
+            let mut ret = Ok(());
+            for offset in (start..end).step_by(PAGE_SIZE) {
+                if let Err(e) = self.commit_page_locked(&mut inner, offset,
+                                                        &mut stale,
+                                                        &mut alive) {
+                    ret = Err(e);
+                    break;
+                }
+            }
+            ret
+        };
+        VmoMappedRange::unmap(&stale)?;
+        ret
+    }
+
+    /* In a clone, what was decommitted reads as zeroes rather than
+     * as the parent */
+    fn decommit_range(&self, offset: usize, len: usize)
+        -> Result<(), ErrNO> {
+
+        let mut alive = Vec::new();
+        let mut ranges = Vec::new();
+        let mut list = {
+            let _hierarchy = self.hierarchy.lock();
+            let mut inner = self.inner.lock();
+            let (start, end) = Self::page_range(offset, len, inner.size)?;
+            let list = Self::take_pages_locked(&mut inner, start, end)?;
+            let shown_end = core::cmp::min(end, inner.parent_limit);
+            for offset in (start..shown_end).step_by(PAGE_SIZE) {
+                inner.markers.insert(offset);
+            }
+            self.mappings.collect_ranges(start, end - start, &mut ranges);
+            Self::collect_child_ranges_locked(&inner, start, end,
+                                              &mut ranges, &mut alive);
+            list
+        };
+        Self::release_pages(&ranges, &mut list)
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), ErrNO> {
+        let _hierarchy = self.hierarchy.lock();
+        let mut inner = self.inner.lock();
+        Self::page_range(offset, buf.len(), inner.size)?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let page_offset = pos % PAGE_SIZE;
+            let len = core::cmp::min(PAGE_SIZE - page_offset,
+                                     buf.len() - done);
+            let dst = &mut buf[done..done + len];
+            match Self::lookup_locked(&mut inner, pos - page_offset) {
+                Some((page, _)) => unsafe {
+                    let src = paddr_to_physmap(page_paddr(page)) +
+                        page_offset;
+                    core::ptr::copy_nonoverlapping(src as *const u8,
+                                                   dst.as_mut_ptr(), len);
+                },
+                None => dst.fill(0),
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<(), ErrNO> {
+        let mut alive = Vec::new();
+        let mut stale = Vec::new();
+        let ret = {
+            let _hierarchy = self.hierarchy.lock();
+            let mut inner = self.inner.lock();
+            self.write_locked(&mut inner, offset, buf, &mut stale,
+                              &mut alive)
+        };
+        VmoMappedRange::unmap(&stale)?;
+        ret
+    }
+
+    fn resize(&self, size: usize) -> Result<(), ErrNO> {
+        if (self.options & VMO_OPTION_RESIZABLE) == 0 {
+            return Err(ErrNO::NotSupported);
+        }
+        if !IS_PAGE_ALIGNED!(size) {
+            return Err(ErrNO::InvalidArgs);
+        }
+
+        let mut alive = Vec::new();
+        let mut ranges = Vec::new();
+        let mut list = {
+            let _hierarchy = self.hierarchy.lock();
+            let mut inner = self.inner.lock();
+            let old_size = inner.size;
+            if size >= old_size {
+                inner.size = size;
+                return Ok(());
+            }
+            let list = Self::take_pages_locked(&mut inner, size, old_size)?;
+            self.mappings.collect_ranges(size, old_size - size, &mut ranges);
+            Self::collect_child_ranges_locked(&inner, size, old_size,
+                                              &mut ranges, &mut alive);
+
+            /* what is cut off doesn't come back if it grows again */
+            inner.size = size;
+            inner.markers.retain(|&offset| offset < size);
+            inner.parent_limit = core::cmp::min(inner.parent_limit, size);
+            for child in inner.children.iter().filter_map(|w| w.upgrade()) {
+                {
+                    let mut c = child.inner.lock();
+                    c.parent_limit = core::cmp::min(
+                        c.parent_limit, size.saturating_sub(c.parent_offset));
+                }
+                alive.push(child);
+            }
+            list
+        };
+        Self::release_pages(&ranges, &mut list)
+    }
+
+    fn committed_pages(&self) -> usize {
+        self.inner.lock().pages.len()
+    }
+
+    fn mapping_list(&self) -> &VmoMappingList {
+        &self.mappings
+    }
+
+    fn dump(&self, depth: usize) {
+        let _hierarchy = self.hierarchy.lock();
+        let inner = self.inner.lock();
+        dprint!(ALWAYS, "{:indent$}vmo paged{} size {:#x} pages {} \
+                mappings {}\n", "", if self.hidden { " hidden" } else { "" },
+                inner.size, inner.pages.len(), self.mappings.len(),
+                indent = depth * 2);
+        if let Some(parent) = &inner.parent {
+            dprint!(ALWAYS, "{:indent$}clone of [{:#x}, {:#x}) of {:p}\n",
+                    "", inner.parent_offset,
+                    inner.parent_offset + inner.parent_limit,
+                    Arc::as_ptr(parent), indent = depth * 2 + 2);
+        }
+    }
+}
+
+impl Drop for VmObjectPaged {
+    fn drop(&mut self) {
+        /* declared first so that they go after the locks */
+        let mut release = Vec::new();
+        let mut free = List::<vm_page_t>::new();
+        {
+            let _hierarchy = self.hierarchy.lock();
+            let mut inner = self.inner.lock();
+            for (_, page) in core::mem::take(&mut inner.pages) {
+                free.add_tail(page);
+            }
+
+            /* the children hold the parent, so there are none left */
+            if let Some(parent) = inner.parent.take() {
+                {
+                    let mut p = parent.inner.lock();
+                    let me = self as *const VmObjectPaged;
+                    p.children.retain(|w| w.as_ptr() != me);
+
+                    /* reclaim what only the sibling could see */
+                    if parent.hidden && p.children.len() == 1 {
+                        if let Some(survivor) = p.children[0].upgrade() {
+                            Self::merge_into_child_locked(
+                                Arc::as_ptr(&parent), &mut p, &survivor,
+                                &mut free, &mut release);
+                            release.push(survivor);
+                        }
+                    }
+                }
+                release.push(parent);
+            }
+        }
+        pmm_free(&mut free);
+    }
+}
@@ -0,0 +1,147 @@
+/*
+ * Use of this source code is governed by a MIT-style license
+ * that can be found in the LICENSE file or
+ * at https://opensource.org/licenses/MIT
+ */
+
+/*
+ * Physical VMOs
+ *
+ * A fixed range of physical address space, typically the registers
+ * of a device, that is mapped as is. There are no pages to commit
+ * or give back, and the range can't be read or written other than
+ * through a mapping.
+ */
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::{
+    ErrNO, dprint, ALWAYS, paddr_t, PAGE_SIZE, IS_ALIGNED, IS_PAGE_ALIGNED,
+    ROUNDDOWN,
+};
+use crate::arch::mmu::{
+    ARCH_MMU_FLAG_CACHED, ARCH_MMU_FLAG_UNCACHED_DEVICE,
+    ARCH_MMU_FLAG_CACHE_MASK,
+};
+use crate::arch::periphmap::PeriphRange;
+use crate::vm::vm_object::{VmObject, VmoMappingList, VmoMappedRange};
+
+pub struct VmObjectPhysical {
+    base: paddr_t,
+    size: usize,
+    cache_policy: AtomicU32,
+    mappings: VmoMappingList,
+}
+
+impl VmObjectPhysical {
+    pub fn create(base: paddr_t, size: usize)
+        -> Result<Arc<VmObjectPhysical>, ErrNO> {
+
+        if !IS_PAGE_ALIGNED!(base) || !IS_PAGE_ALIGNED!(size) || size == 0 {
+            return Err(ErrNO::InvalidArgs);
+        }
+        base.checked_add(size - 1).ok_or(ErrNO::OutOfRange)?;
+
+        Ok(Arc::new(VmObjectPhysical {
+            base,
+            size,
+            cache_policy: AtomicU32::new(ARCH_MMU_FLAG_CACHED),
+            mappings: VmoMappingList::new(),
+        }))
+    }
+
+    /* A VMO for the registers of a peripheral range,
+     * mapped as device memory */
+    pub fn from_periph_range(range: &PeriphRange)
+        -> Result<Arc<VmObjectPhysical>, ErrNO> {
+
+        let vmo = Self::create(range.base_phys, range.length)?;
+        vmo.set_cache_policy(ARCH_MMU_FLAG_UNCACHED_DEVICE)?;
+        Ok(vmo)
+    }
+
+    pub fn base(&self) -> paddr_t {
+        self.base
+    }
+
+    /* Mappings that exist keep the policy they were made with,
+     * so it may only change while there are none. */
+    pub fn set_cache_policy(&self, cache_policy: u32) -> Result<(), ErrNO> {
+        if (cache_policy & !ARCH_MMU_FLAG_CACHE_MASK) != 0 {
+            return Err(ErrNO::InvalidArgs);
+        }
+        if self.mappings.len() != 0 {
+            return Err(ErrNO::BadState);
+        }
+        self.cache_policy.store(cache_policy, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl VmObject for VmObjectPhysical {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn is_paged(&self) -> bool {
+        false
+    }
+
+    fn cache_policy(&self) -> u32 {
+        self.cache_policy.load(Ordering::Relaxed)
+    }
+
+    fn get_page(&self, offset: usize, _pf_flags: u32,
+                _stale: &mut Vec<VmoMappedRange>)
+        -> Result<(paddr_t, bool), ErrNO> {
+        self.lookup_page(offset).ok_or(ErrNO::OutOfRange)
+    }
+
+    fn lookup_page(&self, offset: usize) -> Option<(paddr_t, bool)> {
+        if offset >= self.size {
+            return None;
+        }
+        Some((self.base + ROUNDDOWN!(offset, PAGE_SIZE), true))
+    }
+
+    /* always committed */
+    fn commit_range(&self, offset: usize, len: usize) -> Result<(), ErrNO> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(ErrNO::OutOfRange),
+        }
+    }
+
+    fn decommit_range(&self, _offset: usize, _len: usize)
+        -> Result<(), ErrNO> {
+        Err(ErrNO::NotSupported)
+    }
+
+    fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<(), ErrNO> {
+        Err(ErrNO::NotSupported)
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<(), ErrNO> {
+        Err(ErrNO::NotSupported)
+    }
+
+    fn resize(&self, _size: usize) -> Result<(), ErrNO> {
+        Err(ErrNO::NotSupported)
+    }
+
+    fn committed_pages(&self) -> usize {
+        0
+    }
+
+    fn mapping_list(&self) -> &VmoMappingList {
+        &self.mappings
+    }
+
+    fn dump(&self, depth: usize) {
+        dprint!(ALWAYS, "{:indent$}vmo physical [{:x}, {:x}) cache {} \
+                mappings {}\n", "", self.base, self.base + self.size,
+                self.cache_policy(), self.mappings.len(),
+                indent = depth * 2);
+    }
+}
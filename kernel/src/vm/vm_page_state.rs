@@ -11,17 +11,94 @@
  * Defines the state of a VM page (|vm_page_t|).
  * Be sure to keep this enum in sync with the definition of |vm_page_t|.
  */
-pub const FREE:     u8 = 0;
-pub const ALLOC:    u8 = 1;
-pub const OBJECT:   u8 = 2;
-pub const WIRED:    u8 = 3;
-pub const HEAP:     u8 = 4;
-pub const MMU:      u8 = 5; /* serve arch-specific mmu purposes */
-pub const IOMMU:    u8 = 6; /* platform-specific iommu structures */
-pub const IPC:      u8 = 7;
-pub const CACHE:    u8 = 8;
-pub const SLAB:     u8 = 9;
-
-pub const COUNT_:   u8 = 10;
-
-pub type vm_page_state_t = u8;
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmPageState {
+    Free    = 0,
+    Alloc   = 1,
+    Object  = 2,
+    Wired   = 3,
+    Heap    = 4,
+    Mmu     = 5, /* serve arch-specific mmu purposes */
+    Iommu   = 6, /* platform-specific iommu structures */
+    Ipc     = 7,
+    Cache   = 8,
+    Slab    = 9,
+}
+
+pub const FREE:     VmPageState = VmPageState::Free;
+pub const ALLOC:    VmPageState = VmPageState::Alloc;
+pub const OBJECT:   VmPageState = VmPageState::Object;
+pub const WIRED:    VmPageState = VmPageState::Wired;
+pub const HEAP:     VmPageState = VmPageState::Heap;
+pub const MMU:      VmPageState = VmPageState::Mmu;
+pub const IOMMU:    VmPageState = VmPageState::Iommu;
+pub const IPC:      VmPageState = VmPageState::Ipc;
+pub const CACHE:    VmPageState = VmPageState::Cache;
+pub const SLAB:     VmPageState = VmPageState::Slab;
+
+pub const COUNT_:   usize = 10;
+
+pub type vm_page_state_t = VmPageState;
+
+const ALL_STATES: [VmPageState; COUNT_] = [
+    FREE, ALLOC, OBJECT, WIRED, HEAP, MMU, IOMMU, IPC, CACHE, SLAB,
+];
+
+impl VmPageState {
+    pub fn from_raw(raw: u8) -> Self {
+        debug_assert!((raw as usize) < COUNT_, "bad page state {}", raw);
+        ALL_STATES[raw as usize]
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VmPageState::Free => "free",
+            VmPageState::Alloc => "alloc",
+            VmPageState::Object => "object",
+            VmPageState::Wired => "wired",
+            VmPageState::Heap => "heap",
+            VmPageState::Mmu => "mmu",
+            VmPageState::Iommu => "iommu",
+            VmPageState::Ipc => "ipc",
+            VmPageState::Cache => "cache",
+            VmPageState::Slab => "slab",
+        }
+    }
+}
+
+const fn bit(state: VmPageState) -> u16 {
+    1 << (state as u16)
+}
+
+/* The states an allocated page may be handed over to by its owner. */
+const OWNED: u16 =
+    bit(OBJECT) | bit(WIRED) | bit(HEAP) | bit(MMU) |
+    bit(IOMMU) | bit(IPC) | bit(CACHE) | bit(SLAB);
+
+/*
+ * Legal transitions through |vm_page::set_state()|, indexed by the
+ * current state. Pages leave the PMM as ALLOC, and go back to FREE
+ * only through the PMM free path (|vm_page::set_state_free()|),
+ * hence nothing here leads to FREE.
+ */
+const TRANSITIONS: [u16; COUNT_] = [
+    /* FREE   */ bit(ALLOC),
+    /* ALLOC  */ OWNED,
+    /* OBJECT */ bit(ALLOC) | bit(WIRED),
+    /* WIRED  */ bit(ALLOC) | bit(OBJECT),
+    /* HEAP   */ bit(ALLOC),
+    /* MMU    */ bit(ALLOC),
+    /* IOMMU  */ bit(ALLOC),
+    /* IPC    */ bit(ALLOC),
+    /* CACHE  */ bit(ALLOC),
+    /* SLAB   */ bit(ALLOC),
+];
+
+pub fn is_valid_transition(from: VmPageState, to: VmPageState) -> bool {
+    (TRANSITIONS[from.index()] & bit(to)) != 0
+}